@@ -0,0 +1,60 @@
+/*
+    Embedding cjit in another crate: build a program, compile it, run it,
+    and surface both kinds of errors to the caller.
+*/
+
+use std::error::Error;
+
+use cjit::{CompileError, CompileOptions, Compiler, Instruction, Invoker, Program};
+
+/*
+    n! computed with a countdown loop.
+*/
+fn factorial(n: i64) -> Program {
+    Program::new(vec![
+        Instruction::Load(1),
+        Instruction::Store(0),      /* acc = 1 */
+        Instruction::Load(n),
+        Instruction::Store(1),      /* i = n */
+
+        Instruction::Label(1),
+        Instruction::LoadVar(1),
+        Instruction::JmpIfNot(2),   /* while i != 0 */
+
+        Instruction::LoadVar(0),
+        Instruction::LoadVar(1),
+        Instruction::Mul,
+        Instruction::Store(0),      /* acc = acc * i */
+        Instruction::LoadVar(1),
+        Instruction::Load(1),
+        Instruction::Sub,
+        Instruction::Store(1),      /* i = i - 1 */
+        Instruction::Jmp(1),
+
+        Instruction::Label(2),
+        Instruction::LoadVar(0),
+        Instruction::Ret,
+    ])
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut compiler = Compiler::with_options(CompileOptions { verify: true, ..CompileOptions::default() });
+    let mut invoker = Invoker::new();
+
+    let code = compiler.compile(&factorial(10))?;
+    println!("10! = {}", unsafe { invoker.execute(&code) }?);     /* verified, straight from the compiler */
+
+    let stats = compiler.peephole_stats();
+    println!("peephole: {} -> {} bytes, {} instructions removed", stats.before, stats.after, stats.removed);
+
+    /*
+        Jumping to a label which does not exist is reported instead of compiled.
+    */
+    let broken = Program::new(vec![Instruction::Jmp(7), Instruction::Ret]);
+    match compiler.compile(&broken) {
+        Err(CompileError::UndefinedLabel(id)) => println!("label {} is missing, as expected", id),
+        other => println!("unexpected: {:?}", other),
+    }
+
+    Ok(())
+}
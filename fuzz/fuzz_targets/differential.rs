@@ -0,0 +1,775 @@
+#![no_main]
+
+/*
+    Differential fuzzing, interpreter vs JIT.
+
+    Every input is turned into a well-formed random program, which is run
+    through the small evaluator at the bottom of this file and through the
+    `Interpreter`, which have to agree. The interpreter's result is then what
+    everything else is held to: the default compiler, the constant folded and
+    dead code eliminated programs, the register cached and SSA code generation
+    modes, unoptimised code, code compiled with `verify` on and a
+    `TieredProgram` which gets compiled after its first run. Any divergence in
+    the result or in whether the program traps is a bug in one of them, and
+    most likely in the encodings the compiler picks.
+
+    Run it locally with:
+
+        cargo +nightly fuzz run differential
+*/
+
+use arbitrary::{Result, Unstructured};
+use libfuzzer_sys::fuzz_target;
+
+use std::collections::HashMap;
+
+use cjit::opt::{eliminate_dead_code, fold_constants};
+use cjit::{
+    CodegenMode, CompileOptions, Compiler, Instruction, Interpreter, Invoker, OptLevel, Program, RunError, TierOptions,
+    TieredProgram, Trap,
+};
+
+const MAX_VARS: u32 = 8;        /* Plain variables, well inside the 1024 byte frame */
+const MAX_NEST: u32 = 3;        /* Nesting of ifs and loops */
+const MAX_STMTS: u32 = 12;      /* Statements per block */
+const MAX_EXPR_DEPTH: u32 = 5;  /* Nesting of expressions */
+const MAX_TRIPS: i64 = 8;       /* Iterations of a generated loop */
+const MAX_STEPS: usize = 200_000;
+
+fuzz_target!(|data: &[u8]| {
+    let mut u = Unstructured::new(data);
+    let Ok(insts) = Generator::new(&mut u).program() else {
+        return;
+    };
+    let program = Program::new(insts.clone());
+
+    /*
+        The JIT has no notion of fuel, so there is nothing to compare.
+    */
+    let expected = evaluate(&insts);
+    if let Outcome::OutOfFuel = expected {
+        return;
+    }
+
+    let reference = Interpreter::new().prepare(&program).unwrap().run();
+    match expected {
+        Outcome::Returned(value) => assert_eq!(reference, Ok(value), "interpreter diverged for {:?}", insts),
+        Outcome::Trapped => assert!(
+            matches!(reference, Err(Trap::DivideByZero | Trap::Overflow)),
+            "interpreter did not trap for {:?}", insts
+        ),
+        Outcome::OutOfFuel => unreachable!(),
+    }
+
+    let compile = |options: CompileOptions, program: &Program| Compiler::with_options(options).compile(program).unwrap();
+    let default = CompileOptions::default();
+    let compiled = [
+        ("default", compile(default, &program)),
+        ("folding", compile(default, &fold_constants(&program))),
+        ("dead code elimination", compile(default, &eliminate_dead_code(&program).0)),
+        ("cached mode", compile(CompileOptions { mode: Some(CodegenMode::Cached), ..default }, &program)),
+        ("ssa mode", compile(CompileOptions { mode: Some(CodegenMode::Ssa), ..default }, &program)),
+        ("O0", compile(CompileOptions { opt_level: OptLevel::O0, ..default }, &program)),
+        ("verified O3", compile(CompileOptions { opt_level: OptLevel::O3, verify: true, ..default }, &program)),
+    ];
+
+    for (name, code) in &compiled {
+        match reference {
+            Ok(value) => {
+                let got = unsafe { Invoker::new().execute(code) };
+                assert_eq!(got, Ok(value), "{} diverged for {:?}", name, insts);
+            }
+
+            #[cfg(unix)]
+            Err(_) => assert!(traps(code), "{} did not trap for {:?}", name, insts),
+            #[cfg(not(unix))]
+            Err(_) => {}
+        }
+    }
+
+    /*
+        Interpreted the first time round, compiled the second, unless it trapped.
+    */
+    let options = TierOptions { call_threshold: 1, ..TierOptions::default() };
+    let mut tiered = TieredProgram::new(program, options).unwrap();
+    for run in 0..2 {
+        assert_eq!(tiered.run(), reference.clone().map_err(RunError::Trap), "tiered run {} diverged for {:?}", run, insts);
+    }
+});
+
+/*
+    Generates structured programs out of the fuzzer input.
+
+    Programs are built from statements which all start and end with an empty
+    operand stack, so every label is reached with the same stack depth no matter
+    which edge got there, and every expression pushes exactly one value so the
+    stack never underflows. Loops count down a counter variable which their
+    bodies never store to, so every program terminates.
+
+    On top of that there are gotos, forward only, from any statement to a
+    label placed at the start of a later one, even inside a loop. A loop
+    tests its counter with `c > 0`, and the counters start at zero, so
+    jumping into one runs it a bounded number of times too. Plain variables
+    are only sometimes initialised, reading one before it is stored to gives
+    0. Dead code after a `Jmp` can leave values on the stack, which the label
+    after it has to ignore.
+
+    Programs end in a `Ret`, by falling off the end with an empty stack, or in
+    a loop whose conditional jump is the last instruction. Some put the `Ret`
+    first and jump round it, so the code after it is reachable, and returns
+    from inside an if can have dead code after them.
+*/
+struct Generator<'a, 'b> {
+    u: &'a mut Unstructured<'b>,
+    insts: Vec<Instruction>,
+    vars: u32,
+    next_label: u32,
+    gotos: Vec<u32>,        /* Labels jumped to which still have to be placed */
+}
+
+impl<'a, 'b> Generator<'a, 'b> {
+    fn new(u: &'a mut Unstructured<'b>) -> Self {
+        Self {
+            u,
+            insts: vec![],
+            vars: 0,
+            next_label: 0,
+            gotos: vec![],
+        }
+    }
+
+    fn program(mut self) -> Result<Vec<Instruction>> {
+        self.vars = self.u.int_in_range(1..=MAX_VARS)?;
+
+        for id in 0..self.vars {
+            if self.u.arbitrary()? {
+                let v = self.imm()?;
+                self.insts.push(Instruction::Load(v));
+                self.insts.push(Instruction::Store(id));
+            }
+        }
+
+        /*
+            Loop counters live above the plain variables, one per nesting level.
+        */
+        for id in self.vars..self.vars + MAX_NEST {
+            self.insts.push(Instruction::Load(0));
+            self.insts.push(Instruction::Store(id));
+        }
+
+        match self.u.int_in_range(0..=3)? {
+            0 => {
+                self.top_block()?;
+                self.expr(0)?;
+                self.insts.push(Instruction::Ret);
+            }
+
+            /*
+                Off the end with nothing on the stack, which returns 0.
+            */
+            1 => self.top_block()?,
+
+            2 => {
+                self.top_block()?;
+                self.tail()?;
+            }
+
+            /*
+                The return first, then the code which jumps back to it.
+            */
+            _ => {
+                let main = self.label();
+                let end = self.label();
+                self.insts.push(Instruction::Jmp(main));
+                self.insts.push(Instruction::Label(end));
+                self.expr(0)?;
+                self.insts.push(Instruction::Ret);
+                self.insts.push(Instruction::Label(main));
+                self.top_block()?;
+                self.insts.push(Instruction::Jmp(end));
+            }
+        }
+
+        Ok(self.insts)
+    }
+
+    /*
+        A loop ending the program with its conditional jump, with or without
+        a value underneath for the fall through to return.
+
+            c = trips; top: c = c - 1; if (c) goto top
+    */
+    fn tail(&mut self) -> Result<()> {
+        if self.u.arbitrary()? {
+            self.expr(0)?;
+        }
+
+        let counter = self.vars;
+        let top = self.label();
+        let trips = self.u.int_in_range(1..=MAX_TRIPS)?;
+
+        self.insts.push(Instruction::Load(trips));
+        self.insts.push(Instruction::Store(counter));
+        self.insts.push(Instruction::Label(top));
+        self.insts.push(Instruction::LoadVar(counter));
+        self.insts.push(Instruction::Load(1));
+        self.insts.push(Instruction::Sub);
+        self.insts.push(Instruction::Dup);
+        self.insts.push(Instruction::Store(counter));
+
+        /* Going round while c is zero stops after a trip or two */
+        if self.u.arbitrary()? {
+            self.insts.push(Instruction::JmpIf(top));
+        } else {
+            self.insts.push(Instruction::JmpIfNot(top));
+        }
+        Ok(())
+    }
+
+    fn label(&mut self) -> u32 {
+        self.next_label += 1;
+        self.next_label
+    }
+
+    fn var(&mut self) -> Result<u32> {
+        self.u.int_in_range(0..=self.vars - 1)
+    }
+
+    fn imm(&mut self) -> Result<i64> {
+        Ok(match self.u.int_in_range(0..=9)? {
+            0 => 0,
+            1 => 1,
+            2 => -1,
+            3 => i64::MIN,
+            4 => i64::MAX,
+            5 => self.u.int_in_range(-70..=70)?,    /* Shift counts on both sides of 64 */
+            6 => self.u.arbitrary::<i32>()? as i64,
+            _ => self.u.arbitrary::<i64>()?,
+        })
+    }
+
+    fn float(&mut self) -> Result<f64> {
+        Ok(match self.u.int_in_range(0..=9)? {
+            0 => 0.0,
+            1 => -0.0,
+            2 => f64::NAN,
+            3 => f64::INFINITY,
+            4 => f64::NEG_INFINITY,
+            5 => i64::MAX as f64,                   /* Just past what fits an i64 */
+            6 => self.u.int_in_range(-70..=70)? as f64 / 4.0,
+            _ => f64::from_bits(self.u.arbitrary()?),
+        })
+    }
+
+    /*
+        The outermost block, followed by every goto label it didn't place yet.
+    */
+    fn top_block(&mut self) -> Result<()> {
+        self.block(0)?;
+        for label in std::mem::take(&mut self.gotos) {
+            self.insts.push(Instruction::Label(label));
+        }
+        Ok(())
+    }
+
+    fn block(&mut self, nest: u32) -> Result<()> {
+        let count = self.u.int_in_range(0..=MAX_STMTS)?;
+        for _ in 0..count {
+            if self.u.is_empty() {
+                break;
+            }
+            self.stmt(nest)?;
+        }
+        Ok(())
+    }
+
+    fn stmt(&mut self, nest: u32) -> Result<()> {
+        let nested = nest < MAX_NEST;
+
+        /*
+            Somewhere for earlier gotos to land.
+        */
+        if !self.gotos.is_empty() && self.u.ratio(1, 3)? {
+            let n = self.u.choose_index(self.gotos.len())?;
+            let label = self.gotos.swap_remove(n);
+            self.insts.push(Instruction::Label(label));
+        }
+
+        match self.u.int_in_range(0..=8)? {
+            /*
+                if (cond) { ... }
+            */
+            1 if nested => {
+                let end = self.label();
+                self.expr(0)?;
+                if self.u.arbitrary()? {
+                    self.insts.push(Instruction::JmpIf(end));
+                } else {
+                    self.insts.push(Instruction::JmpIfNot(end));
+                }
+                self.block(nest + 1)?;
+                self.insts.push(Instruction::Label(end));
+            }
+
+            /*
+                if (cond) { ... } else { ... }
+            */
+            2 if nested => {
+                let other = self.label();
+                let end = self.label();
+                self.expr(0)?;
+                self.insts.push(Instruction::JmpIfNot(other));
+                self.block(nest + 1)?;
+                self.insts.push(Instruction::Jmp(end));
+                self.insts.push(Instruction::Label(other));
+                self.block(nest + 1)?;
+                self.insts.push(Instruction::Label(end));
+            }
+
+            /*
+                for (c = trips; c != 0; c -= 1) { ... }
+            */
+            3 if nested => {
+                let counter = self.vars + nest;
+                let top = self.label();
+                let end = self.label();
+                let trips = self.u.int_in_range(0..=MAX_TRIPS)?;
+
+                self.insts.push(Instruction::Load(trips));
+                self.insts.push(Instruction::Store(counter));
+                self.insts.push(Instruction::Label(top));
+                self.insts.push(Instruction::LoadVar(counter));
+                self.insts.push(Instruction::Load(0));
+                self.insts.push(Instruction::Gt);
+                self.insts.push(Instruction::JmpIfNot(end));
+                self.block(nest + 1)?;
+                self.insts.push(Instruction::LoadVar(counter));
+                self.insts.push(Instruction::Load(1));
+                self.insts.push(Instruction::Sub);
+                self.insts.push(Instruction::Store(counter));
+                self.insts.push(Instruction::Jmp(top));
+                self.insts.push(Instruction::Label(end));
+            }
+
+            /*
+                if (cond) { return value; dead code }
+            */
+            4 => {
+                let skip = self.label();
+                self.expr(0)?;
+                self.insts.push(Instruction::JmpIfNot(skip));
+                self.expr(0)?;
+                self.insts.push(Instruction::Ret);
+                if self.u.ratio(1, 4)? {
+                    self.stmt(MAX_NEST)?;
+                }
+                self.insts.push(Instruction::Label(skip));
+            }
+
+            /*
+                goto, or if (cond) goto, to a label somewhere further on
+            */
+            5 => {
+                let label = self.label();
+                if self.u.arbitrary()? {
+                    self.expr(0)?;
+                    self.insts.push(Instruction::JmpIf(label));
+                } else {
+                    self.insts.push(Instruction::Jmp(label));
+                }
+                self.gotos.push(label);
+            }
+
+            /*
+                goto skip; dead code; skip:
+                the dead code may leave anything on the stack.
+            */
+            6 => {
+                let skip = self.label();
+                self.insts.push(Instruction::Jmp(skip));
+                if self.u.arbitrary()? {
+                    self.stmt(MAX_NEST)?;
+                } else {
+                    for _ in 0..self.u.int_in_range(1..=3)? {
+                        self.expr(MAX_EXPR_DEPTH - 1)?;
+                    }
+                }
+                self.insts.push(Instruction::Label(skip));
+            }
+
+            /*
+                Writes don't take anything off the stack, so they're fine on an empty one.
+            */
+            7 => {
+                let op = self.u.choose(&[Instruction::Write, Instruction::WriteChar])?;
+                self.insts.push(*op);
+            }
+
+            /*
+                var = value
+            */
+            _ => {
+                let id = self.var()?;
+                self.expr(0)?;
+                self.insts.push(Instruction::Store(id));
+            }
+        }
+
+        Ok(())
+    }
+
+    /*
+        Push exactly one value onto the operand stack.
+    */
+    fn expr(&mut self, depth: u32) -> Result<()> {
+        let choice = if depth >= MAX_EXPR_DEPTH || self.u.is_empty() {
+            self.u.int_in_range(0..=1)?
+        } else {
+            self.u.int_in_range(0..=8)?
+        };
+
+        match choice {
+            0 => {
+                if self.u.ratio(1, 4)? {
+                    let v = self.float()?;
+                    self.insts.push(Instruction::LoadF(v));
+                } else {
+                    let v = self.imm()?;
+                    self.insts.push(Instruction::Load(v));
+                }
+            }
+
+            1 => {
+                let id = self.var()?;
+                self.insts.push(Instruction::LoadVar(id));
+            }
+
+            2 => {
+                self.expr(depth + 1)?;
+                let op = self.u.choose(&[
+                    Instruction::Neg,
+                    Instruction::Not,
+                    Instruction::Bnot,
+                    Instruction::FNeg,
+                    Instruction::FSqrt,
+                    Instruction::IToF,
+                    Instruction::FToI,
+                ])?;
+                self.insts.push(*op);
+            }
+
+            /*
+                Operand order matters for most binops, so also try them swapped.
+            */
+            3 | 4 => {
+                self.expr(depth + 1)?;
+                self.expr(depth + 1)?;
+                if choice == 4 {
+                    self.insts.push(Instruction::Swap);
+                }
+                self.binop()?;
+            }
+
+            /*
+                x op x
+            */
+            5 => {
+                self.expr(depth + 1)?;
+                self.insts.push(Instruction::Dup);
+                self.binop()?;
+            }
+
+            /*
+                Evaluate something and throw it away.
+            */
+            6 => {
+                self.expr(depth + 1)?;
+                self.expr(depth + 1)?;
+                self.insts.push(Instruction::Pop);
+            }
+
+            /*
+                Shuffle three values, then combine whatever is left into one.
+            */
+            7 => {
+                for _ in 0..3 {
+                    self.expr(depth + 1)?;
+                }
+
+                let n = self.u.int_in_range(0..=2)?;
+                let &(op, left) = self.u.choose(&[
+                    (Instruction::Over, 4),
+                    (Instruction::Rot, 3),
+                    (Instruction::Nip, 2),
+                    (Instruction::Tuck, 4),
+                    (Instruction::Pick(n), 4),
+                    (Instruction::Roll(n), 3),
+                ])?;
+                self.insts.push(op);
+
+                for _ in 1..left {
+                    self.binop()?;
+                }
+            }
+
+            /*
+                Writes leave their operand on the stack.
+            */
+            _ => {
+                self.expr(depth + 1)?;
+                let op = self.u.choose(&[Instruction::Write, Instruction::WriteChar])?;
+                self.insts.push(*op);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn binop(&mut self) -> Result<()> {
+        let op = self.u.choose(&[
+            Instruction::Add,
+            Instruction::Sub,
+            Instruction::Mul,
+            Instruction::Div,
+            Instruction::Mod,
+            Instruction::Eq,
+            Instruction::Ne,
+            Instruction::Lt,
+            Instruction::Gt,
+            Instruction::Lte,
+            Instruction::Gte,
+            Instruction::And,
+            Instruction::Or,
+            Instruction::Band,
+            Instruction::Bor,
+            Instruction::Bxor,
+            Instruction::Shl,
+            Instruction::Shr,
+            Instruction::UDiv,
+            Instruction::UMod,
+            Instruction::LShr,
+            Instruction::ULt,
+            Instruction::UGt,
+            Instruction::ULte,
+            Instruction::UGte,
+            Instruction::FAdd,
+            Instruction::FSub,
+            Instruction::FMul,
+            Instruction::FDiv,
+            Instruction::FEq,
+            Instruction::FNe,
+            Instruction::FLt,
+            Instruction::FGt,
+            Instruction::FLte,
+            Instruction::FGte,
+        ])?;
+        self.insts.push(*op);
+        Ok(())
+    }
+}
+
+enum Outcome {
+    Returned(i64),
+    Trapped,        /* Division by zero or i64::MIN / -1, a #DE on x86 */
+    OutOfFuel,
+}
+
+/*
+    Reference evaluator for the instructions the generator emits.
+
+    This mirrors what the backend does today rather than what the comments
+    on `Instruction` promise, `And`/`Or` are bitwise and writes leave their
+    operand on the stack.
+*/
+fn evaluate(insts: &[Instruction]) -> Outcome {
+    let labels: HashMap<u32, usize> = insts
+        .iter()
+        .enumerate()
+        .filter_map(|(pc, i)| match i {
+            Instruction::Label(id) => Some((*id, pc)),
+            _ => None,
+        })
+        .collect();
+
+    let mut stack: Vec<i64> = vec![];
+    let mut vars: HashMap<u32, i64> = HashMap::new();
+    let mut pc = 0;
+
+    for _ in 0..MAX_STEPS {
+        let Some(inst) = insts.get(pc) else {
+            return Outcome::Returned(stack.last().copied().unwrap_or(0));
+        };
+        pc += 1;
+
+        macro_rules! binop {
+            ($f:expr) => {{
+                let b = stack.pop().unwrap();
+                let a = stack.pop().unwrap();
+                let f: fn(i64, i64) -> Option<i64> = $f;
+                match f(a, b) {
+                    Some(v) => stack.push(v),
+                    None => return Outcome::Trapped,
+                }
+            }};
+        }
+
+        match inst {
+            Instruction::Load(v) => stack.push(*v),
+            Instruction::Dup => stack.push(*stack.last().unwrap()),
+            Instruction::Pop => {
+                stack.pop().unwrap();
+            }
+            Instruction::Swap => {
+                let len = stack.len();
+                stack.swap(len - 1, len - 2);
+            }
+            Instruction::Over => stack.push(stack[stack.len() - 2]),
+            Instruction::Rot => {
+                let a = stack.remove(stack.len() - 3);
+                stack.push(a);
+            }
+            Instruction::Nip => {
+                stack.remove(stack.len() - 2);
+            }
+            Instruction::Tuck => {
+                let b = *stack.last().unwrap();
+                stack.insert(stack.len() - 2, b);
+            }
+            Instruction::Pick(n) => stack.push(stack[stack.len() - 1 - *n as usize]),
+            Instruction::Roll(n) => {
+                let a = stack.remove(stack.len() - 1 - *n as usize);
+                stack.push(a);
+            }
+            Instruction::Add => binop!(|a, b| Some(a.wrapping_add(b))),
+            Instruction::Sub => binop!(|a, b| Some(a.wrapping_sub(b))),
+            Instruction::Mul => binop!(|a, b| Some(a.wrapping_mul(b))),
+            Instruction::Div => binop!(|a, b| a.checked_div(b)),
+            Instruction::Mod => binop!(|a, b| a.checked_rem(b)),
+            Instruction::Eq => binop!(|a, b| Some((a == b) as i64)),
+            Instruction::Ne => binop!(|a, b| Some((a != b) as i64)),
+            Instruction::Lt => binop!(|a, b| Some((a < b) as i64)),
+            Instruction::Gt => binop!(|a, b| Some((a > b) as i64)),
+            Instruction::Lte => binop!(|a, b| Some((a <= b) as i64)),
+            Instruction::Gte => binop!(|a, b| Some((a >= b) as i64)),
+            Instruction::And | Instruction::Band => binop!(|a, b| Some(a & b)),
+            Instruction::Or | Instruction::Bor => binop!(|a, b| Some(a | b)),
+            Instruction::Bxor => binop!(|a, b| Some(a ^ b)),
+            Instruction::Shl => binop!(|a, b| Some(a.wrapping_shl(b as u32))),   /* Count masked to 6 bits, like cl */
+            Instruction::Shr => binop!(|a, b| Some(a.wrapping_shr(b as u32))),
+            Instruction::UDiv => binop!(|a, b| (a as u64).checked_div(b as u64).map(|r| r as i64)),
+            Instruction::UMod => binop!(|a, b| (a as u64).checked_rem(b as u64).map(|r| r as i64)),
+            Instruction::LShr => binop!(|a, b| Some((a as u64).wrapping_shr(b as u32) as i64)),
+            Instruction::ULt => binop!(|a, b| Some(((a as u64) < b as u64) as i64)),
+            Instruction::UGt => binop!(|a, b| Some((a as u64 > b as u64) as i64)),
+            Instruction::ULte => binop!(|a, b| Some((a as u64 <= b as u64) as i64)),
+            Instruction::UGte => binop!(|a, b| Some((a as u64 >= b as u64) as i64)),
+            Instruction::Neg => {
+                let a = stack.pop().unwrap();
+                stack.push(a.wrapping_neg());
+            }
+            Instruction::Not => {
+                let a = stack.pop().unwrap();
+                stack.push((a == 0) as i64);
+            }
+            Instruction::Bnot => {
+                let a = stack.pop().unwrap();
+                stack.push(!a);
+            }
+            Instruction::LoadF(v) => stack.push(v.to_bits() as i64),
+            Instruction::FAdd => binop!(|a, b| Some(sse(f(a) + f(b), a, b))),
+            Instruction::FSub => binop!(|a, b| Some(sse(f(a) - f(b), a, b))),
+            Instruction::FMul => binop!(|a, b| Some(sse(f(a) * f(b), a, b))),
+            Instruction::FDiv => binop!(|a, b| Some(sse(f(a) / f(b), a, b))),
+            Instruction::FEq => binop!(|a, b| Some((f(a) == f(b)) as i64)),
+            Instruction::FNe => binop!(|a, b| Some((f(a) != f(b)) as i64)),
+            Instruction::FLt => binop!(|a, b| Some((f(a) < f(b)) as i64)),
+            Instruction::FGt => binop!(|a, b| Some((f(a) > f(b)) as i64)),
+            Instruction::FLte => binop!(|a, b| Some((f(a) <= f(b)) as i64)),
+            Instruction::FGte => binop!(|a, b| Some((f(a) >= f(b)) as i64)),
+            Instruction::FNeg => {
+                let a = stack.pop().unwrap();
+                stack.push(a ^ i64::MIN);
+            }
+            Instruction::FSqrt => {
+                let a = stack.pop().unwrap();
+                stack.push(sse(f(a).sqrt(), a, a));
+            }
+            Instruction::IToF => {
+                let a = stack.pop().unwrap();
+                stack.push((a as f64).to_bits() as i64);
+            }
+            Instruction::FToI => {
+                let a = f(stack.pop().unwrap());
+                let fits = a >= i64::MIN as f64 && a < -(i64::MIN as f64);
+                stack.push(if fits { a as i64 } else { i64::MIN });
+            }
+            Instruction::Store(id) => {
+                vars.insert(*id, stack.pop().unwrap());
+            }
+            Instruction::LoadVar(id) => stack.push(vars.get(id).copied().unwrap_or(0)),
+            Instruction::Jmp(label) => pc = labels[label],
+            Instruction::JmpIf(label) => {
+                if stack.pop().unwrap() != 0 {
+                    pc = labels[label];
+                }
+            }
+            Instruction::JmpIfNot(label) => {
+                if stack.pop().unwrap() == 0 {
+                    pc = labels[label];
+                }
+            }
+            Instruction::Label(_) => {}
+            Instruction::Write | Instruction::WriteChar => {}
+            Instruction::Ret => return Outcome::Returned(stack.pop().unwrap()),
+
+            /*
+                Still stubs in the backend, so the generator never emits them.
+            */
+            Instruction::Call(_) | Instruction::Read | Instruction::Halt => {
+                unreachable!("{:?} is not generated", inst)
+            }
+        }
+    }
+
+    Outcome::OutOfFuel
+}
+
+fn f(bits: i64) -> f64 {
+    f64::from_bits(bits as u64)
+}
+
+/*
+    The bits SSE2 leaves for a float result: a NaN operand quieted, the
+    first one if both are, or the default NaN for things like 0 / 0.
+*/
+fn sse(r: f64, a: i64, b: i64) -> i64 {
+    match (r.is_nan(), f(a).is_nan(), f(b).is_nan()) {
+        (false, _, _) => r.to_bits() as i64,
+        (true, true, _) => a | 1 << 51,
+        (true, false, true) => b | 1 << 51,
+        (true, false, false) => 0xFFF8_0000_0000_0000_u64 as i64,
+    }
+}
+
+/*
+    A trapping program takes the whole process down with it, so run it in a
+    child and check how the child died. libFuzzer hooks SIGFPE itself, so the
+    child puts the default action back first.
+*/
+#[cfg(unix)]
+fn traps(code: &[u8]) -> bool {
+    unsafe {
+        let pid = libc::fork();
+        assert!(pid >= 0, "fork failed");
+
+        if pid == 0 {
+            libc::signal(libc::SIGFPE, libc::SIG_DFL);
+            let _ = Invoker::new().execute(code);
+            libc::_exit(0);
+        }
+
+        let mut status = 0;
+        libc::waitpid(pid, &mut status, 0);
+        libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == libc::SIGFPE
+    }
+}
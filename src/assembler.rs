@@ -0,0 +1,822 @@
+/*
+    A small typed x86-64 assembler.
+
+    The compiler describes what it wants in terms of registers, memory
+    operands and labels, and the assembler works out the REX prefixes,
+    ModRM/SIB bytes and jump displacements. Instructions are recorded as
+    they are emitted and only encoded by `finish`, once every label has
+    been bound.
+
+    It encodes the whole register file and every condition code, not just
+    the handful the compiler happens to use today.
+*/
+
+mod peephole;
+
+use std::ops::Range;
+
+/*
+    The 64-bit general purpose registers, numbered as the hardware numbers them.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Reg {
+    Rax, Rcx, Rdx, Rbx, Rsp, Rbp, Rsi, Rdi,
+    R8, R9, R10, R11, R12, R13, R14, R15,
+}
+
+impl Reg {
+    /*
+        The low three bits, which go into the opcode, ModRM or SIB byte.
+    */
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    /*
+        r8 to r15 need their fourth bit in a REX prefix.
+    */
+    fn ext(self) -> bool {
+        self as u8 >= 8
+    }
+
+    /*
+        The lowest byte of the register, al for rax and so on.
+    */
+    pub fn low8(self) -> Reg8 {
+        Reg8(self)
+    }
+}
+
+/*
+    The SSE registers, which hold the f64s in their low 64 bits. The
+    compiler only needs xmm0 and xmm1 so far, the rest are here so the
+    encodings can be tested for the whole register file.
+*/
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Xmm {
+    Xmm0, Xmm1, Xmm2, Xmm3, Xmm4, Xmm5, Xmm6, Xmm7,
+    Xmm8, Xmm9, Xmm10, Xmm11, Xmm12, Xmm13, Xmm14, Xmm15,
+}
+
+impl Xmm {
+    fn low(self) -> u8 {
+        self as u8 & 7
+    }
+
+    fn ext(self) -> bool {
+        self as u8 >= 8
+    }
+}
+
+/*
+    The lowest byte of a 64-bit register.
+    spl, bpl, sil and dil are only reachable with a REX prefix, without one
+    the same encodings mean ah, ch, dh and bh.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reg8(Reg);
+
+impl Reg8 {
+    fn needs_rex(self) -> bool {
+        matches!(self.0, Reg::Rsp | Reg::Rbp | Reg::Rsi | Reg::Rdi)
+    }
+}
+
+/*
+    Condition codes, in the order of their encodings.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    O, No, B, Ae, E, Ne, Be, A, S, Ns, P, Np, L, Ge, Le, G,
+}
+
+impl Cond {
+    /*
+        The opposite condition, conditions come in pairs which only differ in the lowest bit.
+    */
+    pub fn negate(self) -> Cond {
+        use Cond::*;
+        const ALL: [Cond; 16] = [O, No, B, Ae, E, Ne, Be, A, S, Ns, P, Np, L, Ge, Le, G];
+        ALL[self as usize ^ 1]
+    }
+}
+
+/*
+    A memory operand, [base + disp].
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mem {
+    pub base: Reg,
+    pub disp: i32,
+}
+
+impl Mem {
+    pub fn new(base: Reg, disp: i32) -> Self {
+        Self { base, disp }
+    }
+}
+
+/*
+    A position in the code which jumps can target, bound with `Assembler::bind`.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Label(usize);
+
+/*
+    Two operand ALU instructions of the form `op r/m64, r64`, named by their opcode.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alu {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+    Test = 0x85,
+}
+
+/*
+    Single operand instructions of the 0xF7 group, named by their ModRM reg field.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Group3 {
+    Not = 2,
+    Neg = 3,
+    Imul = 5,
+    Div = 6,
+    Idiv = 7,
+}
+
+/*
+    Shifts of the 0xD3 (by cl) and 0xC1 (by imm8) groups, named by their ModRM reg field.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/*
+    Scalar double instructions of the form `op xmm, xmm/m64`, with an F2 prefix, named by their opcode.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Sse {
+    Sqrt = 0x51,
+    Add = 0x58,
+    Mul = 0x59,
+    Sub = 0x5C,
+    Div = 0x5E,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Inst {
+    Bind(Label),
+    Mark(usize),                    /* Where the code for a bytecode instruction starts, no bytes */
+    MovRR(Reg, Reg),
+    MovRI(Reg, i64),
+    Load(Reg, Mem),
+    Store(Mem, Reg),
+    Push(Reg),
+    PushI(i32),
+    Pop(Reg),
+    Alu(Alu, Reg, Reg),
+    SubRI(Reg, i32),
+    Imul(Reg, Reg),
+    Group3(Group3, Reg),
+    Shift(Shift, Reg),
+    ShiftI(Shift, Reg, u8),
+    ImulRI(Reg, Reg, i32),
+    Lea(Reg, Reg, Reg, u8),         /* lea dst, [base + index * scale] */
+    Cqo,
+    Setcc(Cond, Reg8),
+    Movzx(Reg, Reg8),
+    Btc(Reg, u8),
+    Sse(Sse, Xmm, Xmm),
+    Ucomisd(Xmm, Xmm),
+    MovqXR(Xmm, Reg),
+    MovqRX(Reg, Xmm),
+    Cvtsi2sd(Xmm, Reg),
+    Cvttsd2si(Reg, Xmm),
+    Jmp(Label),
+    Jcc(Cond, Label),
+    Ret,
+    Ud2,
+}
+
+/*
+    Code size before and after the peephole optimiser, in bytes.
+*/
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeepholeStats {
+    pub before: usize,
+    pub after: usize,
+    pub removed: usize,     /* Instructions removed */
+}
+
+#[derive(Default)]
+pub struct Assembler {
+    insts: Vec<Inst>,
+    labels: usize,      /* Number of labels handed out */
+}
+
+impl Assembler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /*
+        Create a new, not yet bound, label.
+    */
+    pub fn new_label(&mut self) -> Label {
+        self.labels += 1;
+        Label(self.labels - 1)
+    }
+
+    /*
+        Bind a label to the current position.
+    */
+    pub fn bind(&mut self, label: Label) {
+        self.insts.push(Inst::Bind(label));
+    }
+
+    /*
+        Record that the code for bytecode instruction `pc` starts here. Like a
+        label, nothing is rewritten across a mark by the peephole optimiser.
+    */
+    pub fn mark(&mut self, pc: usize) {
+        self.insts.push(Inst::Mark(pc));
+    }
+
+    pub fn mov_rr(&mut self, dst: Reg, src: Reg) {
+        self.insts.push(Inst::MovRR(dst, src));
+    }
+
+    /*
+        mov dst, imm, in the shortest encoding which produces `imm`. Zero is
+        `xor dst, dst`, so unlike the other movs this may clobber the flags.
+    */
+    pub fn mov_ri(&mut self, dst: Reg, imm: i64) {
+        self.insts.push(Inst::MovRI(dst, imm));
+    }
+
+    /*
+        mov dst, [mem]
+    */
+    pub fn mov_rm(&mut self, dst: Reg, mem: Mem) {
+        self.insts.push(Inst::Load(dst, mem));
+    }
+
+    /*
+        mov [mem], src
+    */
+    pub fn mov_mr(&mut self, mem: Mem, src: Reg) {
+        self.insts.push(Inst::Store(mem, src));
+    }
+
+    pub fn push(&mut self, reg: Reg) {
+        self.insts.push(Inst::Push(reg));
+    }
+
+    /*
+        push imm, sign extended to 64 bits.
+    */
+    pub fn push_i(&mut self, imm: i32) {
+        self.insts.push(Inst::PushI(imm));
+    }
+
+    pub fn pop(&mut self, reg: Reg) {
+        self.insts.push(Inst::Pop(reg));
+    }
+
+    pub fn add_rr(&mut self, dst: Reg, src: Reg) {
+        self.insts.push(Inst::Alu(Alu::Add, dst, src));
+    }
+
+    pub fn sub_rr(&mut self, dst: Reg, src: Reg) {
+        self.insts.push(Inst::Alu(Alu::Sub, dst, src));
+    }
+
+    pub fn and_rr(&mut self, dst: Reg, src: Reg) {
+        self.insts.push(Inst::Alu(Alu::And, dst, src));
+    }
+
+    pub fn or_rr(&mut self, dst: Reg, src: Reg) {
+        self.insts.push(Inst::Alu(Alu::Or, dst, src));
+    }
+
+    pub fn xor_rr(&mut self, dst: Reg, src: Reg) {
+        self.insts.push(Inst::Alu(Alu::Xor, dst, src));
+    }
+
+    pub fn cmp_rr(&mut self, a: Reg, b: Reg) {
+        self.insts.push(Inst::Alu(Alu::Cmp, a, b));
+    }
+
+    pub fn test_rr(&mut self, a: Reg, b: Reg) {
+        self.insts.push(Inst::Alu(Alu::Test, a, b));
+    }
+
+    pub fn sub_ri(&mut self, dst: Reg, imm: i32) {
+        self.insts.push(Inst::SubRI(dst, imm));
+    }
+
+    pub fn imul_rr(&mut self, dst: Reg, src: Reg) {
+        self.insts.push(Inst::Imul(dst, src));
+    }
+
+    /*
+        dst = src * imm
+    */
+    pub fn imul_ri(&mut self, dst: Reg, src: Reg, imm: i32) {
+        self.insts.push(Inst::ImulRI(dst, src, imm));
+    }
+
+    /*
+        Signed multiply rax by `reg`, into the 128 bits of rdx:rax.
+    */
+    pub fn imul_wide(&mut self, reg: Reg) {
+        self.insts.push(Inst::Group3(Group3::Imul, reg));
+    }
+
+    /*
+        dst = base + index * scale, scale being 1, 2, 4 or 8. The index cannot be rsp.
+    */
+    pub fn lea(&mut self, dst: Reg, base: Reg, index: Reg, scale: u8) {
+        assert!(matches!(scale, 1 | 2 | 4 | 8) && index != Reg::Rsp, "lea with an unencodable index");
+        self.insts.push(Inst::Lea(dst, base, index, scale));
+    }
+
+    pub fn neg(&mut self, reg: Reg) {
+        self.insts.push(Inst::Group3(Group3::Neg, reg));
+    }
+
+    pub fn not(&mut self, reg: Reg) {
+        self.insts.push(Inst::Group3(Group3::Not, reg));
+    }
+
+    /*
+        Signed divide rdx:rax by `reg`, quotient in rax and remainder in rdx.
+    */
+    pub fn idiv(&mut self, reg: Reg) {
+        self.insts.push(Inst::Group3(Group3::Idiv, reg));
+    }
+
+    /*
+        Unsigned divide rdx:rax by `reg`, quotient in rax and remainder in rdx.
+    */
+    pub fn div(&mut self, reg: Reg) {
+        self.insts.push(Inst::Group3(Group3::Div, reg));
+    }
+
+    /*
+        Sign extend rax into rdx:rax.
+    */
+    pub fn cqo(&mut self) {
+        self.insts.push(Inst::Cqo);
+    }
+
+    pub fn shl_cl(&mut self, reg: Reg) {
+        self.insts.push(Inst::Shift(Shift::Shl, reg));
+    }
+
+    pub fn sar_cl(&mut self, reg: Reg) {
+        self.insts.push(Inst::Shift(Shift::Sar, reg));
+    }
+
+    /*
+        Logical shift right by cl, shifting in zeroes.
+    */
+    pub fn shr_cl(&mut self, reg: Reg) {
+        self.insts.push(Inst::Shift(Shift::Shr, reg));
+    }
+
+    pub fn shl_ri(&mut self, reg: Reg, count: u8) {
+        self.insts.push(Inst::ShiftI(Shift::Shl, reg, count));
+    }
+
+    /*
+        Logical shift right, shifting in zeroes.
+    */
+    pub fn shr_ri(&mut self, reg: Reg, count: u8) {
+        self.insts.push(Inst::ShiftI(Shift::Shr, reg, count));
+    }
+
+    pub fn sar_ri(&mut self, reg: Reg, count: u8) {
+        self.insts.push(Inst::ShiftI(Shift::Sar, reg, count));
+    }
+
+    pub fn setcc(&mut self, cond: Cond, reg: Reg8) {
+        self.insts.push(Inst::Setcc(cond, reg));
+    }
+
+    pub fn movzx_r8(&mut self, dst: Reg, src: Reg8) {
+        self.insts.push(Inst::Movzx(dst, src));
+    }
+
+    /*
+        Flip bit `bit` of `reg`, the sign of an f64 for 63.
+    */
+    pub fn btc_ri(&mut self, reg: Reg, bit: u8) {
+        self.insts.push(Inst::Btc(reg, bit));
+    }
+
+    pub fn addsd(&mut self, dst: Xmm, src: Xmm) {
+        self.insts.push(Inst::Sse(Sse::Add, dst, src));
+    }
+
+    pub fn subsd(&mut self, dst: Xmm, src: Xmm) {
+        self.insts.push(Inst::Sse(Sse::Sub, dst, src));
+    }
+
+    pub fn mulsd(&mut self, dst: Xmm, src: Xmm) {
+        self.insts.push(Inst::Sse(Sse::Mul, dst, src));
+    }
+
+    pub fn divsd(&mut self, dst: Xmm, src: Xmm) {
+        self.insts.push(Inst::Sse(Sse::Div, dst, src));
+    }
+
+    pub fn sqrtsd(&mut self, dst: Xmm, src: Xmm) {
+        self.insts.push(Inst::Sse(Sse::Sqrt, dst, src));
+    }
+
+    /*
+        Compare two f64s, setting ZF, PF and CF like an unsigned compare,
+        or all three if either is NaN.
+    */
+    pub fn ucomisd(&mut self, a: Xmm, b: Xmm) {
+        self.insts.push(Inst::Ucomisd(a, b));
+    }
+
+    /*
+        Move the bits of a general purpose register into an SSE register and back.
+    */
+    pub fn movq_xr(&mut self, dst: Xmm, src: Reg) {
+        self.insts.push(Inst::MovqXR(dst, src));
+    }
+
+    pub fn movq_rx(&mut self, dst: Reg, src: Xmm) {
+        self.insts.push(Inst::MovqRX(dst, src));
+    }
+
+    /*
+        Convert a signed integer to the nearest f64.
+    */
+    pub fn cvtsi2sd(&mut self, dst: Xmm, src: Reg) {
+        self.insts.push(Inst::Cvtsi2sd(dst, src));
+    }
+
+    /*
+        Convert an f64 to a signed integer, truncating, or i64::MIN if it doesn't fit.
+    */
+    pub fn cvttsd2si(&mut self, dst: Reg, src: Xmm) {
+        self.insts.push(Inst::Cvttsd2si(dst, src));
+    }
+
+    pub fn jmp(&mut self, label: Label) {
+        self.insts.push(Inst::Jmp(label));
+    }
+
+    pub fn jcc(&mut self, cond: Cond, label: Label) {
+        self.insts.push(Inst::Jcc(cond, label));
+    }
+
+    pub fn ret(&mut self) {
+        self.insts.push(Inst::Ret);
+    }
+
+    /*
+        Raise an invalid opcode exception, SIGILL, for traps the hardware has no fault of its own for.
+    */
+    pub fn ud2(&mut self) {
+        self.insts.push(Inst::Ud2);
+    }
+
+    /*
+        Run the peephole optimiser over everything emitted so far.
+        Every label which is jumped to must have been bound by now.
+    */
+    pub fn optimise(&mut self) -> PeepholeStats {
+        let before = self.size();
+        let removed = peephole::optimise(&mut self.insts);
+        let after = self.size();
+
+        PeepholeStats { before, after, removed }
+    }
+
+    /*
+        Bytes everything emitted so far encodes to.
+    */
+    pub fn size(&self) -> usize {
+        self.encode().0.len()
+    }
+
+    /*
+        Encode everything emitted so far, along with the offset of every mark
+        as (pc, offset), in order. Every label which is jumped to must have been bound.
+    */
+    pub fn finish_with_marks(self) -> (Vec<u8>, Vec<(usize, usize)>) {
+        self.encode()
+    }
+
+    /*
+        Jumps get a 2 byte rel8 form when their target is close enough and a
+        rel32 one otherwise, but whether a target is close enough depends on
+        the size of the jumps in between. So every jump starts out short, and
+        the ones which cannot reach are made long until nothing changes. Jumps
+        only ever grow, so this always ends, usually after one or two rounds.
+    */
+    fn encode(&self) -> (Vec<u8>, Vec<(usize, usize)>) {
+        let mut fixed = Encoder::default();
+        let mut spans = Vec::with_capacity(self.insts.len());  /* Bytes of each instruction, empty for jumps */
+
+        for inst in &self.insts {
+            let start = fixed.code.len();
+            if !matches!(inst, Inst::Bind(_) | Inst::Mark(_) | Inst::Jmp(_) | Inst::Jcc(..)) {
+                fixed.inst(inst);
+            }
+            spans.push(start..fixed.code.len());
+        }
+
+        let mut long = vec![false; self.insts.len()];
+        let (offsets, bound) = loop {
+            let (offsets, bound) = self.layout(&spans, &long);
+            let mut changed = false;
+
+            for (i, inst) in self.insts.iter().enumerate() {
+                if let Inst::Jmp(label) | Inst::Jcc(_, label) = *inst
+                    && !long[i]
+                    && i8::try_from(rel(&bound, label, offsets[i] + jump_size(inst, false))).is_err()
+                {
+                    long[i] = true;
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                break (offsets, bound);
+            }
+        };
+
+        let mut enc = Encoder::default();
+        for (i, inst) in self.insts.iter().enumerate() {
+            let end = offsets[i] + jump_size(inst, long[i]);
+
+            match (*inst, long[i]) {
+                (Inst::Jmp(label), false) => enc.bytes(&[0xEB, rel(&bound, label, end) as u8]),
+                (Inst::Jmp(label), true) => {
+                    enc.bytes(&[0xE9]);
+                    enc.bytes(&(rel(&bound, label, end) as i32).to_le_bytes());
+                }
+                (Inst::Jcc(cond, label), false) => enc.bytes(&[0x70 + cond as u8, rel(&bound, label, end) as u8]),
+                (Inst::Jcc(cond, label), true) => {
+                    enc.bytes(&[0x0F, 0x80 + cond as u8]);
+                    enc.bytes(&(rel(&bound, label, end) as i32).to_le_bytes());
+                }
+                _ => enc.bytes(&fixed.code[spans[i].clone()]),
+            }
+        }
+
+        let marks = self
+            .insts
+            .iter()
+            .zip(&offsets)
+            .filter_map(|(inst, &offset)| match *inst {
+                Inst::Mark(pc) => Some((pc, offset)),
+                _ => None,
+            })
+            .collect();
+
+        (enc.code, marks)
+    }
+
+    /*
+        The offset of every instruction and every bound label, for the given jump sizes.
+    */
+    fn layout(&self, spans: &[Range<usize>], long: &[bool]) -> (Vec<usize>, Vec<Option<usize>>) {
+        let mut offsets = Vec::with_capacity(self.insts.len());
+        let mut bound = vec![None; self.labels];
+        let mut pos = 0;
+
+        for (i, inst) in self.insts.iter().enumerate() {
+            if let Inst::Bind(label) = inst {
+                bound[label.0] = Some(pos);
+            }
+
+            offsets.push(pos);
+            pos += spans[i].len() + jump_size(inst, long[i]);
+        }
+
+        (offsets, bound)
+    }
+}
+
+/*
+    Size of a jump in its short or long form, 0 for anything else.
+*/
+fn jump_size(inst: &Inst, long: bool) -> usize {
+    match (inst, long) {
+        (Inst::Jmp(_), false) | (Inst::Jcc(..), false) => 2,
+        (Inst::Jmp(_), true) => 5,
+        (Inst::Jcc(..), true) => 6,
+        _ => 0,
+    }
+}
+
+/*
+    Displacement from the end of a jump to its label.
+*/
+fn rel(bound: &[Option<usize>], label: Label, end: usize) -> isize {
+    let target = bound[label.0].expect("jump to a label which was never bound");
+    target as isize - end as isize
+}
+
+#[derive(Default)]
+struct Encoder {
+    code: Vec<u8>,
+}
+
+impl Encoder {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /*
+        REX prefix, W selects 64-bit operands, R, X and B extend the
+        ModRM reg, SIB index and ModRM rm / SIB base fields.
+    */
+    fn rex(&mut self, w: bool, r: bool, b: bool) {
+        self.code.push(0x40 | (w as u8) << 3 | (r as u8) << 2 | b as u8);
+    }
+
+    /*
+        `op` with a register in the ModRM reg field and another in the rm field.
+    */
+    fn rr(&mut self, op: &[u8], reg: u8, reg_ext: bool, rm: Reg) {
+        self.rex(true, reg_ext, rm.ext());
+        self.bytes(op);
+        self.code.push(0xC0 | reg << 3 | rm.low());
+    }
+
+    /*
+        An SSE instruction, its mandatory prefix, then a REX prefix only if one is needed.
+        Either register may be an xmm or a general purpose one, depending on `op`.
+    */
+    fn sse(&mut self, prefix: u8, op: u8, w: bool, reg: (u8, bool), rm: (u8, bool)) {
+        self.code.push(prefix);
+        if w || reg.1 || rm.1 {
+            self.rex(w, reg.1, rm.1);
+        }
+        self.bytes(&[0x0F, op, 0xC0 | reg.0 << 3 | rm.0]);
+    }
+
+    /*
+        `op` with a register in the ModRM reg field and [base + disp] in the rm field.
+    */
+    fn rm(&mut self, op: &[u8], reg: Reg, mem: Mem) {
+        self.rex(true, reg.ext(), mem.base.ext());
+        self.bytes(op);
+
+        /*
+            mod 00 with rbp/r13 as the base means rip relative, so those always carry a displacement.
+        */
+        let (mode, len) = if mem.disp == 0 && mem.base.low() != 5 {
+            (0x00, 0)
+        } else if i8::try_from(mem.disp).is_ok() {
+            (0x40, 1)
+        } else {
+            (0x80, 4)
+        };
+
+        self.code.push(mode | reg.low() << 3 | mem.base.low());
+
+        /*
+            rm 100 means a SIB byte follows, so rsp/r12 as the base need one with no index.
+        */
+        if mem.base.low() == 4 {
+            self.code.push(0x24);
+        }
+
+        self.bytes(&mem.disp.to_le_bytes()[..len]);
+    }
+
+    fn inst(&mut self, inst: &Inst) {
+        match *inst {
+            Inst::MovRR(dst, src) => self.rr(&[0x89], src.low(), src.ext(), dst),
+            /*
+                Writing a 32-bit register zero extends into the whole register,
+                so only negative or large values need a REX.W form.
+            */
+            Inst::MovRI(dst, 0) => {
+                if dst.ext() {
+                    self.rex(false, true, true);
+                }
+                self.bytes(&[0x31, 0xC0 | dst.low() << 3 | dst.low()]);
+            }
+            Inst::MovRI(dst, imm) => {
+                if let Ok(imm) = u32::try_from(imm) {
+                    if dst.ext() {
+                        self.rex(false, false, true);
+                    }
+                    self.code.push(0xB8 + dst.low());
+                    self.bytes(&imm.to_le_bytes());
+                } else if let Ok(imm) = i32::try_from(imm) {
+                    self.rr(&[0xC7], 0, false, dst);
+                    self.bytes(&imm.to_le_bytes());
+                } else {
+                    self.rex(true, false, dst.ext());
+                    self.code.push(0xB8 + dst.low());
+                    self.bytes(&imm.to_le_bytes());
+                }
+            }
+            Inst::Load(dst, mem) => self.rm(&[0x8B], dst, mem),
+            Inst::Store(mem, src) => self.rm(&[0x89], src, mem),
+            Inst::Push(reg) => {
+                if reg.ext() {
+                    self.code.push(0x41);
+                }
+                self.code.push(0x50 + reg.low());
+            }
+            Inst::PushI(imm) => {
+                if let Ok(imm) = i8::try_from(imm) {
+                    self.bytes(&[0x6A, imm as u8]);
+                } else {
+                    self.code.push(0x68);
+                    self.bytes(&imm.to_le_bytes());
+                }
+            }
+            Inst::Pop(reg) => {
+                if reg.ext() {
+                    self.code.push(0x41);
+                }
+                self.code.push(0x58 + reg.low());
+            }
+            Inst::Alu(op, dst, src) => self.rr(&[op as u8], src.low(), src.ext(), dst),
+            Inst::SubRI(dst, imm) => {
+                if let Ok(imm) = i8::try_from(imm) {
+                    self.rr(&[0x83], 5, false, dst);
+                    self.code.push(imm as u8);
+                } else {
+                    self.rr(&[0x81], 5, false, dst);
+                    self.bytes(&imm.to_le_bytes());
+                }
+            }
+            Inst::Imul(dst, src) => self.rr(&[0x0F, 0xAF], dst.low(), dst.ext(), src),
+            Inst::Group3(op, reg) => self.rr(&[0xF7], op as u8, false, reg),
+            Inst::Shift(op, reg) => self.rr(&[0xD3], op as u8, false, reg),
+            Inst::ShiftI(op, reg, count) => {
+                self.rr(&[0xC1], op as u8, false, reg);
+                self.code.push(count);
+            }
+            Inst::ImulRI(dst, src, imm) => {
+                if let Ok(imm) = i8::try_from(imm) {
+                    self.rr(&[0x6B], dst.low(), dst.ext(), src);
+                    self.code.push(imm as u8);
+                } else {
+                    self.rr(&[0x69], dst.low(), dst.ext(), src);
+                    self.bytes(&imm.to_le_bytes());
+                }
+            }
+            /*
+                rm 100 for a SIB byte, and rbp/r13 as the base only exist with a displacement.
+            */
+            Inst::Lea(dst, base, index, scale) => {
+                self.code.push(0x48 | (dst.ext() as u8) << 2 | (index.ext() as u8) << 1 | base.ext() as u8);
+                self.code.push(0x8D);
+                let mode = if base.low() == 5 { 0x40 } else { 0x00 };
+                self.code.push(mode | dst.low() << 3 | 0x04);
+                self.code.push((scale.trailing_zeros() as u8) << 6 | index.low() << 3 | base.low());
+                if mode == 0x40 {
+                    self.code.push(0);
+                }
+            }
+            Inst::Cqo => self.bytes(&[0x48, 0x99]),
+            Inst::Setcc(cond, Reg8(reg)) => {
+                if reg.ext() || Reg8(reg).needs_rex() {
+                    self.rex(false, false, reg.ext());
+                }
+                self.bytes(&[0x0F, 0x90 + cond as u8, 0xC0 | reg.low()]);
+            }
+            Inst::Movzx(dst, Reg8(src)) => self.rr(&[0x0F, 0xB6], dst.low(), dst.ext(), src),
+            Inst::Btc(reg, bit) => {
+                self.rr(&[0x0F, 0xBA], 7, false, reg);
+                self.code.push(bit);
+            }
+            Inst::Sse(op, dst, src) => self.sse(0xF2, op as u8, false, (dst.low(), dst.ext()), (src.low(), src.ext())),
+            Inst::Ucomisd(a, b) => self.sse(0x66, 0x2E, false, (a.low(), a.ext()), (b.low(), b.ext())),
+            Inst::MovqXR(dst, src) => self.sse(0x66, 0x6E, true, (dst.low(), dst.ext()), (src.low(), src.ext())),
+            Inst::MovqRX(dst, src) => self.sse(0x66, 0x7E, true, (src.low(), src.ext()), (dst.low(), dst.ext())),
+            Inst::Cvtsi2sd(dst, src) => self.sse(0xF2, 0x2A, true, (dst.low(), dst.ext()), (src.low(), src.ext())),
+            Inst::Cvttsd2si(dst, src) => self.sse(0xF2, 0x2C, true, (dst.low(), dst.ext()), (src.low(), src.ext())),
+            Inst::Ret => self.code.push(0xC3),
+            Inst::Ud2 => self.bytes(&[0x0F, 0x0B]),
+            Inst::Bind(_) | Inst::Mark(_) | Inst::Jmp(_) | Inst::Jcc(..) => unreachable!("handled by encode"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,155 @@
+use super::{Alu, Group3, Inst, Reg};
+
+/*
+    Peephole optimiser.
+
+    The compiler keeps its operand stack on the machine stack, so almost
+    every instruction ends with a `push rax` which the next one pops right
+    back off. This looks at the last few instructions as they are added and
+    rewrites
+
+        push a; pop a               ->  (nothing)
+        push a; pop b               ->  mov b, a
+        push a; x; pop b            ->  x; mov b, a         (x leaves the stack and a alone)
+        push imm; pop b             ->  mov b, imm
+        push imm; x; pop b          ->  x; mov b, imm       (x leaves the stack alone)
+
+    except that push imm8; pop b is 3 bytes and the mov is 5, so small
+    immediates other than zero stay on the stack.
+        x = ...; mov b, x; x = ...  ->  b = ...; x = ...
+        mov a, a                    ->  (nothing)
+        mov b, a; mov a, b          ->  mov b, a
+
+    A label is bound in the middle of any sequence that can be jumped into,
+    so sequences are never rewritten across one. Returns the number of
+    instructions removed.
+*/
+pub(super) fn optimise(insts: &mut Vec<Inst>) -> usize {
+    let before = insts.len();
+    let mut out = Vec::with_capacity(before);
+
+    for inst in insts.drain(..) {
+        out.push(inst);
+        while rewrite(&mut out) {}
+    }
+
+    *insts = out;
+    before - insts.len()
+}
+
+/*
+    Apply the first rule which matches the end of `out`, if any.
+*/
+fn rewrite(out: &mut Vec<Inst>) -> bool {
+    let n = out.len();
+
+    match *out.as_slice() {
+        [.., Inst::MovRR(a, b)] if a == b => {
+            out.pop();
+        }
+
+        [.., Inst::Push(a), Inst::Pop(b)] => {
+            out.truncate(n - 2);
+            if a != b {
+                out.push(Inst::MovRR(b, a));
+            }
+        }
+
+        [.., Inst::PushI(imm), Inst::Pop(b)] if shorter_as_mov(imm) => {
+            out.truncate(n - 2);
+            out.push(Inst::MovRI(b, imm as i64));
+        }
+
+        [.., Inst::MovRR(b, a), Inst::MovRR(c, d)] if (c, d) == (a, b) || (c, d) == (b, a) => {
+            out.pop();
+        }
+
+        [.., first, Inst::MovRR(b, a), last] if overwrites(last, a) && retarget(first, a, b).is_some() => {
+            out[n - 3] = retarget(first, a, b).unwrap();
+            out.remove(n - 2);
+        }
+
+        [.., Inst::Push(a), x, Inst::Pop(b)] if independent(x, a) => {
+            out.truncate(n - 3);
+            out.push(x);
+            if a != b {
+                out.push(Inst::MovRR(b, a));
+            }
+        }
+
+        /*
+            mov b, 0 is a xor, which must not land between x and whoever reads the flags x set.
+        */
+        [.., Inst::PushI(imm), x, Inst::Pop(b)] if shorter_as_mov(imm) && independent(x, Reg::Rsp) && (imm != 0 || !sets_flags(x)) => {
+            out.truncate(n - 3);
+            out.push(x);
+            out.push(Inst::MovRI(b, imm as i64));
+        }
+
+        _ => return false,
+    }
+
+    true
+}
+
+/*
+    `inst` with its destination changed from `from` to `to`, if all it does is write `from`.
+*/
+fn retarget(inst: Inst, from: Reg, to: Reg) -> Option<Inst> {
+    match inst {
+        Inst::MovRI(d, imm) if d == from => Some(Inst::MovRI(to, imm)),
+        Inst::MovRR(d, s) if d == from => Some(Inst::MovRR(to, s)),
+        Inst::Load(d, mem) if d == from => Some(Inst::Load(to, mem)),
+        _ => None,
+    }
+}
+
+/*
+    Whether `inst` replaces the value of `reg` without reading it.
+*/
+fn overwrites(inst: Inst, reg: Reg) -> bool {
+    match inst {
+        Inst::Pop(d) | Inst::MovRI(d, _) => d == reg,
+        Inst::MovRR(d, s) => d == reg && s != reg,
+        Inst::Load(d, mem) => d == reg && mem.base != reg,
+        _ => false,
+    }
+}
+
+/*
+    Whether `inst` leaves the stack pointer, stack memory and `reg` alone,
+    so it can move across a push of `reg`.
+*/
+fn independent(inst: Inst, reg: Reg) -> bool {
+    let writes = |d: Reg| d != reg && d != Reg::Rsp;
+
+    match inst {
+        Inst::MovRR(_, Reg::Rsp) | Inst::Alu(_, _, Reg::Rsp) => false,     /* reads where the stack is */
+        Inst::MovRI(d, _) | Inst::MovRR(d, _) | Inst::Imul(d, _) | Inst::Movzx(d, _) => writes(d),
+        Inst::Load(d, mem) => writes(d) && mem.base != Reg::Rsp,
+        Inst::Store(mem, _) => mem.base != Reg::Rsp,
+        Inst::Alu(Alu::Cmp | Alu::Test, ..) => true,
+        Inst::Alu(_, d, _) | Inst::Shift(_, d) | Inst::Group3(Group3::Neg | Group3::Not, d) => writes(d),
+        Inst::ShiftI(_, d, _) | Inst::ImulRI(d, ..) => writes(d),
+        Inst::Setcc(_, d) => writes(d.0),
+        _ => false,
+    }
+}
+
+fn shorter_as_mov(imm: i32) -> bool {
+    imm == 0 || i8::try_from(imm).is_err()
+}
+
+/*
+    Whether `inst` leaves anything in the flags which could be read later.
+*/
+fn sets_flags(inst: Inst) -> bool {
+    matches!(
+        inst,
+        Inst::Alu(..) | Inst::Imul(..) | Inst::ImulRI(..) | Inst::Shift(..) | Inst::ShiftI(..) |
+        Inst::Group3(Group3::Neg, _) | Inst::MovRI(_, 0)
+    )
+}
+
+#[cfg(test)]
+mod tests;
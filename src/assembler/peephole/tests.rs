@@ -0,0 +1,110 @@
+use super::*;
+use crate::assembler::{Label, Mem};
+
+fn opt(mut insts: Vec<Inst>) -> Vec<Inst> {
+    optimise(&mut insts);
+    insts
+}
+
+#[test]
+fn push_pop_same_register() {
+    assert_eq!(opt(vec![Inst::Push(Reg::Rax), Inst::Pop(Reg::Rax)]), []);
+}
+
+#[test]
+fn push_pop_other_register() {
+    assert_eq!(opt(vec![Inst::Push(Reg::Rax), Inst::Pop(Reg::Rbx)]), [Inst::MovRR(Reg::Rbx, Reg::Rax)]);
+}
+
+#[test]
+fn redundant_moves() {
+    assert_eq!(opt(vec![Inst::MovRR(Reg::Rax, Reg::Rax)]), []);
+    assert_eq!(
+        opt(vec![Inst::MovRR(Reg::Rbx, Reg::Rax), Inst::MovRR(Reg::Rax, Reg::Rbx)]),
+        [Inst::MovRR(Reg::Rbx, Reg::Rax)]
+    );
+    assert_eq!(
+        opt(vec![Inst::MovRR(Reg::Rbx, Reg::Rax), Inst::MovRR(Reg::Rbx, Reg::Rax)]),
+        [Inst::MovRR(Reg::Rbx, Reg::Rax)]
+    );
+}
+
+/*
+    Load(10), Load(5), Add, as the compiler emits it.
+*/
+#[test]
+fn binop_on_immediates() {
+    let insts = vec![
+        Inst::MovRI(Reg::Rax, 10), Inst::Push(Reg::Rax),
+        Inst::MovRI(Reg::Rax, 5), Inst::Push(Reg::Rax),
+        Inst::Pop(Reg::Rbx), Inst::Pop(Reg::Rax),
+        Inst::Alu(Alu::Add, Reg::Rax, Reg::Rbx), Inst::Push(Reg::Rax),
+    ];
+
+    assert_eq!(opt(insts), [
+        Inst::MovRI(Reg::Rax, 10),
+        Inst::MovRI(Reg::Rbx, 5),
+        Inst::Alu(Alu::Add, Reg::Rax, Reg::Rbx),
+        Inst::Push(Reg::Rax),
+    ]);
+}
+
+#[test]
+fn moves_across_a_push() {
+    let var = Mem::new(Reg::Rbp, -8);
+    let insts = vec![Inst::Push(Reg::Rax), Inst::Load(Reg::Rbx, var), Inst::Pop(Reg::Rcx)];
+    assert_eq!(opt(insts), [Inst::Load(Reg::Rbx, var), Inst::MovRR(Reg::Rcx, Reg::Rax)]);
+}
+
+#[test]
+fn stack_accesses_stay_put() {
+    let top = Mem::new(Reg::Rsp, 0);
+    let insts = vec![Inst::Push(Reg::Rax), Inst::Load(Reg::Rbx, top), Inst::Pop(Reg::Rcx)];
+    assert_eq!(opt(insts.clone()), insts);
+
+    let insts = vec![Inst::Push(Reg::Rax), Inst::Alu(Alu::Sub, Reg::R11, Reg::Rsp), Inst::Pop(Reg::Rcx)];
+    assert_eq!(opt(insts.clone()), insts);
+}
+
+#[test]
+fn pushed_register_must_not_change() {
+    let insts = vec![Inst::Push(Reg::Rax), Inst::MovRI(Reg::Rax, 1), Inst::Pop(Reg::Rbx)];
+    assert_eq!(opt(insts.clone()), insts);
+}
+
+#[test]
+fn labels_are_barriers() {
+    let insts = vec![Inst::Push(Reg::Rax), Inst::Bind(Label(0)), Inst::Pop(Reg::Rax)];
+    assert_eq!(opt(insts.clone()), insts);
+}
+
+#[test]
+fn retarget_needs_the_register_overwritten() {
+    let insts = vec![Inst::MovRI(Reg::Rax, 5), Inst::MovRR(Reg::Rbx, Reg::Rax), Inst::Push(Reg::Rax)];
+    assert_eq!(opt(insts.clone()), insts);
+}
+
+#[test]
+fn push_imm_pop() {
+    assert_eq!(opt(vec![Inst::PushI(0), Inst::Pop(Reg::Rbx)]), [Inst::MovRI(Reg::Rbx, 0)]);
+    assert_eq!(opt(vec![Inst::PushI(500), Inst::Pop(Reg::Rbx)]), [Inst::MovRI(Reg::Rbx, 500)]);
+
+    let var = Mem::new(Reg::Rbp, -8);
+    let insts = vec![Inst::PushI(500), Inst::Load(Reg::Rax, var), Inst::Pop(Reg::Rbx)];
+    assert_eq!(opt(insts), [Inst::Load(Reg::Rax, var), Inst::MovRI(Reg::Rbx, 500)]);
+}
+
+#[test]
+fn push_imm8_pop_is_shorter() {
+    let insts = vec![Inst::PushI(5), Inst::Pop(Reg::Rbx)];
+    assert_eq!(opt(insts.clone()), insts);
+}
+
+/*
+    Zeroing with xor would clobber what the cmp left for a later jcc.
+*/
+#[test]
+fn push_zero_keeps_the_flags() {
+    let insts = vec![Inst::PushI(0), Inst::Alu(Alu::Cmp, Reg::Rax, Reg::Rcx), Inst::Pop(Reg::Rbx)];
+    assert_eq!(opt(insts.clone()), insts);
+}
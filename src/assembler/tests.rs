@@ -0,0 +1,256 @@
+use super::*;
+
+/*
+    Assemble whatever `f` emits.
+*/
+fn asm(f: impl FnOnce(&mut Assembler)) -> Vec<u8> {
+    let mut asm = Assembler::new();
+    f(&mut asm);
+    asm.finish_with_marks().0
+}
+
+#[test]
+fn mov_rr() {
+    assert_eq!(asm(|a| a.mov_rr(Reg::Rbp, Reg::Rsp)), [0x48,0x89,0xE5]);
+    assert_eq!(asm(|a| a.mov_rr(Reg::R8, Reg::Rax)), [0x49,0x89,0xC0]);
+    assert_eq!(asm(|a| a.mov_rr(Reg::Rax, Reg::R15)), [0x4C,0x89,0xF8]);
+    assert_eq!(asm(|a| a.mov_rr(Reg::R9, Reg::R10)), [0x4D,0x89,0xD1]);
+}
+
+#[test]
+fn mov_ri() {
+    assert_eq!(asm(|a| a.mov_ri(Reg::Rax, 0)), [0x31,0xC0]);
+    assert_eq!(asm(|a| a.mov_ri(Reg::R12, 0)), [0x45,0x31,0xE4]);
+    assert_eq!(asm(|a| a.mov_ri(Reg::Rbx, 1)), [0xBB,0x01,0x00,0x00,0x00]);
+    assert_eq!(asm(|a| a.mov_ri(Reg::R12, 1)), [0x41,0xBC,0x01,0x00,0x00,0x00]);
+    assert_eq!(asm(|a| a.mov_ri(Reg::Rax, 0xFFFF_FFFF)), [0xB8,0xFF,0xFF,0xFF,0xFF]);
+    assert_eq!(asm(|a| a.mov_ri(Reg::Rax, -2)), [0x48,0xC7,0xC0,0xFE,0xFF,0xFF,0xFF]);
+    assert_eq!(asm(|a| a.mov_ri(Reg::R9, i32::MIN as i64)), [0x49,0xC7,0xC1,0x00,0x00,0x00,0x80]);
+    assert_eq!(asm(|a| a.mov_ri(Reg::Rax, 1 << 32)), [0x48,0xB8,0,0,0,0,0x01,0,0,0]);
+    assert_eq!(asm(|a| a.mov_ri(Reg::R12, i64::MIN)), [0x49,0xBC,0,0,0,0,0,0,0,0x80]);
+}
+
+#[test]
+fn push_pop() {
+    assert_eq!(asm(|a| a.push(Reg::Rbx)), [0x53]);
+    assert_eq!(asm(|a| a.push(Reg::R12)), [0x41,0x54]);
+    assert_eq!(asm(|a| a.push_i(-1)), [0x6A,0xFF]);
+    assert_eq!(asm(|a| a.push_i(128)), [0x68,0x80,0x00,0x00,0x00]);
+    assert_eq!(asm(|a| a.pop(Reg::Rax)), [0x58]);
+    assert_eq!(asm(|a| a.pop(Reg::R15)), [0x41,0x5F]);
+}
+
+#[test]
+fn memory_operands() {
+    assert_eq!(asm(|a| a.mov_rm(Reg::Rax, Mem::new(Reg::Rsp, 0))), [0x48,0x8B,0x04,0x24]);
+    assert_eq!(asm(|a| a.mov_rm(Reg::Rax, Mem::new(Reg::R12, 0))), [0x49,0x8B,0x04,0x24]);
+    assert_eq!(asm(|a| a.mov_rm(Reg::R9, Mem::new(Reg::Rsp, 16))), [0x4C,0x8B,0x4C,0x24,0x10]);
+    assert_eq!(asm(|a| a.mov_rm(Reg::Rax, Mem::new(Reg::Rbp, -8))), [0x48,0x8B,0x45,0xF8]);
+    assert_eq!(asm(|a| a.mov_rm(Reg::Rax, Mem::new(Reg::R13, 0))), [0x49,0x8B,0x45,0x00]);
+    assert_eq!(asm(|a| a.mov_rm(Reg::Rcx, Mem::new(Reg::Rax, 0))), [0x48,0x8B,0x08]);
+    assert_eq!(asm(|a| a.mov_mr(Mem::new(Reg::Rbp, -1024), Reg::Rax)), [0x48,0x89,0x85,0x00,0xFC,0xFF,0xFF]);
+    assert_eq!(asm(|a| a.mov_mr(Mem::new(Reg::Rbp, -8), Reg::R14)), [0x4C,0x89,0x75,0xF8]);
+}
+
+#[test]
+fn alu() {
+    assert_eq!(asm(|a| a.add_rr(Reg::Rax, Reg::Rbx)), [0x48,0x01,0xD8]);
+    assert_eq!(asm(|a| a.sub_rr(Reg::Rax, Reg::Rbx)), [0x48,0x29,0xD8]);
+    assert_eq!(asm(|a| a.and_rr(Reg::Rax, Reg::Rbx)), [0x48,0x21,0xD8]);
+    assert_eq!(asm(|a| a.or_rr(Reg::Rax, Reg::Rbx)), [0x48,0x09,0xD8]);
+    assert_eq!(asm(|a| a.xor_rr(Reg::Rax, Reg::Rbx)), [0x48,0x31,0xD8]);
+    assert_eq!(asm(|a| a.cmp_rr(Reg::Rax, Reg::Rbx)), [0x48,0x39,0xD8]);
+    assert_eq!(asm(|a| a.test_rr(Reg::Rax, Reg::Rax)), [0x48,0x85,0xC0]);
+    assert_eq!(asm(|a| a.add_rr(Reg::R13, Reg::R8)), [0x4D,0x01,0xC5]);
+    assert_eq!(asm(|a| a.imul_rr(Reg::Rax, Reg::Rbx)), [0x48,0x0F,0xAF,0xC3]);
+    assert_eq!(asm(|a| a.imul_rr(Reg::R10, Reg::R11)), [0x4D,0x0F,0xAF,0xD3]);
+}
+
+#[test]
+fn sub_ri() {
+    assert_eq!(asm(|a| a.sub_ri(Reg::Rsp, 1024)), [0x48,0x81,0xEC,0x00,0x04,0x00,0x00]);
+    assert_eq!(asm(|a| a.sub_ri(Reg::Rsp, 16)), [0x48,0x83,0xEC,0x10]);
+}
+
+#[test]
+fn group3() {
+    assert_eq!(asm(|a| a.idiv(Reg::Rbx)), [0x48,0xF7,0xFB]);
+    assert_eq!(asm(|a| a.idiv(Reg::R14)), [0x49,0xF7,0xFE]);
+    assert_eq!(asm(|a| a.div(Reg::Rbx)), [0x48,0xF7,0xF3]);
+    assert_eq!(asm(|a| a.div(Reg::R11)), [0x49,0xF7,0xF3]);
+    assert_eq!(asm(|a| a.neg(Reg::Rax)), [0x48,0xF7,0xD8]);
+    assert_eq!(asm(|a| a.not(Reg::Rax)), [0x48,0xF7,0xD0]);
+    assert_eq!(asm(|a| a.cqo()), [0x48,0x99]);
+}
+
+#[test]
+fn shifts() {
+    assert_eq!(asm(|a| a.shl_cl(Reg::Rax)), [0x48,0xD3,0xE0]);
+    assert_eq!(asm(|a| a.sar_cl(Reg::Rax)), [0x48,0xD3,0xF8]);
+    assert_eq!(asm(|a| a.sar_cl(Reg::R9)), [0x49,0xD3,0xF9]);
+    assert_eq!(asm(|a| a.shr_cl(Reg::Rax)), [0x48,0xD3,0xE8]);
+    assert_eq!(asm(|a| a.shr_cl(Reg::R9)), [0x49,0xD3,0xE9]);
+}
+
+#[test]
+fn shifts_by_immediate() {
+    assert_eq!(asm(|a| a.shl_ri(Reg::Rax, 3)), [0x48,0xC1,0xE0,0x03]);
+    assert_eq!(asm(|a| a.shr_ri(Reg::Rcx, 63)), [0x48,0xC1,0xE9,0x3F]);
+    assert_eq!(asm(|a| a.sar_ri(Reg::R10, 1)), [0x49,0xC1,0xFA,0x01]);
+}
+
+#[test]
+fn multiplies() {
+    assert_eq!(asm(|a| a.imul_ri(Reg::Rax, Reg::Rbx, 10)), [0x48,0x6B,0xC3,0x0A]);
+    assert_eq!(asm(|a| a.imul_ri(Reg::Rdx, Reg::Rdx, 1000)), [0x48,0x69,0xD2,0xE8,0x03,0x00,0x00]);
+    assert_eq!(asm(|a| a.imul_wide(Reg::Rsi)), [0x48,0xF7,0xEE]);
+}
+
+#[test]
+fn lea() {
+    assert_eq!(asm(|a| a.lea(Reg::Rax, Reg::Rax, Reg::Rax, 2)), [0x48,0x8D,0x04,0x40]);
+    assert_eq!(asm(|a| a.lea(Reg::Rbx, Reg::Rbx, Reg::Rbx, 8)), [0x48,0x8D,0x1C,0xDB]);
+    assert_eq!(asm(|a| a.lea(Reg::Rax, Reg::Rbp, Reg::Rbp, 4)), [0x48,0x8D,0x44,0xAD,0x00]);
+    assert_eq!(asm(|a| a.lea(Reg::R9, Reg::R13, Reg::R12, 1)), [0x4F,0x8D,0x4C,0x25,0x00]);
+}
+
+#[test]
+#[should_panic(expected = "unencodable")]
+fn lea_rsp_index() {
+    asm(|a| a.lea(Reg::Rax, Reg::Rax, Reg::Rsp, 1));
+}
+
+#[test]
+fn setcc_and_movzx() {
+    assert_eq!(asm(|a| a.setcc(Cond::L, Reg::Rax.low8())), [0x0F,0x9C,0xC0]);
+    assert_eq!(asm(|a| a.setcc(Cond::E, Reg::Rsi.low8())), [0x40,0x0F,0x94,0xC6]);
+    assert_eq!(asm(|a| a.setcc(Cond::Ne, Reg::R9.low8())), [0x41,0x0F,0x95,0xC1]);
+    assert_eq!(asm(|a| a.movzx_r8(Reg::Rax, Reg::Rax.low8())), [0x48,0x0F,0xB6,0xC0]);
+    assert_eq!(asm(|a| a.movzx_r8(Reg::R8, Reg::Rdi.low8())), [0x4C,0x0F,0xB6,0xC7]);
+}
+
+#[test]
+fn sse_arithmetic() {
+    assert_eq!(asm(|a| a.addsd(Xmm::Xmm0, Xmm::Xmm1)), [0xF2,0x0F,0x58,0xC1]);
+    assert_eq!(asm(|a| a.subsd(Xmm::Xmm2, Xmm::Xmm3)), [0xF2,0x0F,0x5C,0xD3]);
+    assert_eq!(asm(|a| a.mulsd(Xmm::Xmm8, Xmm::Xmm1)), [0xF2,0x44,0x0F,0x59,0xC1]);
+    assert_eq!(asm(|a| a.divsd(Xmm::Xmm0, Xmm::Xmm15)), [0xF2,0x41,0x0F,0x5E,0xC7]);
+    assert_eq!(asm(|a| a.sqrtsd(Xmm::Xmm0, Xmm::Xmm0)), [0xF2,0x0F,0x51,0xC0]);
+    assert_eq!(asm(|a| a.ucomisd(Xmm::Xmm1, Xmm::Xmm0)), [0x66,0x0F,0x2E,0xC8]);
+}
+
+#[test]
+fn sse_moves_and_conversions() {
+    assert_eq!(asm(|a| a.movq_xr(Xmm::Xmm0, Reg::Rax)), [0x66,0x48,0x0F,0x6E,0xC0]);
+    assert_eq!(asm(|a| a.movq_xr(Xmm::Xmm1, Reg::R11)), [0x66,0x49,0x0F,0x6E,0xCB]);
+    assert_eq!(asm(|a| a.movq_rx(Reg::Rbx, Xmm::Xmm0)), [0x66,0x48,0x0F,0x7E,0xC3]);
+    assert_eq!(asm(|a| a.movq_rx(Reg::R9, Xmm::Xmm9)), [0x66,0x4D,0x0F,0x7E,0xC9]);
+    assert_eq!(asm(|a| a.cvtsi2sd(Xmm::Xmm0, Reg::Rsi)), [0xF2,0x48,0x0F,0x2A,0xC6]);
+    assert_eq!(asm(|a| a.cvttsd2si(Reg::R10, Xmm::Xmm0)), [0xF2,0x4C,0x0F,0x2C,0xD0]);
+    assert_eq!(asm(|a| a.btc_ri(Reg::Rax, 63)), [0x48,0x0F,0xBA,0xF8,0x3F]);
+    assert_eq!(asm(|a| a.btc_ri(Reg::R12, 63)), [0x49,0x0F,0xBA,0xFC,0x3F]);
+}
+
+#[test]
+fn negate() {
+    assert_eq!(Cond::E.negate(), Cond::Ne);
+    assert_eq!(Cond::Ne.negate(), Cond::E);
+    assert_eq!(Cond::L.negate(), Cond::Ge);
+    assert_eq!(Cond::G.negate(), Cond::Le);
+    assert_eq!(Cond::B.negate(), Cond::Ae);
+}
+
+#[test]
+fn ud2() {
+    assert_eq!(asm(|a| a.ud2()), [0x0F,0x0B]);
+}
+
+#[test]
+fn forward_jump() {
+    let code = asm(|a| {
+        let l = a.new_label();
+        a.jmp(l);
+        a.ret();
+        a.bind(l);
+    });
+    assert_eq!(code, [0xEB,0x01,0xC3]);
+}
+
+#[test]
+fn backward_jump() {
+    let code = asm(|a| {
+        let l = a.new_label();
+        a.bind(l);
+        a.jcc(Cond::Ne, l);
+    });
+    assert_eq!(code, [0x75,0xFE]);
+}
+
+/*
+    A jump over `n` one byte instructions.
+*/
+fn jump_over(n: usize) -> Vec<u8> {
+    asm(|a| {
+        let l = a.new_label();
+        a.jmp(l);
+        for _ in 0..n {
+            a.ret();
+        }
+        a.bind(l);
+    })
+}
+
+#[test]
+fn long_jumps() {
+    assert_eq!(jump_over(127)[..2], [0xEB,0x7F]);
+    assert_eq!(jump_over(128)[..5], [0xE9,0x80,0x00,0x00,0x00]);
+
+    let code = asm(|a| {
+        let l = a.new_label();
+        a.bind(l);
+        for _ in 0..200 {
+            a.ret();
+        }
+        a.jcc(Cond::E, l);
+    });
+    assert_eq!(code[200..], [0x0F,0x84,0x32,0xFF,0xFF,0xFF]);
+}
+
+/*
+    The first jump reaches over the second one as long as the second one is
+    short, so once the second one has to be long the first one does too.
+*/
+#[test]
+fn relaxation() {
+    let chained = |gap: usize| asm(|a| {
+        let first = a.new_label();
+        let second = a.new_label();
+        a.jmp(first);
+        for _ in 0..123 {
+            a.ret();
+        }
+        a.jmp(second);
+        a.bind(first);
+        for _ in 0..gap {
+            a.ret();
+        }
+        a.bind(second);
+    });
+
+    let code = chained(127);
+    assert_eq!(code[..2], [0xEB,0x7D]);
+    assert_eq!(code[125..127], [0xEB,0x7F]);
+
+    let code = chained(128);
+    assert_eq!(code[..5], [0xE9,0x80,0x00,0x00,0x00]);
+    assert_eq!(code[128..133], [0xE9,0x80,0x00,0x00,0x00]);
+}
+
+#[test]
+#[should_panic(expected = "never bound")]
+fn unbound_label() {
+    asm(|a| {
+        let l = a.new_label();
+        a.jmp(l);
+    });
+}
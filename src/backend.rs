@@ -0,0 +1,156 @@
+use std::fmt;
+
+use crate::compiler::{verify, CompileError, Compiler};
+use crate::interpreter::{Bytecode, Interpreter, Trap};
+use crate::invoker::{InvokeError, Invoker};
+use crate::program::Program;
+
+/*
+    A backend lowers a program into something which can be run.
+    The frontend only ever talks to this trait, so targets can be added
+    or swapped without it noticing.
+*/
+pub trait Backend {
+    fn name(&self) -> &'static str;
+    fn lower(&mut self, program: &Program) -> Result<Artifact, CompileError>;
+}
+
+/*
+    The output of a backend.
+*/
+#[derive(Debug, Clone)]
+pub enum Artifact {
+    Native(NativeCode),     /* x86-64 machine code, run through an `Invoker` */
+    Bytecode(Bytecode),     /* Resolved instructions, run by the interpreter */
+}
+
+/*
+    Machine code straight out of the `Compiler`, for a program which passed
+    verification. Nothing else can make one, so `Artifact::run` only ever
+    executes code the compiler generated for a program whose stack can't
+    underflow or come apart between paths.
+*/
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeCode(Vec<u8>);
+
+impl NativeCode {
+    pub fn bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/*
+    Everything that can go wrong while running an artifact.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum RunError {
+    Invoke(InvokeError),
+    Trap(Trap),
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::Invoke(e) => write!(f, "{}", e),
+            RunError::Trap(e) => write!(f, "trap: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+impl From<InvokeError> for RunError {
+    fn from(e: InvokeError) -> Self {
+        RunError::Invoke(e)
+    }
+}
+
+impl From<Trap> for RunError {
+    fn from(e: Trap) -> Self {
+        RunError::Trap(e)
+    }
+}
+
+impl Artifact {
+    pub fn run(&self) -> Result<i64, RunError> {
+        match self {
+            Artifact::Native(code) => Ok(unsafe { Invoker::new().execute(&code.0) }?),     /* verified and compiled by `lower` */
+            Artifact::Bytecode(bytecode) => Ok(bytecode.run()?),
+        }
+    }
+}
+
+impl Backend for Compiler {
+    fn name(&self) -> &'static str {
+        "x86_64"
+    }
+
+    /*
+        Always verified, whatever the options say, as the code gets run by a safe function.
+    */
+    fn lower(&mut self, program: &Program) -> Result<Artifact, CompileError> {
+        verify(program)?;
+        Ok(Artifact::Native(NativeCode(self.compile(program)?)))
+    }
+}
+
+impl Backend for Interpreter {
+    fn name(&self) -> &'static str {
+        "interpreter"
+    }
+
+    fn lower(&mut self, program: &Program) -> Result<Artifact, CompileError> {
+        Ok(Artifact::Bytecode(self.prepare(program)?))
+    }
+}
+
+/*
+    The backends which can be selected at runtime.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    X86_64,
+    Interpreter,
+}
+
+impl BackendKind {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "x86_64" | "native" => Some(BackendKind::X86_64),
+            "interpreter" | "interp" => Some(BackendKind::Interpreter),
+            _ => None,
+        }
+    }
+
+    /*
+        Whether this backend can run on the host at all.
+    */
+    pub fn is_supported(self) -> bool {
+        match self {
+            BackendKind::X86_64 => cfg!(target_arch = "x86_64"),
+            BackendKind::Interpreter => true,
+        }
+    }
+}
+
+/*
+    Create the backend asked for, falling back to the interpreter
+    when the host cannot run it.
+*/
+pub fn select(kind: BackendKind) -> Box<dyn Backend> {
+    if !kind.is_supported() {
+        return Box::new(Interpreter::new());
+    }
+
+    match kind {
+        BackendKind::X86_64 => Box::new(Compiler::new()),
+        BackendKind::Interpreter => Box::new(Interpreter::new()),
+    }
+}
+
+/*
+    The fastest backend the host supports.
+*/
+pub fn native() -> Box<dyn Backend> {
+    select(BackendKind::X86_64)
+}
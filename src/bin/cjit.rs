@@ -1,10 +1,25 @@
-use crate::compiler::{Compiler, Instruction, Invoker, Program};
-
-mod compiler;
+use std::error::Error;
+
+use cjit::{select, BackendKind, Instruction, Program};
+
+/*
+    Pick the backend from `--backend <name>`, the native one by default.
+*/
+fn backend_kind() -> Result<BackendKind, String> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.iter().position(|a| a == "--backend") {
+        None => Ok(BackendKind::X86_64),
+        Some(i) => {
+            let name = args.get(i + 1).ok_or("--backend needs a name")?;
+            BackendKind::parse(name).ok_or(format!("unknown backend: {}", name))
+        }
+    }
+}
 
-fn main() {
-    let mut compiler = Compiler::new();
-    let mut invoker = Invoker::new();
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut backend = select(backend_kind()?);
+    println!("[Backend] {}", backend.name());
 
     println!("[Example 1] 100 + 200");
     let test = Program::new(vec![
@@ -14,7 +29,7 @@ fn main() {
         Instruction::Ret
     ]);
 
-    println!("[Example 1] Result: {}", invoker.execute(&*compiler.compile(&test)));
+    println!("[Example 1] Result: {}", backend.lower(&test)?.run()?);
 
     println!("[Example 2] (10 + 5) * 3 - 2");
     let test2 = Program::new(vec![
@@ -28,7 +43,7 @@ fn main() {
         Instruction::Ret
     ]);
 
-    println!("[Example 2] Result: {}", invoker.execute(&*compiler.compile(&test2)));
+    println!("[Example 2] Result: {}", backend.lower(&test2)?.run()?);
 
     println!("[Example 3] duplicate and swap on the stack: load 42, dupe it, load 10, swap them -> [42, 10, 42] -> add -> mul -> 2184");
     let test3 = Program::new(vec![
@@ -41,7 +56,7 @@ fn main() {
         Instruction::Ret
     ]);
 
-    println!("[Example 3] Result: {}", invoker.execute(&*compiler.compile(&test3)));
+    println!("[Example 3] Result: {}", backend.lower(&test3)?.run()?);
 
     println!("[Example 3] storing and loading variables");
     println!("[Example 3] load 25 and 17 into variables, load the variables and add them");
@@ -56,7 +71,7 @@ fn main() {
         Instruction::Ret
     ]);
 
-    println!("[Example 3] Result: {}", invoker.execute(&*compiler.compile(&test4)));
+    println!("[Example 3] Result: {}", backend.lower(&test4)?.run()?);
 
     println!("[Example 4] bitwise operations: (5 << 2) | (3 & 7)");
     let test5 = Program::new(vec![
@@ -70,30 +85,33 @@ fn main() {
         Instruction::Ret
     ]);
 
-    println!("[Example 4] Result: {}", invoker.execute(&*compiler.compile(&test5)));
+    println!("[Example 4] Result: {}", backend.lower(&test5)?.run()?);
 
     println!("[Example 5] JmpIfNot test with 0 (should jump)");
     let jump_test_zero = Program::new(vec![
         Instruction::Load(0),           /* load 0 to represent false*/
         Instruction::JmpIfNot(1),
         Instruction::Load(999),
+        Instruction::Ret,
         Instruction::Label(1),
         Instruction::Load(42),
         Instruction::Ret               /* Should return 42 */
     ]);
-    println!("[Example 5] Result: {}", invoker.execute(&*compiler.compile(&jump_test_zero)));
+    println!("[Example 5] Result: {}", backend.lower(&jump_test_zero)?.run()?);
 
     println!("[Example 6] JmpIfNot test with 1 (should not jump)");
     let jump_test_one = Program::new(vec![
         Instruction::Load(1), /* load 1 to represent true */
         Instruction::JmpIfNot(1),
         Instruction::Load(999),
-        Instruction::Label(1),
         Instruction::Load(42),
         Instruction::Add, /* 999 + 42 = 1041 */
+        Instruction::Ret,
+        Instruction::Label(1),
+        Instruction::Load(42),
         Instruction::Ret
     ]);
-    println!("[Example 6] Result (should be 1041): {}", invoker.execute(&*compiler.compile(&jump_test_one)));
+    println!("[Example 6] Result (should be 1041): {}", backend.lower(&jump_test_one)?.run()?);
 
     println!("[Example 7] Simple comparison test: 1 <= 5");
     let cmp_test = Program::new(vec![
@@ -102,7 +120,7 @@ fn main() {
         Instruction::Lte, /* push 1 */
         Instruction::Ret
     ]);
-    println!("[Example 7] Result: {}", invoker.execute(&*compiler.compile(&cmp_test)));
+    println!("[Example 7] Result: {}", backend.lower(&cmp_test)?.run()?);
 
     println!("[Example 8] Loop from 0 to 10");
     let loop_test = Program::new(vec![
@@ -136,5 +154,7 @@ fn main() {
         Instruction::Ret, /* Return i */
     ]);
 
-    println!("[Example 8] Result: {}", invoker.execute(&*compiler.compile(&loop_test)));
+    println!("[Example 8] Result: {}", backend.lower(&loop_test)?.run()?);
+
+    Ok(())
 }
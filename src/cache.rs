@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::compiler::{verify, CodegenMode, CompileError, CompileOptions, Compiler, OptLevel, CODEGEN_VERSION};
+use crate::invoker::{Function, InvokeError, Invoker};
+use crate::program::{Instruction, Program};
+
+/*
+    Everything that can go wrong while looking up a program.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheError {
+    Compile(CompileError),
+    Invoke(InvokeError),
+}
+
+impl fmt::Display for CacheError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheError::Compile(e) => write!(f, "{}", e),
+            CacheError::Invoke(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for CacheError {}
+
+impl From<CompileError> for CacheError {
+    fn from(e: CompileError) -> Self {
+        CacheError::Compile(e)
+    }
+}
+
+impl From<InvokeError> for CacheError {
+    fn from(e: InvokeError) -> Self {
+        CacheError::Invoke(e)
+    }
+}
+
+/*
+    What the cache did so far.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,          /* Found loaded in memory */
+    pub disk_hits: u64,     /* Found in the cache directory, loaded without compiling */
+    pub misses: u64,        /* Compiled */
+    pub evictions: u64,     /* Dropped from memory to stay within capacity */
+}
+
+struct Entry {
+    key: Vec<u8>,               /* The whole key, the hash alone could collide */
+    function: Rc<Function>,
+    used: u64,                  /* Tick of the last lookup, the lowest is evicted first */
+}
+
+/*
+    Compiled programs, loaded into executable memory and kept around.
+
+    Programs are looked up by a hash of their instructions and the options
+    they're compiled with, which is the same from one run to the next, and
+    at most `capacity` of them are kept loaded, dropping whichever was used
+    least recently. With a directory the machine code is also written there,
+    so later processes can load it instead of compiling it again. Whatever is
+    in that directory gets executed, so it must be somewhere only trusted
+    users can write to.
+*/
+pub struct CodeCache {
+    capacity: usize,
+    dir: Option<PathBuf>,
+    entries: HashMap<u64, Entry>,
+    tick: u64,
+    stats: CacheStats,
+}
+
+/*
+    Start of every file in the cache directory, then the key length, the key and the code.
+*/
+const MAGIC: &[u8; 4] = b"cjit";
+
+impl CodeCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            dir: None,
+            entries: HashMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /**
+        Also persist machine code to `dir`, creating it if needed.
+
+        # Safety
+        Code found in `dir` is loaded and run as is, so only trusted users
+        may be able to write there, and only caches may write the files.
+    */
+    pub unsafe fn with_dir(capacity: usize, dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir: Some(dir), ..Self::new(capacity) })
+    }
+
+    /*
+        The loaded code for a program, compiling it only if it isn't in memory
+        or on disk yet. The function stays valid after it is evicted.
+
+        The function can be called without `unsafe`, so the program is always
+        verified before any code is loaded for it, whatever the options say.
+    */
+    pub fn get(&mut self, program: &Program, options: &CompileOptions) -> Result<Rc<Function>, CacheError> {
+        let key = key(program, options);
+        let hash = fnv1a(&key);
+        self.tick += 1;
+
+        if let Some(entry) = self.entries.get_mut(&hash)
+            && entry.key == key
+        {
+            entry.used = self.tick;
+            self.stats.hits += 1;
+            return Ok(entry.function.clone());
+        }
+
+        verify(program)?;
+        let code = match self.read(hash, &key) {
+            Some(code) => {
+                self.stats.disk_hits += 1;
+                code
+            }
+            None => {
+                self.stats.misses += 1;
+                let code = Compiler::with_options(*options).compile(program)?;
+
+                /*
+                    The directory only saves later processes compiling it again,
+                    so if it can't be written the code is just kept in memory.
+                */
+                let _ = self.write(hash, &key, &code);
+                code
+            }
+        };
+
+        /*
+            The program passed verification, and the code was either just compiled or read back from
+            the directory, which only trusted users can write to, as the caller of `with_dir` promised.
+        */
+        let function = Rc::new(unsafe { Invoker::new().load(&code) }?);
+        self.entries.insert(hash, Entry { key, function: function.clone(), used: self.tick });
+        self.evict();
+        Ok(function)
+    }
+
+    /*
+        The hash programs are looked up by, also naming their files in the cache directory.
+    */
+    pub fn hash(program: &Program, options: &CompileOptions) -> u64 {
+        fnv1a(&key(program, options))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /*
+        Unload everything, leaving the cache directory alone.
+    */
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    fn evict(&mut self) {
+        while self.entries.len() > self.capacity {
+            let Some((&oldest, _)) = self.entries.iter().min_by_key(|(_, entry)| entry.used) else {
+                break;
+            };
+
+            self.entries.remove(&oldest);
+            self.stats.evictions += 1;
+        }
+    }
+
+    fn path(&self, hash: u64) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(format!("{:016x}.bin", hash)))
+    }
+
+    /*
+        Code from the cache directory, if it has a file for exactly this key.
+        Anything unreadable or malformed is ignored and compiled over.
+    */
+    fn read(&self, hash: u64, key: &[u8]) -> Option<Vec<u8>> {
+        let bytes = fs::read(self.path(hash)?).ok()?;
+        let rest = bytes.strip_prefix(MAGIC)?;
+        let (len, rest) = rest.split_first_chunk::<4>()?;
+        let len = u32::from_le_bytes(*len) as usize;
+
+        if rest.get(..len)? != key {
+            return None;
+        }
+
+        Some(rest[len..].to_vec())
+    }
+
+    /*
+        Write to a temporary file first, so no other process ever reads half a file.
+    */
+    fn write(&self, hash: u64, key: &[u8], code: &[u8]) -> io::Result<()> {
+        let Some(path) = self.path(hash) else {
+            return Ok(());
+        };
+
+        let mut bytes = Vec::with_capacity(MAGIC.len() + 4 + key.len() + code.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(key);
+        bytes.extend_from_slice(code);
+
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp, bytes)?;
+        fs::rename(&tmp, &path)
+    }
+}
+
+/*
+    Everything the machine code depends on, as bytes: the version of the
+    code generator and of the crate, the options and every instruction with
+    its operand.
+*/
+fn key(program: &Program, options: &CompileOptions) -> Vec<u8> {
+    let mut key = Vec::with_capacity(20 + program.insts().len() * 9);
+    key.extend_from_slice(&CODEGEN_VERSION.to_le_bytes());
+    key.extend_from_slice(env!("CARGO_PKG_VERSION").as_bytes());
+    key.push(0);
+
+    key.push(match options.opt_level {
+        OptLevel::O0 => 0,
+        OptLevel::O1 => 1,
+        OptLevel::O2 => 2,
+        OptLevel::O3 => 3,
+    });
+    key.push(match options.mode {
+        None => 0,
+        Some(CodegenMode::Stack) => 1,
+        Some(CodegenMode::Cached) => 2,
+        Some(CodegenMode::Ssa) => 3,
+    });
+    key.push(options.verify as u8);
+    key.push(options.emit_source_map as u8);
+
+    for inst in program.insts() {
+        let (op, operand) = encode(inst);
+        key.push(op);
+        if let Some(operand) = operand {
+            key.extend_from_slice(&operand.to_le_bytes());
+        }
+    }
+
+    key
+}
+
+/*
+    A number for each instruction which never changes, and its operand if it has one.
+*/
+fn encode(inst: &Instruction) -> (u8, Option<i64>) {
+    match *inst {
+        Instruction::Load(v) => (0, Some(v)),
+        Instruction::Dup => (1, None),
+        Instruction::Pop => (2, None),
+        Instruction::Swap => (3, None),
+        Instruction::Add => (4, None),
+        Instruction::Sub => (5, None),
+        Instruction::Mul => (6, None),
+        Instruction::Div => (7, None),
+        Instruction::Mod => (8, None),
+        Instruction::Neg => (9, None),
+        Instruction::Eq => (10, None),
+        Instruction::Ne => (11, None),
+        Instruction::Lt => (12, None),
+        Instruction::Gt => (13, None),
+        Instruction::Lte => (14, None),
+        Instruction::Gte => (15, None),
+        Instruction::And => (16, None),
+        Instruction::Or => (17, None),
+        Instruction::Not => (18, None),
+        Instruction::Band => (19, None),
+        Instruction::Bor => (20, None),
+        Instruction::Bxor => (21, None),
+        Instruction::Bnot => (22, None),
+        Instruction::Shl => (23, None),
+        Instruction::Shr => (24, None),
+        Instruction::Store(id) => (25, Some(id as i64)),
+        Instruction::LoadVar(id) => (26, Some(id as i64)),
+        Instruction::Jmp(label) => (27, Some(label as i64)),
+        Instruction::JmpIf(label) => (28, Some(label as i64)),
+        Instruction::JmpIfNot(label) => (29, Some(label as i64)),
+        Instruction::Label(id) => (30, Some(id as i64)),
+        Instruction::Call(label) => (31, Some(label as i64)),
+        Instruction::Write => (32, None),
+        Instruction::WriteChar => (33, None),
+        Instruction::Read => (34, None),
+        Instruction::Ret => (35, None),
+        Instruction::Halt => (36, None),
+        Instruction::LoadF(v) => (37, Some(v.to_bits() as i64)),
+        Instruction::FAdd => (38, None),
+        Instruction::FSub => (39, None),
+        Instruction::FMul => (40, None),
+        Instruction::FDiv => (41, None),
+        Instruction::FNeg => (42, None),
+        Instruction::FSqrt => (43, None),
+        Instruction::FEq => (44, None),
+        Instruction::FNe => (45, None),
+        Instruction::FLt => (46, None),
+        Instruction::FGt => (47, None),
+        Instruction::FLte => (48, None),
+        Instruction::FGte => (49, None),
+        Instruction::IToF => (50, None),
+        Instruction::FToI => (51, None),
+        Instruction::UDiv => (52, None),
+        Instruction::UMod => (53, None),
+        Instruction::LShr => (54, None),
+        Instruction::ULt => (55, None),
+        Instruction::UGt => (56, None),
+        Instruction::ULte => (57, None),
+        Instruction::UGte => (58, None),
+        Instruction::Over => (59, None),
+        Instruction::Rot => (60, None),
+        Instruction::Nip => (61, None),
+        Instruction::Tuck => (62, None),
+        Instruction::Pick(n) => (63, Some(n as i64)),
+        Instruction::Roll(n) => (64, Some(n as i64)),
+    }
+}
+
+/*
+    64 bit FNV-1a, which unlike the standard library's hasher is the same everywhere.
+*/
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[cfg(test)]
+mod tests;
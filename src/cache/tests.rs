@@ -0,0 +1,152 @@
+use super::*;
+use crate::program::Instruction::*;
+
+fn program(n: i64) -> Program {
+    Program::new(vec![Load(n), Load(2), Mul, Ret])
+}
+
+/*
+    A fresh directory of its own for each test.
+*/
+fn scratch_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cjit-cache-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn second_lookup_is_a_hit() {
+    let mut cache = CodeCache::new(4);
+    let options = CompileOptions::default();
+
+    let first = cache.get(&program(21), &options).unwrap();
+    let second = cache.get(&program(21), &options).unwrap();
+
+    assert!(Rc::ptr_eq(&first, &second));
+    assert_eq!(second.call(), 42);
+    assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, ..CacheStats::default() });
+}
+
+#[test]
+fn options_are_part_of_the_key() {
+    let mut cache = CodeCache::new(4);
+    let o0 = CompileOptions { opt_level: OptLevel::O0, ..CompileOptions::default() };
+    let o3 = CompileOptions { opt_level: OptLevel::O3, ..CompileOptions::default() };
+
+    assert_ne!(CodeCache::hash(&program(1), &o0), CodeCache::hash(&program(1), &o3));
+    assert_ne!(CodeCache::hash(&program(1), &o0), CodeCache::hash(&program(2), &o0));
+    assert_eq!(CodeCache::hash(&program(1), &o0), CodeCache::hash(&program(1), &o0));
+
+    assert_eq!(cache.get(&program(1), &o0).unwrap().call(), 2);
+    assert_eq!(cache.get(&program(1), &o3).unwrap().call(), 2);
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.stats().misses, 2);
+}
+
+#[test]
+fn least_recently_used_is_evicted() {
+    let mut cache = CodeCache::new(2);
+    let options = CompileOptions::default();
+
+    let kept = cache.get(&program(1), &options).unwrap();
+    cache.get(&program(2), &options).unwrap();
+    cache.get(&program(1), &options).unwrap();
+    cache.get(&program(3), &options).unwrap();     /* 2 was used longest ago */
+
+    assert_eq!(cache.len(), 2);
+    assert_eq!(cache.stats().evictions, 1);
+
+    cache.get(&program(1), &options).unwrap();
+    cache.get(&program(3), &options).unwrap();
+    assert_eq!(cache.stats().hits, 3);
+
+    cache.get(&program(2), &options).unwrap();
+    assert_eq!(cache.stats().misses, 4);
+    assert_eq!(kept.call(), 2);                     /* still loaded, whatever the cache did */
+}
+
+#[test]
+fn zero_capacity_keeps_nothing() {
+    let mut cache = CodeCache::new(0);
+    let function = cache.get(&program(5), &CompileOptions::default()).unwrap();
+
+    assert_eq!(function.call(), 10);
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn compile_errors_are_passed_on() {
+    let mut cache = CodeCache::new(2);
+    let err = cache.get(&Program::new(vec![Jmp(3)]), &CompileOptions::default());
+
+    assert_eq!(err.unwrap_err(), CacheError::Compile(CompileError::UndefinedLabel(3)));
+    assert!(cache.is_empty());
+}
+
+/*
+    Unverified, this would pop the return address and return into the loads.
+*/
+#[test]
+fn programs_are_always_verified() {
+    let mut insts = vec![Pop; 300];
+    insts.extend([Load(0x4141); 300]);
+    insts.push(Ret);
+
+    let mut cache = CodeCache::new(2);
+    let err = cache.get(&Program::new(insts), &CompileOptions::default());
+    assert_eq!(err.unwrap_err(), CacheError::Compile(CompileError::StackUnderflow(0)));
+    assert!(cache.is_empty());
+}
+
+#[test]
+fn code_persists_across_caches() {
+    let dir = scratch_dir("persist");
+    let options = CompileOptions::default();
+
+    let mut cache = unsafe { CodeCache::with_dir(2, &dir) }.unwrap();
+    assert_eq!(cache.get(&program(7), &options).unwrap().call(), 14);
+    assert_eq!(cache.stats().misses, 1);
+
+    let mut cache = unsafe { CodeCache::with_dir(2, &dir) }.unwrap();
+    assert_eq!(cache.get(&program(7), &options).unwrap().call(), 14);
+    assert_eq!(cache.stats(), CacheStats { disk_hits: 1, ..CacheStats::default() });
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn bad_files_are_compiled_over() {
+    let dir = scratch_dir("corrupt");
+    let options = CompileOptions::default();
+    let mut cache = unsafe { CodeCache::with_dir(2, &dir) }.unwrap();
+    let path = cache.path(CodeCache::hash(&program(4), &options)).unwrap();
+
+    fs::write(&path, b"cjit\xff\xff\xff\xff").unwrap();
+    assert_eq!(cache.get(&program(4), &options).unwrap().call(), 8);
+    assert_eq!(cache.stats().misses, 1);
+
+    let mut cache = unsafe { CodeCache::with_dir(2, &dir) }.unwrap();
+    assert_eq!(cache.get(&program(4), &options).unwrap().call(), 8);
+    assert_eq!(cache.stats().disk_hits, 1);
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unwritable_dir_is_not_an_error() {
+    let dir = scratch_dir("unwritable");
+    let options = CompileOptions::default();
+    let mut cache = unsafe { CodeCache::with_dir(2, &dir) }.unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(cache.get(&program(5), &options).unwrap().call(), 10);
+    assert_eq!(cache.get(&program(5), &options).unwrap().call(), 10);
+    assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, ..CacheStats::default() });
+    assert!(!dir.exists());
+}
+
+#[test]
+fn codegen_version_is_part_of_the_key() {
+    let key = key(&program(1), &CompileOptions::default());
+    assert_eq!(key[..4], CODEGEN_VERSION.to_le_bytes());
+}
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::compiler::{CompileError, EXIT_LABEL};
+use crate::program::{Instruction, Program};
+
+/*
+    Index of a block in its `Cfg`.
+*/
+pub type BlockId = usize;
+
+/*
+    A run of instructions which is only ever entered at the top and left at the bottom.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub range: Range<usize>,        /* Indices of its instructions in the program */
+    pub succs: Vec<BlockId>,        /* Jump target first, then the fall through */
+    pub preds: Vec<BlockId>,
+}
+
+/*
+    The control flow graph of a program.
+
+    A block starts at the first instruction, at every `Label` and after every
+    jump, `Ret` and `Halt`, and ends right before the next one starts. Like the
+    backends, everything after the first `Halt` is ignored, and programs with
+    duplicate or missing labels are rejected with the same errors.
+*/
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    insts: Vec<Instruction>,
+    blocks: Vec<Block>,
+    labels: HashMap<u32, BlockId>,      /* The block each label starts */
+}
+
+impl Cfg {
+    pub fn build(program: &Program) -> Result<Self, CompileError> {
+        let insts = program.insts();
+        let end = insts.iter().position(|i| matches!(i, Instruction::Halt)).map_or(insts.len(), |pc| pc + 1);
+        let insts = insts[..end].to_vec();
+
+        let mut starts = vec![];
+        for (pc, inst) in insts.iter().enumerate() {
+            if pc == 0 || matches!(inst, Instruction::Label(_)) || ends_block(&insts[pc - 1]) {
+                starts.push(pc);
+            }
+        }
+
+        let mut blocks: Vec<Block> = starts
+            .iter()
+            .enumerate()
+            .map(|(id, &start)| Block {
+                range: start..starts.get(id + 1).copied().unwrap_or(insts.len()),
+                succs: vec![],
+                preds: vec![],
+            })
+            .collect();
+
+        let mut labels = HashMap::new();
+        for (id, block) in blocks.iter().enumerate() {
+            if let Instruction::Label(label) = insts[block.range.start] {
+                if label == EXIT_LABEL {
+                    return Err(CompileError::ReservedLabel(label));
+                }
+
+                if labels.insert(label, id).is_some() {
+                    return Err(CompileError::DuplicateLabel(label));
+                }
+            }
+        }
+
+        for id in 0..blocks.len() {
+            let next = (id + 1 < blocks.len()).then_some(id + 1);
+            let target = |label: u32| labels.get(&label).copied().ok_or(CompileError::UndefinedLabel(label));
+
+            let succs = match insts[blocks[id].range.end - 1] {
+                Instruction::Jmp(label) => vec![target(label)?],
+                Instruction::JmpIf(label) | Instruction::JmpIfNot(label) => {
+                    let mut succs = vec![target(label)?];
+                    succs.extend(next.filter(|&next| next != succs[0]));
+                    succs
+                }
+                Instruction::Ret | Instruction::Halt => vec![],
+                _ => next.into_iter().collect(),
+            };
+
+            for &succ in &succs {
+                blocks[succ].preds.push(id);
+            }
+            blocks[id].succs = succs;
+        }
+
+        Ok(Self { insts, blocks, labels })
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    pub fn block(&self, id: BlockId) -> &Block {
+        &self.blocks[id]
+    }
+
+    /*
+        The instructions of a block.
+    */
+    pub fn insts(&self, id: BlockId) -> &[Instruction] {
+        &self.insts[self.blocks[id].range.clone()]
+    }
+
+    /*
+        The block a label starts.
+    */
+    pub fn label(&self, label: u32) -> Option<BlockId> {
+        self.labels.get(&label).copied()
+    }
+
+    /*
+        Every block reachable from the entry, each one before its successors
+        except where a loop leads back to it. Unreachable blocks are left out.
+    */
+    pub fn reverse_post_order(&self) -> Vec<BlockId> {
+        let mut order = vec![];
+        let mut seen = vec![false; self.blocks.len()];
+        let mut stack = vec![];                 /* (block, index of the next successor to visit) */
+
+        if !self.blocks.is_empty() {
+            seen[0] = true;
+            stack.push((0, 0));
+        }
+
+        while let Some((id, next)) = stack.last_mut() {
+            match self.blocks[*id].succs.get(*next) {
+                Some(&succ) => {
+                    *next += 1;
+                    if !seen[succ] {
+                        seen[succ] = true;
+                        stack.push((succ, 0));
+                    }
+                }
+                None => {
+                    order.push(*id);
+                    stack.pop();
+                }
+            }
+        }
+
+        order.reverse();
+        order
+    }
+}
+
+/*
+    Whether the instruction after `inst` starts a new block.
+*/
+fn ends_block(inst: &Instruction) -> bool {
+    matches!(
+        inst,
+        Instruction::Jmp(_) | Instruction::JmpIf(_) | Instruction::JmpIfNot(_) | Instruction::Ret | Instruction::Halt
+    )
+}
+
+#[cfg(test)]
+mod tests;
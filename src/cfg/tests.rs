@@ -0,0 +1,88 @@
+use super::*;
+use crate::program::Instruction::*;
+
+fn cfg(insts: Vec<Instruction>) -> Cfg {
+    Cfg::build(&Program::new(insts)).unwrap()
+}
+
+#[test]
+fn empty() {
+    let cfg = cfg(vec![]);
+    assert!(cfg.blocks().is_empty());
+    assert!(cfg.reverse_post_order().is_empty());
+}
+
+#[test]
+fn straight_line() {
+    let cfg = cfg(vec![Load(1), Load(2), Add, Ret]);
+    assert_eq!(cfg.blocks().len(), 1);
+    assert_eq!(cfg.insts(0), [Load(1), Load(2), Add, Ret]);
+    assert!(cfg.block(0).succs.is_empty());
+}
+
+/*
+    if (x) { 1 } else { 2 }
+*/
+#[test]
+fn diamond() {
+    let cfg = cfg(vec![
+        LoadVar(0), JmpIfNot(1),            /* 0 */
+        Load(1), Jmp(2),                    /* 1 */
+        Label(1), Load(2),                  /* 2 */
+        Label(2), Ret,                      /* 3 */
+    ]);
+
+    assert_eq!(cfg.blocks().len(), 4);
+    assert_eq!(cfg.block(0).succs, [2, 1]);
+    assert_eq!(cfg.block(1).succs, [3]);
+    assert_eq!(cfg.block(2).succs, [3]);
+    assert_eq!(cfg.block(3).preds, [1, 2]);
+    assert_eq!(cfg.label(1), Some(2));
+    assert_eq!(cfg.reverse_post_order(), [0, 1, 2, 3]);
+}
+
+#[test]
+fn loop_back_edge() {
+    let cfg = cfg(vec![
+        Load(0), Store(0),                  /* 0 */
+        Label(1), LoadVar(0), JmpIfNot(2),  /* 1 */
+        Load(0), Store(0), Jmp(1),          /* 2 */
+        Label(2), Ret,                      /* 3 */
+    ]);
+
+    assert_eq!(cfg.block(1).preds, [0, 2]);
+    assert_eq!(cfg.block(2).succs, [1]);
+    assert_eq!(cfg.reverse_post_order(), [0, 1, 2, 3]);
+}
+
+#[test]
+fn unreachable_blocks_are_not_ordered() {
+    let cfg = cfg(vec![Ret, Load(1), Label(1), Ret]);
+    assert_eq!(cfg.blocks().len(), 3);
+    assert!(cfg.block(1).preds.is_empty());
+    assert_eq!(cfg.block(1).succs, [2]);
+    assert_eq!(cfg.reverse_post_order(), [0]);
+}
+
+#[test]
+fn jump_to_the_next_block_is_one_edge() {
+    let cfg = cfg(vec![Load(1), JmpIf(1), Label(1), Ret]);
+    assert_eq!(cfg.block(0).succs, [1]);
+    assert_eq!(cfg.block(1).preds, [0]);
+}
+
+#[test]
+fn halt_ends_the_program() {
+    let cfg = cfg(vec![Load(1), Halt, Label(1), Ret]);
+    assert_eq!(cfg.blocks().len(), 1);
+    assert_eq!(cfg.insts(0), [Load(1), Halt]);
+}
+
+#[test]
+fn bad_labels() {
+    let build = |insts| Cfg::build(&Program::new(insts)).map(|_| ());
+    assert_eq!(build(vec![Jmp(3)]), Err(CompileError::UndefinedLabel(3)));
+    assert_eq!(build(vec![Label(1), Label(1)]), Err(CompileError::DuplicateLabel(1)));
+    assert_eq!(build(vec![Label(EXIT_LABEL)]), Err(CompileError::ReservedLabel(EXIT_LABEL)));
+    assert_eq!(build(vec![Load(1), Halt, Jmp(3)]), Ok(()));
+}
@@ -1,166 +1,451 @@
-#[cfg(unix)]
-use libc;
+mod float;
+mod locals;
+mod lower;
+mod options;
+mod regalloc;
+mod strength;
 
-#[cfg(windows)]
-use winapi::um::{
-    memoryapi::{VirtualAlloc, VirtualFree, VirtualProtect},
-    winnt::{MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_READWRITE, PAGE_EXECUTE_READ},
-};
+pub use options::{CompileOptions, OptLevel, SourceMap};
 
-#[cfg(windows)]
-use winapi::shared::minwindef::DWORD;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 
-#[cfg(windows)]
-use std::ptr;
-
-use std::collections::HashMap;
+use crate::assembler::{Assembler, Cond, Label, Mem, PeepholeStats, Reg};
+use crate::ir::{self, BuildError};
+use crate::opt;
+use crate::program::{Instruction, Program};
 
 /*
     Define where the exit position is in the bytecode.
 */
-const EXIT_LABEL: u32 = u32::MAX;
+pub const EXIT_LABEL: u32 = u32::MAX;
 
 /*
-    Updated Instruction set
+    Which machine code the compiler generates for a given program and options.
+    Code cached on disk is only reused while this matches, so bump it with any
+    change to the code that comes out, however small.
 */
-#[derive(Debug, Clone)]
-pub enum Instruction {
-    Load(i64),      /* Load an immediate value onto the stack :D */
-    Dup,            /* Duplicate stack top value */
-    Pop,            /* Pop stack top value */
-    Swap,           /* Swap stack two top value */
-    Add,            /* Self explanatory, stack based, pop values, add them, push result */
-    Sub,            /* Self explanatory, stack based, pop values, sub them, push result */
-    Mul,            /* Self explanatory, stack based, pop values, mul them, push result */
-    Div,            /* Self explanatory, stack based, pop values, div them, push result */
-    Mod,            /* Self explanatory, stack based, pop values, mod them, push result */
-    Neg,            /* Negate the stack top value */
-    Eq,             /* Comparison, == */
-    Ne,             /* Comparison, != */
-    Lt,             /* Comparison, < */
-    Gt,             /* Comparison, > */
-    Lte,            /* Comparison, <= */
-    Gte,            /* Comparison, >= */
-    And,            /* Logical, && */
-    Or,             /* Logical, || */
-    Not,            /* Logical, ! or NOT */
-    Band,           /* Bit, and */
-    Bor,            /* Bit, or */
-    Bxor,           /* Bit, xor */
-    Bnot,           /* Bit, not */
-    Shl,            /* Bit, shift left */
-    Shr,            /* Bit, shift right */
-    Store(u32),     /* Variable, store top of stack to a labelled local variable */
-    LoadVar(u32),   /* Variable, load labelled local variable value to stack */
-    Jmp(u32),       /* Conditional, an unconditional jump to a label */
-    JmpIf(u32),     /* Conditional, a conditional jump to a label, condition is true if top stack value is not zero */
-    JmpIfNot(u32),  /* Conditional, a conditional jump to a label, condition is true if top stack value is zero */
-    Label(u32),     /* Control Flow?, define a label for jumps, funcstions etc */
-    Call(u32),      /* Control Flow, call a function via label */
-    Write,          /* Write the top of the stack value to the console */
-    WriteChar,      /* Write the top of the stack value as a char */
-    Read,           /* read an int from stdin */
-    Ret,            /* Return from function */
-    Halt,           /* Stop execution */
-}
+pub(crate) const CODEGEN_VERSION: u32 = 7;
 
 /*
-    A structure to represent a program in the form of bytecode instructions.
+    Everything that can go wrong while compiling a program.
 */
-pub struct Program {
-    insts: Vec<Instruction>,
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    ReservedLabel(u32),     /* A program defined the label reserved for the exit */
+    DuplicateLabel(u32),    /* The same label was defined twice */
+    UndefinedLabel(u32),    /* A jump targets a label which is never defined */
+    StackUnderflow(usize),  /* Instruction pops more than can be on the stack, when verifying */
+    UnbalancedStack(usize), /* Paths into the instruction leave the stack at different depths, when verifying */
+    FrameTooLarge,          /* Variables and spill slots need more frame than rbp can address */
 }
 
-impl Program {
-    pub fn new(insts: Vec<Instruction>) -> Self {
-        Self { insts }
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompileError::ReservedLabel(id) => write!(f, "label id {} is reserved", id),
+            CompileError::DuplicateLabel(id) => write!(f, "label {} is defined more than once", id),
+            CompileError::UndefinedLabel(id) => write!(f, "jump to undefined label {}", id),
+            CompileError::StackUnderflow(pc) => write!(f, "instruction {} underflows the operand stack", pc),
+            CompileError::UnbalancedStack(pc) => write!(f, "stack depth differs between paths into instruction {}", pc),
+            CompileError::FrameTooLarge => write!(f, "variables don't fit in a stack frame"),
+        }
     }
 }
 
+impl std::error::Error for CompileError {}
+
+/*
+    How the operand stack is laid out in the generated code.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodegenMode {
+    #[default]
+    Stack,      /* Every slot lives on the machine stack */
+    Cached,     /* The top two slots live in rax/rbx, the rest are spilled to the machine stack */
+    Ssa,        /* Go through the SSA form and optimise it, or `Cached` if the stack is not balanced */
+}
+
+/*
+    Registers the top of the operand stack is cached in, in `CodegenMode::Cached`.
+*/
+const CACHE_REGS: [Reg; 2] = [Reg::Rax, Reg::Rbx];
+
+/*
+    How many slots a Roll moves with straight line code, deeper ones loop.
+*/
+const UNROLLED_ROLL: usize = 4;
+
+/*
+    Frame space for variables, at [rbp - (id + 1) * 8], at least.
+*/
+const VAR_FRAME: i32 = 1024;
+
 /*
-    A structure to represent a compiler instance, containing the bytecode and stack offset.
+    A structure to represent a compiler instance, containing the assembler and stack offset.
 */
 pub struct Compiler {
-    bytecode: Vec<u8>,                  /* Bytecode, a "string" of bytes to represent code */
+    asm: Assembler,                     /* Assembler the machine code is emitted into */
+    options: CompileOptions,            /* What the compiler was asked to do */
+    mode: CodegenMode,                  /* Where operand stack slots are kept */
+    cache: Vec<Reg>,                    /* Registers holding the top stack slots, bottom first */
+    vars: HashMap<u32, Reg>,            /* Variables kept in registers instead of the frame */
+    zeroed: Vec<u32>,                   /* Variables in the frame which are loaded anywhere, for the prologue to zero */
+    frame: i32,                         /* Bytes the prologue reserves below rbp */
+    slots: i32,                         /* Where the spill slots of SSA values start below rbp */
+    alloc: regalloc::Allocation,        /* Where each SSA value lives */
     stk_offset: i32,                    /* Stack Offset */
-    labels: HashMap<u32, usize>,        /* Map labels to position in bytecode, for example label 1 could be mapped to position 56 in the bytecode */
-    label_patches: Vec<(usize, u32)>    /* All patches needed for labels for forward jmps */
+    labels: HashMap<u32, Label>,        /* Map bytecode labels to assembler labels, for example label 1 could be mapped to assembler label 3 */
+    defined: HashSet<u32>,              /* Bytecode labels defined so far */
+    jumps: Vec<u32>,                    /* Bytecode labels jumped to, in order, to report undefined ones */
+    depths: HashMap<u32, i32>,          /* Stack offset at the first jump to each label, for labels only reached by jumping */
+    stats: PeepholeStats,               /* What the peephole optimiser did to the last program */
+    source_map: Option<SourceMap>,      /* Where each instruction of the last program went, if asked for */
 }
 
 impl Compiler {
     pub fn new() -> Self {
+        Self::with_options(CompileOptions::default())
+    }
+
+    pub fn with_mode(mode: CodegenMode) -> Self {
+        Self::with_options(CompileOptions { mode: Some(mode), ..CompileOptions::default() })
+    }
+
+    pub fn with_options(options: CompileOptions) -> Self {
         Self {
-            bytecode: vec![],
+            asm: Assembler::new(),
+            options,
+            mode: options.mode(),
+            cache: vec![],
+            vars: HashMap::new(),
+            zeroed: vec![],
+            frame: VAR_FRAME,
+            slots: VAR_FRAME,
+            alloc: regalloc::Allocation::default(),
             stk_offset: 0,
             labels: HashMap::new(),
-            label_patches: vec![],
+            defined: HashSet::new(),
+            jumps: vec![],
+            depths: HashMap::new(),
+            stats: PeepholeStats::default(),
+            source_map: None,
         }
     }
 
+    pub fn mode(&self) -> CodegenMode {
+        self.mode
+    }
+
+    pub fn options(&self) -> &CompileOptions {
+        &self.options
+    }
+
+    /*
+        Where each instruction of the last program compiled starts in its code,
+        when `emit_source_map` is on.
+    */
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    /*
+        Code size before and after the peephole optimiser, for the last program compiled.
+    */
+    pub fn peephole_stats(&self) -> PeepholeStats {
+        self.stats
+    }
+
     /*
-        Emit a byte to the bytecode buffer.
+        Get the assembler label for a bytecode label, creating it on first use.
     */
-    fn emit(&mut self, bytes: &[u8]) {
-        self.bytecode.extend_from_slice(bytes); /* Bytes cloned? Let's use extend_from_slice */
+    fn label(&mut self, id: u32) -> Label {
+        *self.labels.entry(id).or_insert_with(|| self.asm.new_label())
+    }
+
+    /*
+        The callee saved registers variables were given, in the order they are saved.
+    */
+    fn var_regs(&self) -> Vec<Reg> {
+        locals::VAR_REGS.into_iter().filter(|reg| self.vars.values().any(|r| r == reg)).collect()
     }
 
     /*
         Emit function prologue, set up the function.
+        Variables start out as zero, like the interpreter's, those in the
+        frame only if they're ever loaded.
     */
     fn emit_fn_prologue(&mut self) {
-        self.emit(&[0x55]);                 /* push rbp */
-        self.emit(&[0x48,0x89,0xE5]);       /* mov rbp, rsp */
-        self.emit(&[                        /* sub rsp, 1024 ; allocate enough stack space */
-            0x48,0x81,0xEC,0x00,
-            0x04,0x00,0x00
-        ]);
+        self.asm.push(Reg::Rbx);            /* callee saved, but the binops use it */
+        for reg in self.var_regs() {
+            self.asm.push(reg);
+        }
+        self.asm.push(Reg::Rbp);
+        self.asm.mov_rr(Reg::Rbp, Reg::Rsp);
+        self.asm.sub_ri(Reg::Rsp, self.frame);  /* allocate enough stack space */
+        for reg in self.var_regs() {
+            self.asm.mov_ri(reg, 0);
+        }
+        if !self.zeroed.is_empty() {
+            self.asm.mov_ri(Reg::Rax, 0);
+        }
+        for &id in &self.zeroed {
+            let offset = var_offset(id).expect("frame_size checks every variable");
+            self.asm.mov_mr(Mem::new(Reg::Rbp, -offset), Reg::Rax);
+        }
     }
 
     /*
         Emit function epilogue, "end" the function.
     */
     fn emit_fn_epilogue(&mut self) {
-        self.emit(&[0x48,0x89,0xEC]);       /* mov rsp, rbp */
-        self.emit(&[0x5D]);                 /* pop rbp */
-        self.emit(&[0xC3]);                 /* ret */
+        self.asm.mov_rr(Reg::Rsp, Reg::Rbp);
+        self.asm.pop(Reg::Rbp);
+        for reg in self.var_regs().into_iter().rev() {
+            self.asm.pop(reg);
+        }
+        self.asm.pop(Reg::Rbx);
+        self.asm.ret();
+    }
+
+    /*
+        The operand stack, as seen by the emitters below. Values are pushed
+        from and popped into registers, and whether they really go through the
+        machine stack depends on the mode: in `Stack` mode every slot is pushed
+        straight away, in `Cached` mode the top slots stay in registers and the
+        bottom one is only pushed ("spilled") when a new one does not fit.
+
+        Jumps and labels are where different paths meet, so the cache is
+        flushed before both and every path agrees it is empty.
+    */
+    fn capacity(&self) -> usize {
+        match self.mode {
+            CodegenMode::Stack => 0,
+            CodegenMode::Cached | CodegenMode::Ssa => CACHE_REGS.len(),
+        }
+    }
+
+    fn spill(&mut self) {
+        let reg = self.cache.remove(0);
+        self.asm.push(reg);
+    }
+
+    fn flush(&mut self) {
+        while !self.cache.is_empty() {
+            self.spill();
+        }
+    }
+
+    /*
+        A register the next pushed value can be computed into, spilling if every one is taken.
+    */
+    fn alloc(&mut self) -> Reg {
+        if self.cache.len() == CACHE_REGS.len() {
+            self.spill();
+        }
+
+        *CACHE_REGS.iter().find(|reg| !self.cache.contains(reg)).unwrap()
+    }
+
+    fn push_slot(&mut self, reg: Reg) {
+        self.cache.push(reg);
+        self.stk_offset += 8;
+
+        if self.cache.len() > self.capacity() {
+            self.spill();
+        }
+    }
+
+    fn pop_slot(&mut self) -> Reg {
+        self.stk_offset -= 8;
+
+        match self.cache.pop() {
+            Some(reg) => reg,
+            None => {
+                self.asm.pop(Reg::Rax);
+                Reg::Rax
+            }
+        }
+    }
+
+    /*
+        Pop the top two slots, returning the registers of (first, second).
+    */
+    fn pop_pair(&mut self) -> (Reg, Reg) {
+        let second = match self.cache.pop() {
+            Some(reg) => reg,
+            None => {
+                self.asm.pop(Reg::Rbx);
+                Reg::Rbx
+            }
+        };
+
+        let first = match self.cache.pop() {
+            Some(reg) => reg,
+            None => {
+                let reg = if second == Reg::Rax { Reg::Rbx } else { Reg::Rax };
+                self.asm.pop(reg);
+                reg
+            }
+        };
+
+        self.stk_offset -= 16;
+        (first, second)
     }
 
     /*
         Load an immediate value (value included within the opcode,
-        not to be confused with values in a register or memory)
+        not to be confused with values in a register or memory).
+        Anything which fits a sign extended imm32 is pushed as is when it is
+        headed for the machine stack anyway, the rest goes through a register.
     */
     fn emit_load_imm(&mut self, val: i64) {
-        self.emit(&[0x48,0xB8]);            /* mov rax, <val> */
-        self.emit(&val.to_le_bytes());            /* Convert the immediate value into bytes and emit them */
-        self.emit(&[0x50]);                 /* push rax */
-        self.stk_offset += 8;                     /* Increment the stack offset by 8 */
+        if self.capacity() == 0
+            && let Ok(imm) = i32::try_from(val)
+        {
+            self.asm.push_i(imm);
+            self.stk_offset += 8;
+            return;
+        }
+
+        let reg = self.alloc();
+        self.asm.mov_ri(reg, val);
+        self.push_slot(reg);
     }
 
     /*
-        Perform a duplication of the top stack value
+        Push a copy of the slot `n` below the top, from its register if it is
+        cached, straight from [rsp + 8 * n] if not. Dup is pick 0 and Over pick 1.
     */
-    fn emit_dup(&mut self) {
-        self.emit(&[0x48,0x8B,0x04,0x24]);  /* mov rax, [rsp] */
-        self.emit(&[0x50]);                 /* push rax */
-        self.stk_offset += 8;
+    fn emit_pick(&mut self, n: u32) {
+        let reg = self.alloc();             /* may spill, so find the slot after */
+        let n = n as usize;
+
+        match self.cache.len().checked_sub(n + 1) {
+            Some(i) => self.asm.mov_rr(reg, self.cache[i]),
+            None => {
+                let slot = n - self.cache.len();
+                if self.emit_slot_check(slot) {
+                    self.asm.mov_rm(reg, stack_slot(slot));
+                }
+            }
+        }
+
+        self.push_slot(reg);
+    }
+
+    /*
+        Trap unless the machine stack holds a slot `n` below the top, that
+        is unless [rsp + 8 * n] is still above the frame. Whatever the
+        program did before, nothing under the frame, like the saved rbp or
+        the return address, is ever read or written as an operand:
+
+            mov r11, rbp
+            sub r11, rsp
+            sub r11, frame + 8 * n
+            jg ok
+            ud2
+        ok:
+
+        A slot too far down for any stack just traps, and false is returned
+        so no access is emitted for it.
+    */
+    fn emit_slot_check(&mut self, n: usize) -> bool {
+        let Some(bytes) = n.checked_mul(8).and_then(|b| i32::try_from(b).ok()).and_then(|b| b.checked_add(self.frame)) else {
+            self.asm.ud2();
+            return false;
+        };
+
+        let ok = self.asm.new_label();
+        self.asm.mov_rr(Reg::R11, Reg::Rbp);
+        self.asm.sub_rr(Reg::R11, Reg::Rsp);
+        self.asm.sub_ri(Reg::R11, bytes);
+        self.asm.jcc(Cond::G, ok);
+        self.asm.ud2();
+        self.asm.bind(ok);
+        true
     }
 
     /*
         Pop top stack value (simple)
     */
     fn emit_pop(&mut self) {
-        self.emit(&[0x58]);                 /* pop rax */
-        self.stk_offset -= 8;
+        self.pop_slot();
     }
 
     /*
-        Simply swap two top stack values
+        Simply swap two top stack values, which with a cache is just renaming the registers
     */
     fn emit_swap(&mut self) {
-        self.emit(&[0x5B]);                 /* pop rbx */
-        self.emit(&[0x58]);                 /* pop rax */
-        self.emit(&[0x53]);                 /* push rbx */
-        self.emit(&[0x50]);                 /* push rax */
+        let (first, second) = self.pop_pair();
+        self.push_slot(second);
+        self.push_slot(first);
+    }
+
+    /*
+        Move the slot `n` below the top to the top, shifting the ones above it
+        down. Within the cache that's renaming registers again, otherwise the
+        cache is flushed and each slot is moved up in place:
+
+            mov rax, [rsp + 8 * n]
+            mov rbx, [rsp + 8 * (n - 1)]; mov [rsp + 8 * n], rbx
+            ...
+            mov rbx, [rsp]; mov [rsp + 8], rbx
+            mov [rsp], rax
+
+        Past a few slots the moves are a loop instead, walking r11 down from
+        rsp + 8 * n to rsp.
+    */
+    fn emit_roll(&mut self, n: u32) {
+        let n = n as usize;
+        if n == 0 {
+            return;
+        }
+
+        if let Some(i) = self.cache.len().checked_sub(n + 1) {
+            let reg = self.cache.remove(i);
+            self.cache.push(reg);
+            return;
+        }
+
+        self.flush();
+        if !self.emit_slot_check(n) {
+            return;
+        }
+
+        self.asm.mov_rm(Reg::Rax, stack_slot(n));
+        if n <= UNROLLED_ROLL {
+            for i in (0..n).rev() {
+                self.asm.mov_rm(Reg::Rbx, stack_slot(i));
+                self.asm.mov_mr(stack_slot(i + 1), Reg::Rbx);
+            }
+        } else {
+            let top = self.asm.new_label();
+            self.asm.mov_ri(Reg::R11, n as i64 * 8);
+            self.asm.add_rr(Reg::R11, Reg::Rsp);
+            self.asm.bind(top);
+            self.asm.mov_rm(Reg::Rbx, Mem::new(Reg::R11, -8));
+            self.asm.mov_mr(Mem::new(Reg::R11, 0), Reg::Rbx);
+            self.asm.sub_ri(Reg::R11, 8);
+            self.asm.cmp_rr(Reg::R11, Reg::Rsp);
+            self.asm.jcc(Cond::Ne, top);
+        }
+        self.asm.mov_mr(stack_slot(0), Reg::Rax);
+    }
+
+    /*
+        Drop the slot below the top. Without a cache, the top is popped and
+        written over it.
+    */
+    fn emit_nip(&mut self) {
+        let top = self.pop_slot();
+
+        if self.cache.pop().is_some() {
+            self.stk_offset -= 8;
+            self.push_slot(top);
+        } else if self.emit_slot_check(0) {
+            self.asm.mov_mr(stack_slot(0), top);
+        }
     }
 
     /*
@@ -168,77 +453,130 @@ impl Compiler {
         Pop the two values and push the result.
     */
     fn emit_binop(&mut self, op: &str) {
-        self.emit(&[0x5B]);                 /* pop rbx  ; second */
-        self.emit(&[0x58]);                 /* pop rax  ; first */
-
-        match op {
-            "add" => self.emit(&[0x48,0x01,0xD8]),          /* add rax, rbx */
-            "sub" => self.emit(&[0x48,0x29,0xD8]),          /* sub rax, rbx */
-            "mul" => self.emit(&[0x48,0x0F,0xAF,0xC3]),     /* imul rax, rbx */
-            "and" => self.emit(&[0x48,0x21,0xD8]),          /* and rax, rbx */
-            "or" => self.emit(&[0x48,0x09,0xD8]),           /* or rax, rbx */
-            "xor" => self.emit(&[0x48,0x31,0xD8]),          /* xor rax, rbx */
+        let (a, b) = self.pop_pair();
+
+        let result = match op {
+            "add" => { self.asm.add_rr(a, b); a }
+            "sub" => { self.asm.sub_rr(a, b); a }
+            "mul" => { self.asm.imul_rr(a, b); a }
+            "and" => { self.asm.and_rr(a, b); a }
+            "or" => { self.asm.or_rr(a, b); a }
+            "xor" => { self.asm.xor_rr(a, b); a }
             /*
                 Division is a bit more complex, we need
-                to sign extend rax to rbx:rax, which
+                to sign extend rax to rdx:rax, which
                 can be done with cqo instruction.
 
                 https://www.felixcloutier.com/x86/cwd:cdq:cqo
             */
-            "div" => {
-                self.emit(&[0x48,0x99]);                    /* cqo */
-                self.emit(&[0x48,0xF7,0xFb]);               /* idiv rbx */
-            }
+            "div" | "mod" | "udiv" | "umod" => {
+                let divisor = if b == Reg::Rax || b == Reg::Rdx {
+                    self.asm.mov_rr(Reg::Rcx, b);           /* out of the way of the dividend */
+                    Reg::Rcx
+                } else {
+                    b
+                };
+
+                if a != Reg::Rax {
+                    self.asm.mov_rr(Reg::Rax, a);
+                }
+
+                if op.starts_with('u') {
+                    self.asm.mov_ri(Reg::Rdx, 0);           /* zero extended instead */
+                    self.asm.div(divisor);
+                } else {
+                    self.asm.cqo();
+                    self.asm.idiv(divisor);
+                }
+
+                if op == "mod" || op == "umod" {
+                    self.asm.mov_rr(Reg::Rax, Reg::Rdx);    /* for remainder */
+                }
 
-            "mod" => {
-                self.emit(&[0x48,0x99]);                    /* cqo */
-                self.emit(&[0x48,0xF7,0xFb]);               /* idiv rbx */
-                self.emit(&[0x48,0x89,0xD0]);               /* mov rax, rdx  ; for remainder */
+                Reg::Rax
             }
 
             "shl" => {
-                self.emit(&[0x48,0x89,0xD9]);               /* mov rcx, rbx */
-                self.emit(&[0x48,0xD3,0xE0]);               /* shl rax, cl */
+                self.asm.mov_rr(Reg::Rcx, b);
+                self.asm.shl_cl(a);
+                a
             }
 
             "shr" => {
-                self.emit(&[0x48,0x89,0xD9]);               /* mov rcx, rbx */
-                self.emit(&[0x48,0xD3,0xF8]);               /* sar rax, cl */
+                self.asm.mov_rr(Reg::Rcx, b);
+                self.asm.sar_cl(a);
+                a
+            }
+
+            "lshr" => {
+                self.asm.mov_rr(Reg::Rcx, b);
+                self.asm.shr_cl(a);
+                a
+            }
+
+            "fadd" | "fsub" | "fmul" | "fdiv" => {
+                self.emit_float_binop(op, a, a, b);
+                a
             }
 
             _ => panic!("unknown binop op: {}", op),
-        }
+        };
 
-        self.emit(&[0x50]);     /* push rax ; result */
-        self.stk_offset -= 8;         /* We just did two pops, so one push */
+        self.push_slot(result);
     }
 
     /*
-        Perform a comparison, like eq, ne, lt.
-        Push the result
+        The condition code of a comparison, like eq, ne, lt.
     */
-    fn emit_cmp(&mut self, op: &str) {
-        self.emit(&[0x5B]);             /* pop rbx  ; second operand */
-        self.emit(&[0x58]);             /* pop rax  ; first operand */
-        self.emit(&[0x48,0x39,0xD8]);   /* cmp rax, rbx */
-
+    fn cmp_cond(op: &str) -> Cond {
         match op {
-            "eq"  => self.emit(&[0x0F,0x94,0xC0]),   /* sete al */
-            "ne"  => self.emit(&[0x0F,0x95,0xC0]),   /* setne al */
-            "lt"  => self.emit(&[0x0F,0x9C,0xC0]),   /* setl al */
-            "lte" => self.emit(&[0x0F,0x9E,0xC0]),   /* setle al */
-            "gt"  => self.emit(&[0x0F,0x9F,0xC0]),   /* setg al */
-            "gte" => self.emit(&[0x0F,0x9D,0xC0]),   /* setge al */
+            "eq"  => Cond::E,
+            "ne"  => Cond::Ne,
+            "lt"  => Cond::L,
+            "lte" => Cond::Le,
+            "gt"  => Cond::G,
+            "gte" => Cond::Ge,
+            "ult"  => Cond::B,
+            "ulte" => Cond::Be,
+            "ugt"  => Cond::A,
+            "ugte" => Cond::Ae,
             _ => panic!("unknown cmp op: {}", op),
         }
+    }
+
+    /*
+        Perform a comparison, like eq, ne, lt.
+        Push the result
+    */
+    fn emit_cmp(&mut self, op: &str) {
+        let (a, b) = self.pop_pair();
+        self.asm.cmp_rr(a, b);
+        self.asm.setcc(Self::cmp_cond(op), a.low8());
 
         /*
-            Zero extend the result from al into rax
+            Zero extend the result from the low byte into the whole register
          */
-        self.emit(&[0x48, 0x0F, 0xB6, 0xC0]); /* movzx rax, al */
+        self.asm.movzx_r8(a, a.low8());
 
-        self.emit(&[0x50]);             /* push rax */
-        self.stk_offset -= 8;
+        self.push_slot(a);
+    }
+
+    /*
+        A comparison followed by a conditional jump, branching on the flags
+        cmp leaves instead of pushing a 0/1 just to test it again.
+        `jump_if` is false for JmpIfNot, which jumps on the opposite condition.
+    */
+    fn emit_cmp_jmp(&mut self, op: &str, label: u32, jump_if: bool) {
+        let target = self.label(label);
+        self.jumps.push(label);
+
+        let (a, b) = self.pop_pair();
+        self.flush();
+        self.depths.entry(label).or_insert(self.stk_offset);
+        self.asm.cmp_rr(a, b);
+
+        let cond = Self::cmp_cond(op);
+        self.asm.jcc(if jump_if { cond } else { cond.negate() }, target);
     }
 
     /*
@@ -246,20 +584,21 @@ impl Compiler {
         Push the result
     */
     fn emit_unary(&mut self, op: &str) {
-        self.emit(&[0x58]);             /* pop rax  ;   operand */
+        let reg = self.pop_slot();                 /* operand */
 
         match op {
-            "bnot" => self.emit(&[0x48,0xF7,0xD0]),   /* not rax */
-            "neg"  => self.emit(&[0x48,0xF7,0xD8]),   /* neg rax */
+            "bnot" => self.asm.not(reg),
+            "neg"  => self.asm.neg(reg),
             "not"  => {
-                self.emit(&[0x48,0x85,0xC0]);         /* test rax, rax */
-                self.emit(&[0x0F,0x94,0xC0]);         /* sete al */
-                self.emit(&[0x48, 0x0F, 0xB6, 0xC0]); /* movzx rax, al */
+                self.asm.test_rr(reg, reg);
+                self.asm.setcc(Cond::E, reg.low8());
+                self.asm.movzx_r8(reg, reg.low8());
             }
+            "fneg" | "fsqrt" | "itof" | "ftoi" => self.emit_float_unary(op, reg, reg),
             _ => panic!("unknown unary op: {}", op),
         }
 
-        self.emit(&[0x50]);             /* push rax */
+        self.push_slot(reg);
     }
 
     /*
@@ -268,103 +607,200 @@ impl Compiler {
         this should be handled when generating bytecode after or during parsing.
     */
     fn emit_store(&mut self, id: u32) {
-        let offset = (id as i32 + 1) * 8;            /* Gonna use RBP offset, so calculate it */
-        self.emit(&[0x58]);                         /* pop rax */
-        self.emit(&[0x48,0x89,0x85]);               /* mov [rbp-offset], rax */
-        self.emit(&(-(offset as i32)).to_le_bytes());      /* Convert the offset into bytes */
-        self.stk_offset -= 8;
+        if let Some(&var) = self.vars.get(&id) {
+            if self.cache.is_empty() {
+                self.asm.pop(var);                  /* straight off the machine stack */
+                self.stk_offset -= 8;
+            } else {
+                let reg = self.pop_slot();
+                self.asm.mov_rr(var, reg);
+            }
+            return;
+        }
+
+        let offset = var_offset(id).expect("frame_size checks every variable");
+        let reg = self.pop_slot();
+        self.asm.mov_mr(Mem::new(Reg::Rbp, -offset), reg);
     }
 
     /*
         Load the local variable from the `id` onto tjhe stack
     */
     fn emit_load_var(&mut self, id: u32) {
-        let offset = (id as i32 + 1) * 8;            /* Gonna use RBP offset, so calculate it */
-        self.emit(&[0x48,0x8B,0x85]);               /* mov rax, [rbp-offset] */
-        self.emit(&(-(offset as i32)).to_le_bytes());     /* Convert the offset into bytes */
-        self.emit(&[0x50]);                         /* push rax */
-        self.stk_offset += 8;
+        if let Some(&var) = self.vars.get(&id) {
+            if self.capacity() == 0 {
+                self.asm.push(var);
+                self.stk_offset += 8;
+            } else {
+                let reg = self.alloc();
+                self.asm.mov_rr(reg, var);
+                self.push_slot(reg);
+            }
+            return;
+        }
+
+        let offset = var_offset(id).expect("frame_size checks every variable");
+        let reg = self.alloc();
+        self.asm.mov_rm(reg, Mem::new(Reg::Rbp, -offset));
+        self.push_slot(reg);
     }
 
     /*
         Emit a jump
     */
     fn emit_jmp(&mut self, label: u32, is_conditional_jmp: Option<bool>) {
+        let target = self.label(label);
+        self.jumps.push(label);
+
         match is_conditional_jmp {
             None => {
                 /*
                     Emit an unconditional "jmp"
                 */
-                self.emit(&[0xE9]);                     /* jmp rel32 */
-                let pos = self.bytecode.len();          /* Calculate the patch position in the bytecode */
-                self.label_patches.push((pos, label));  /* Push the label ID along with it's patch position */
-                self.emit(&[0x00,0x00,0x00,0x00]);
+                self.flush();
+                self.asm.jmp(target);
             }
 
             Some(true) => {
                 /*
                     Emit a condition jump, so if top of stack value is not zero (correction: non-zero)
                 */
-                self.emit(&[0x58]);                 /* pop rax */
-                self.emit(&[0x48,0x85,0xC0]);       /* test rax, rax */
-                self.emit(&[0x0F,0x85]);            /* jnz rel32 */
-                let pos = self.bytecode.len();      /* Calculate the patch position in the bytecode */
-                self.label_patches.push((pos, label));
-                self.emit(&[0x00,0x00,0x00,0x00]);
-                self.stk_offset -= 8;
+                let reg = self.pop_slot();
+                self.flush();
+                self.asm.test_rr(reg, reg);
+                self.asm.jcc(Cond::Ne, target);     /* jnz */
             }
 
             Some(false) => {
                 /*
                     Emit a condition jump, so if top of stack value is zero
                 */
-                self.emit(&[0x58]);                 /* pop rax */
-                self.emit(&[0x48,0x85,0xC0]);       /* test rax, rax */
-                self.emit(&[0x0F,0x84]);            /* jz rel32 */
-                let pos = self.bytecode.len();      /* Calculate the patch position in the bytecode */
-                self.label_patches.push((pos, label));
-                self.emit(&[0x00,0x00,0x00,0x00]);
-                self.stk_offset -= 8;
+                let reg = self.pop_slot();
+                self.flush();
+                self.asm.test_rr(reg, reg);
+                self.asm.jcc(Cond::E, target);      /* jz */
             }
         }
+
+        self.depths.entry(label).or_insert(self.stk_offset);
     }
 
     /*
-        Write syscall
+        Write syscall. Like the interpreter and the SSA form, it doesn't take
+        anything off the stack yet, so it's fine on an empty one too.
     */
     fn emit_write(&mut self) {
-        /* TODO: implement proper syscall calling, but right now just return it */
-        self.emit(&[0x58]); /* pop rax  ; write this value */
-        self.emit(&[0x50]); /* push rax  ; write this value */
+        /* TODO: implement proper syscall calling, but right now leave the value where it is */
     }
 
     /*
-        Helper for patching jumps
+        Helper for checking every jump has somewhere to go, the assembler does the patching
     */
-    fn patch_jumps(&mut self) {
-        for (pos, label) in &self.label_patches {
-            if let Some(&p) = self.labels.get(label) {
-                let offset = p as i32 - (*pos as i32 + 4);  /* Calculate offset of the patch position */
-                let bytes = offset.to_le_bytes();
-                for (i, &b) in bytes.iter().enumerate() {
-                    self.bytecode[pos + i] = b; /* Patch jumps */
-                }
-            }
+    fn check_jumps(&self) -> Result<(), CompileError> {
+        match self.jumps.iter().find(|label| !self.defined.contains(label)) {
+            Some(label) => Err(CompileError::UndefinedLabel(*label)),
+            None => Ok(()),
         }
     }
 
-    pub fn compile(&mut self, program: &Program) -> Vec<u8> {
-        self.bytecode.clear();
+    /*
+        Compile a program into x86-64 machine code, ready to be handed to an `Invoker`.
+    */
+    pub fn compile(&mut self, program: &Program) -> Result<Vec<u8>, CompileError> {
+        self.asm = Assembler::new();
+        self.cache.clear();
         self.stk_offset = 0;
         self.labels.clear();
-        self.label_patches.clear();
+        self.defined.clear();
+        self.jumps.clear();
+        self.depths.clear();
+        self.source_map = None;
+
+        if self.options.verify {
+            verify(program)?;
+        }
+
+        let level = self.options.opt_level;
+        let folded;
+        let program = if level >= OptLevel::O2 && !self.options.emit_source_map {
+            folded = opt::fold_constants(program);     /* folding moves instructions around, which a source map can't follow */
+            &folded
+        } else {
+            program
+        };
+
+        self.vars = if level > OptLevel::O0 { locals::allocate(program) } else { HashMap::new() };
+        self.zeroed = program
+            .insts()
+            .iter()
+            .filter_map(|inst| match *inst {
+                Instruction::LoadVar(id) if !self.vars.contains_key(&id) => Some(id),
+                _ => None,
+            })
+            .collect();
+        self.zeroed.sort();
+        self.zeroed.dedup();
+        self.frame = frame_size(program)?;
+
+        if self.mode == CodegenMode::Ssa {
+            match ir::build(program) {
+                Ok(mut func) => {
+                    if level > OptLevel::O0 {
+                        ir::fold_constants(&mut func);
+                        ir::hoist_invariants(&mut func);
+                        ir::remove_dead_values(&mut func);
+                    }
+                    self.emit_func(&func)?;
+                    return self.finish();
+                }
+                Err(BuildError::Invalid(e)) => return Err(e),
+                Err(_) => {}                /* stack depends on the path taken, leave it to the cache */
+            }
+        }
 
         self.emit_fn_prologue();
 
-        for i in &program.insts {
+        let fuse = level > OptLevel::O0;
+        let mut insts = program.insts().iter().enumerate().peekable();
+
+        let mut reachable = true;           /* whether the previous instruction falls through to this one */
+
+        while let Some((pc, i)) = insts.next() {
+            self.mark(pc);
+
+            /*
+                Code after a Ret or Jmp stays dead up to the next label, however it touches the stack.
+            */
+            let fell_through = reachable;
+            match i {
+                Instruction::Ret | Instruction::Jmp(_) => reachable = false,
+                Instruction::Label(_) => reachable = true,
+                _ => {}
+            }
+
+            if fuse
+                && let Instruction::Load(c) = *i
+                && let Some(&(next, &op)) = insts.peek()
+                && self.emit_binop_const(op, c)
+            {
+                self.mark(next);            /* fused pairs share their code */
+                insts.next();
+                continue;
+            }
+
+            if fuse
+                && let Some(op) = cmp_op(i)
+                && let Some(&(next, Instruction::JmpIf(label) | Instruction::JmpIfNot(label))) = insts.peek()
+            {
+                self.mark(next);
+                let jump_if = matches!(insts.next(), Some((_, Instruction::JmpIf(_))));
+                self.emit_cmp_jmp(op, *label, jump_if);
+                continue;
+            }
+
             match i {
                 Instruction::Load(v) => self.emit_load_imm(*v),
-                Instruction::Dup => self.emit_dup(),
+                Instruction::Dup => self.emit_pick(0),
                 Instruction::Pop => self.emit_pop(),
                 Instruction::Swap => self.emit_swap(),
                 Instruction::Add => self.emit_binop("add"),
@@ -394,152 +830,193 @@ impl Compiler {
                 Instruction::JmpIf(label) => self.emit_jmp(*label, Some(true)),
                 Instruction::JmpIfNot(label) => self.emit_jmp(*label, Some(false)),
                 Instruction::Label(id) => {
-                    assert!(*id != EXIT_LABEL, "label id {} is reserved.", EXIT_LABEL);
-                    self.labels.insert(*id, self.bytecode.len());
+                    if *id == EXIT_LABEL {
+                        return Err(CompileError::ReservedLabel(*id));
+                    }
+
+                    if !self.defined.insert(*id) {
+                        return Err(CompileError::DuplicateLabel(*id));
+                    }
+
+                    /*
+                        Nothing falls through after a Ret or Jmp, so the stack is as deep as the jumps here left it.
+                    */
+                    if !fell_through && let Some(&depth) = self.depths.get(id) {
+                        self.stk_offset = depth;
+                    }
+
+                    self.flush();
+                    let label = self.label(*id);
+                    self.asm.bind(label);
                 },
                 Instruction::Write => self.emit_write(),
                 Instruction::WriteChar => self.emit_write(),
                 Instruction::Read => {},
                 Instruction::Call(_) => {},
                 Instruction::Ret => {
-                    self.emit(&[0x58]);
+                    let reg = self.pop_slot();
+                    if reg != Reg::Rax {
+                        self.asm.mov_rr(Reg::Rax, reg);
+                    }
+
+                    self.cache.clear();             /* the rest of the stack is thrown away */
+                    self.stk_offset = 0;
                     self.emit_jmp(EXIT_LABEL, None);
                 }
                 Instruction::Halt => break,
+                Instruction::LoadF(v) => self.emit_load_imm(v.to_bits() as i64),
+                Instruction::FAdd => self.emit_binop("fadd"),
+                Instruction::FSub => self.emit_binop("fsub"),
+                Instruction::FMul => self.emit_binop("fmul"),
+                Instruction::FDiv => self.emit_binop("fdiv"),
+                Instruction::FNeg => self.emit_unary("fneg"),
+                Instruction::FSqrt => self.emit_unary("fsqrt"),
+                Instruction::FEq => self.emit_fcmp("feq"),
+                Instruction::FNe => self.emit_fcmp("fne"),
+                Instruction::FLt => self.emit_fcmp("flt"),
+                Instruction::FGt => self.emit_fcmp("fgt"),
+                Instruction::FLte => self.emit_fcmp("flte"),
+                Instruction::FGte => self.emit_fcmp("fgte"),
+                Instruction::IToF => self.emit_unary("itof"),
+                Instruction::FToI => self.emit_unary("ftoi"),
+                Instruction::UDiv => self.emit_binop("udiv"),
+                Instruction::UMod => self.emit_binop("umod"),
+                Instruction::LShr => self.emit_binop("lshr"),
+                Instruction::ULt => self.emit_cmp("ult"),
+                Instruction::UGt => self.emit_cmp("ugt"),
+                Instruction::ULte => self.emit_cmp("ulte"),
+                Instruction::UGte => self.emit_cmp("ugte"),
+                Instruction::Over => self.emit_pick(1),
+                Instruction::Rot => self.emit_roll(2),
+                Instruction::Nip => self.emit_nip(),
+                Instruction::Tuck => {
+                    self.emit_roll(1);
+                    self.emit_pick(1);
+                }
+                Instruction::Pick(n) => self.emit_pick(*n),
+                Instruction::Roll(n) => self.emit_roll(*n),
             }
         }
 
-        self.labels.insert(EXIT_LABEL, self.bytecode.len());    /* Insert the exit label */
-        self.emit_fn_epilogue();
-        self.patch_jumps();
-        self.bytecode.clone()
-    }
-}
-
-/*
-    A structure to represent an invoker, this will handle the execution of the bytecode generated from the compiler.
-*/
-pub struct Invoker;
+        /*
+            Running off the end returns the top of the stack, or 0 if it's empty
+        */
+        if reachable {
+            match self.cache.last() {
+                Some(&top) => self.asm.mov_rr(Reg::Rax, top),
+                None if self.stk_offset > 0 => self.asm.mov_rm(Reg::Rax, Mem::new(Reg::Rsp, 0)),
+                None => self.asm.mov_ri(Reg::Rax, 0),
+            }
+        }
 
-impl Invoker {
-    pub fn new() -> Self {
-        Self
+        self.finish()
     }
 
     /*
-        Finally, execute the code from the compiler.
+        Bind the exit label, end the function and encode it.
     */
-    pub fn execute(&mut self, code: &[u8]) -> i64 {
-        unsafe {
-            /*
-                Execute the code for UNIX systems.
-            */
-            #[cfg(unix)]
-            {
-                /*
-                    Allocate memory which is executable
-                */
-                let psize = 4096;       /* Page size */
-                let csize = (code.len() + psize - 1) & !(psize - 1);    /* Calculate the size of the generate code */
-
-                /*
-                    Pointer to the allocated memory
-                */
-                let p = libc::mmap(
-                    std::ptr::null_mut(),
-                    csize,
-                    libc::PROT_READ | libc::PROT_WRITE,
-                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-                    -1,
-                    0
-                );
-
-                if p == libc::MAP_FAILED {
-                    panic!("failed to allocate memory for Invoker.");
-                }
-
-                /*
-                    Then copy the code to memory whichi is executable
-                */
-                std::ptr::copy_nonoverlapping(code.as_ptr(), p as *mut u8, code.len());
-
-                /*
-                    And then obviously, make it executable.
-                */
-                if libc::mprotect(p, csize, libc::PROT_READ | libc::PROT_EXEC) != 0 {
-                    panic!("failed to make memory executable for Invoker.");
-                }
-
-                /*
-                    Cast the function pointer from the executable memory
-                    and then, at last, execute.
-                */
-                let f: extern "C" fn() -> i64 = std::mem::transmute(p);
-                let ret = f();
+    fn finish(&mut self) -> Result<Vec<u8>, CompileError> {
+        let exit = self.label(EXIT_LABEL);      /* Bind the exit label */
+        self.defined.insert(EXIT_LABEL);
+        self.asm.bind(exit);
+        self.emit_fn_epilogue();
+        self.check_jumps()?;
 
-                /*
-                    Cleanup allocated memory
-                */
-                libc::munmap(p, csize);
+        if self.options.opt_level > OptLevel::O0 {
+            self.stats = self.asm.optimise();
+        } else {
+            let size = self.asm.size();
+            self.stats = PeepholeStats { before: size, after: size, removed: 0 };
+        }
 
-                /* Return the result of the executed function */
-                ret
-            }
+        let (code, marks) = std::mem::take(&mut self.asm).finish_with_marks();
+        if self.options.emit_source_map {
+            self.source_map = Some(SourceMap::new(marks));
+        }
+        Ok(code)
+    }
 
-            /*
-                Execute the code for Windows systems.
-            */
-            #[cfg(windows)]
-            {
-                /*
-                    Allocate memory which is executable
-                */
-                let psize = 4096;       /* Page size */
-                let csize = (code.len() + psize - 1) & !(psize - 1);    /* Calculate the size of the generate code */
+    /*
+        Record where the code for instruction `pc` starts, if a source map was asked for.
+    */
+    fn mark(&mut self, pc: usize) {
+        if self.options.emit_source_map {
+            self.asm.mark(pc);
+        }
+    }
+}
 
-                /*
-                    Pointer to the allocated memory
-                */
-                let p = VirtualAlloc(
-                    ptr::null_mut(),
-                    csize,
-                    MEM_COMMIT | MEM_RESERVE,
-                    PAGE_READWRITE
-                );
-
-                if p.is_null() {
-                    panic!("failed to allocate memory for Invoker.");
-                }
+/*
+    Check every path through the program leaves the stack deep enough for
+    each instruction, and at the same depth wherever paths meet.
+*/
+pub(crate) fn verify(program: &Program) -> Result<(), CompileError> {
+    match ir::build(program) {
+        Ok(_) => Ok(()),
+        Err(BuildError::Invalid(e)) => Err(e),
+        Err(BuildError::StackUnderflow(pc)) => Err(CompileError::StackUnderflow(pc)),
+        Err(BuildError::UnbalancedStack(pc)) => Err(CompileError::UnbalancedStack(pc)),
+    }
+}
 
-                /*
-                    Then copy the code to memory whichi is executable
-                */
-                std::ptr::copy_nonoverlapping(code.as_ptr(), p as *mut u8, code.len());
+/*
+    The slot `n` below the top of the machine stack.
+*/
+fn stack_slot(n: usize) -> Mem {
+    Mem::new(Reg::Rsp, n as i32 * 8)
+}
 
-                /*
-                    And then obviously, make it executable.
-                */
-                let mut old: DWORD = 0;
-                if VirtualProtect(p, csize, PAGE_EXECUTE_READ, &mut old) == 0 {
-                    VirtualFree(p, 0, MEM_RELEASE);
-                    panic!("failed to make memory executable for Invoker.");
-                }
+/*
+    Where variable `id` lives below rbp, if it's close enough to address.
+*/
+pub(super) fn var_offset(id: u32) -> Option<i32> {
+    i32::try_from(id).ok()?.checked_add(1)?.checked_mul(8)
+}
 
-                /*
-                    Cast the function pointer from the executable memory
-                    and then, at last, execute.
-                */
-                let f: extern "C" fn() -> i64 = std::mem::transmute(p);
-                let ret = f();
+/*
+    Frame space for the variables of a program, more than VAR_FRAME if
+    their ids go past it, like the ones the inliner makes up.
+*/
+fn frame_size(program: &Program) -> Result<i32, CompileError> {
+    let top = program
+        .insts()
+        .iter()
+        .filter_map(|inst| match *inst {
+            Instruction::Store(id) | Instruction::LoadVar(id) => Some(id),
+            _ => None,
+        })
+        .max()
+        .map_or(Some(0), var_offset);
+
+    top.and_then(|top| top.max(VAR_FRAME).checked_add(15))
+        .map(|size| size & !15)
+        .ok_or(CompileError::FrameTooLarge)
+}
 
-                /*
-                    Cleanup allocated memory
-                */
-                VirtualFree(p, 0, MEM_RELEASE);
+/*
+    The op of a comparison instruction, as `emit_cmp` takes it.
+*/
+fn cmp_op(inst: &Instruction) -> Option<&'static str> {
+    match inst {
+        Instruction::Eq => Some("eq"),
+        Instruction::Ne => Some("ne"),
+        Instruction::Lt => Some("lt"),
+        Instruction::Lte => Some("lte"),
+        Instruction::Gt => Some("gt"),
+        Instruction::Gte => Some("gte"),
+        Instruction::ULt => Some("ult"),
+        Instruction::UGt => Some("ugt"),
+        Instruction::ULte => Some("ulte"),
+        Instruction::UGte => Some("ugte"),
+        _ => None,
+    }
+}
 
-                /* Return the result of the executed function */
-                ret
-            }
-        }
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,115 @@
+use crate::assembler::{Cond, Reg, Xmm};
+
+use super::Compiler;
+
+/*
+    f64 arithmetic on the bit patterns kept in general purpose registers.
+
+    Operands are moved into xmm0 and xmm1, which nothing else uses, worked
+    on with the SSE2 scalar instructions and moved back:
+
+        fadd a, b   ->  movq xmm0, a; movq xmm1, b; addsd xmm0, xmm1; movq dst, xmm0
+        fneg a      ->  btc a, 63
+        itof a      ->  cvtsi2sd xmm0, a; movq dst, xmm0
+        flt a, b    ->  ucomisd xmm1, xmm0; seta dst
+        feq a, b    ->  ucomisd xmm0, xmm1; sete dst; setnp scratch; and dst, scratch
+
+    ucomisd sets ZF, PF and CF when either operand is NaN, so a < b is
+    asked as b > a, which comes out false for NaN like it should, and
+    equality needs PF clear as well. The interpreter picks the same NaNs
+    SSE2 does, so results agree bit for bit.
+*/
+impl Compiler {
+    /*
+        dst = a op b, for fadd, fsub, fmul and fdiv.
+    */
+    pub(super) fn emit_float_binop(&mut self, op: &str, dst: Reg, a: Reg, b: Reg) {
+        self.asm.movq_xr(Xmm::Xmm0, a);
+        self.asm.movq_xr(Xmm::Xmm1, b);
+
+        match op {
+            "fadd" => self.asm.addsd(Xmm::Xmm0, Xmm::Xmm1),
+            "fsub" => self.asm.subsd(Xmm::Xmm0, Xmm::Xmm1),
+            "fmul" => self.asm.mulsd(Xmm::Xmm0, Xmm::Xmm1),
+            "fdiv" => self.asm.divsd(Xmm::Xmm0, Xmm::Xmm1),
+            _ => panic!("unknown float binop: {}", op),
+        }
+
+        self.asm.movq_rx(dst, Xmm::Xmm0);
+    }
+
+    /*
+        dst = op a, for fneg, fsqrt, itof and ftoi.
+    */
+    pub(super) fn emit_float_unary(&mut self, op: &str, dst: Reg, a: Reg) {
+        match op {
+            "fneg" => {
+                if dst != a {
+                    self.asm.mov_rr(dst, a);
+                }
+                self.asm.btc_ri(dst, 63);
+            }
+            "fsqrt" => {
+                self.asm.movq_xr(Xmm::Xmm0, a);
+                self.asm.sqrtsd(Xmm::Xmm0, Xmm::Xmm0);
+                self.asm.movq_rx(dst, Xmm::Xmm0);
+            }
+            "itof" => {
+                self.asm.cvtsi2sd(Xmm::Xmm0, a);
+                self.asm.movq_rx(dst, Xmm::Xmm0);
+            }
+            "ftoi" => {
+                self.asm.movq_xr(Xmm::Xmm0, a);
+                self.asm.cvttsd2si(dst, Xmm::Xmm0);
+            }
+            _ => panic!("unknown float unary op: {}", op),
+        }
+    }
+
+    /*
+        dst = a op b as 0 or 1, for feq, fne, flt, fgt, flte and fgte.
+        `scratch` is overwritten too, and may be a or b but not dst.
+    */
+    pub(super) fn emit_float_cmp(&mut self, op: &str, dst: Reg, a: Reg, b: Reg, scratch: Reg) {
+        self.asm.movq_xr(Xmm::Xmm0, a);
+        self.asm.movq_xr(Xmm::Xmm1, b);
+
+        let (x, y, cond) = match op {
+            "feq" => (Xmm::Xmm0, Xmm::Xmm1, Cond::E),
+            "fne" => (Xmm::Xmm0, Xmm::Xmm1, Cond::Ne),
+            "fgt" => (Xmm::Xmm0, Xmm::Xmm1, Cond::A),
+            "fgte" => (Xmm::Xmm0, Xmm::Xmm1, Cond::Ae),
+            "flt" => (Xmm::Xmm1, Xmm::Xmm0, Cond::A),
+            "flte" => (Xmm::Xmm1, Xmm::Xmm0, Cond::Ae),
+            _ => panic!("unknown float cmp op: {}", op),
+        };
+
+        self.asm.ucomisd(x, y);
+        self.asm.setcc(cond, dst.low8());
+        self.asm.movzx_r8(dst, dst.low8());
+
+        /*
+            Unordered is equal as far as ZF goes, PF tells NaNs apart.
+        */
+        if op == "feq" || op == "fne" {
+            let parity = if op == "feq" { Cond::Np } else { Cond::P };
+            self.asm.setcc(parity, scratch.low8());
+            self.asm.movzx_r8(scratch, scratch.low8());
+
+            if op == "feq" {
+                self.asm.and_rr(dst, scratch);
+            } else {
+                self.asm.or_rr(dst, scratch);
+            }
+        }
+    }
+
+    /*
+        A float comparison on the operand stack.
+    */
+    pub(super) fn emit_fcmp(&mut self, op: &str) {
+        let (a, b) = self.pop_pair();
+        self.emit_float_cmp(op, a, a, b, b);
+        self.push_slot(a);
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+
+use crate::assembler::Reg;
+use crate::program::{Instruction, Program};
+
+/*
+    Callee saved registers variables can live in. rbx is callee saved too,
+    but the operand stack already uses it.
+*/
+pub(super) const VAR_REGS: [Reg; 4] = [Reg::R12, Reg::R13, Reg::R14, Reg::R15];
+
+/*
+    How much more a use inside a loop counts than one outside.
+*/
+const LOOP_WEIGHT: usize = 10;
+
+/*
+    Pick the variables worth keeping in registers and the register for each.
+
+    Every Store/LoadVar counts towards its variable, times LOOP_WEIGHT for
+    every loop it is in, a loop being everything between a label and a
+    jump back to it. The heaviest variables get the registers, ties going
+    to the lower id so the choice never depends on hash order.
+*/
+pub(super) fn allocate(program: &Program) -> HashMap<u32, Reg> {
+    let insts = program.insts();
+    let mut labels = HashMap::new();
+    let mut loops = vec![];             /* (label index, jump index) */
+
+    for (pc, inst) in insts.iter().enumerate() {
+        match *inst {
+            Instruction::Label(id) => {
+                labels.insert(id, pc);
+            }
+            Instruction::Jmp(id) | Instruction::JmpIf(id) | Instruction::JmpIfNot(id) => {
+                if let Some(&start) = labels.get(&id) {
+                    loops.push((start, pc));
+                }
+            }
+            Instruction::Halt => break,
+            _ => {}
+        }
+    }
+
+    let mut weights: HashMap<u32, usize> = HashMap::new();
+    for (pc, inst) in insts.iter().enumerate() {
+        if let Instruction::Store(id) | Instruction::LoadVar(id) = *inst {
+            let depth = loops.iter().filter(|&&(start, end)| start < pc && pc < end).count() as u32;
+            *weights.entry(id).or_default() += LOOP_WEIGHT.saturating_pow(depth);
+        }
+    }
+
+    let mut hot: Vec<(u32, usize)> = weights.into_iter().collect();
+    hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    hot.into_iter().zip(VAR_REGS).map(|((id, _), reg)| (id, reg)).collect()
+}
+
+#[cfg(test)]
+mod tests;
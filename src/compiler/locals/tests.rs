@@ -0,0 +1,42 @@
+use super::*;
+use crate::program::Instruction::*;
+
+#[test]
+fn no_variables() {
+    assert!(allocate(&Program::new(vec![Load(1), Ret])).is_empty());
+}
+
+#[test]
+fn few_variables_all_get_registers() {
+    let regs = allocate(&Program::new(vec![Load(1), Store(3), Load(2), Store(0), LoadVar(3), Ret]));
+    assert_eq!(regs.len(), 2);
+    assert_eq!(regs[&3], Reg::R12);
+    assert_eq!(regs[&0], Reg::R13);
+}
+
+/*
+    Variables 0 to 4 are used twice each up front, variable 5 once in a loop.
+*/
+#[test]
+fn loops_make_variables_hot() {
+    let mut insts = vec![];
+    for id in 0..5 {
+        insts.extend([Load(1), Store(id), LoadVar(id), Pop]);
+    }
+    insts.extend([Label(1), LoadVar(5), JmpIf(1), Ret]);
+
+    let regs = allocate(&Program::new(insts));
+    assert_eq!(regs.len(), VAR_REGS.len());
+    assert_eq!(regs[&5], Reg::R12);
+    assert_eq!(regs.get(&3), None);
+    assert_eq!(regs.get(&4), None);
+}
+
+#[test]
+fn forward_jumps_are_not_loops() {
+    let regs = allocate(&Program::new(vec![
+        LoadVar(0), LoadVar(0), Jmp(1), LoadVar(1), Label(1),
+        LoadVar(2), LoadVar(2), LoadVar(3), LoadVar(3), LoadVar(4), LoadVar(4),
+    ]));
+    assert_eq!(regs.get(&1), None);
+}
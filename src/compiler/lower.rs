@@ -0,0 +1,372 @@
+use super::regalloc::{self, Loc};
+use super::{var_offset, CompileError, Compiler, EXIT_LABEL};
+use crate::assembler::{Cond, Label, Mem, Reg};
+use crate::ir::{BinOp, CmpOp, Func, Op, Target, Terminator, UnOp, Value};
+
+/*
+    Lowering from the SSA form, for `CodegenMode::Ssa`.
+
+    Values live where `regalloc` put them, in a register or in a spill slot
+    of the frame below the variables. Constants don't live anywhere, they
+    are moved straight into whatever register needs them. rax and r11 are
+    scratch registers for operands which are not in a register already,
+    and for results headed for a spill slot.
+
+    Jumping to a block copies the arguments into its parameters. The copies
+    happen at once, like the jump does, so when an argument is also one of
+    the parameters being overwritten they go through the machine stack.
+*/
+impl Compiler {
+    pub(super) fn emit_func(&mut self, func: &Func) -> Result<(), CompileError> {
+        self.alloc = regalloc::allocate(func);
+        self.slots = self.frame;
+        self.frame = i32::try_from(self.alloc.slots)
+            .ok()
+            .and_then(|slots| slots.checked_mul(8)?.checked_add(15))
+            .and_then(|bytes| self.frame.checked_add(bytes & !15))
+            .ok_or(CompileError::FrameTooLarge)?;
+
+        self.emit_fn_prologue();
+
+        let blocks: Vec<Label> = func.blocks.iter().map(|_| self.asm.new_label()).collect();
+        let exit = self.label(EXIT_LABEL);
+        let last = func.blocks.len() - 1;
+
+        for (id, block) in func.blocks.iter().enumerate() {
+            self.asm.bind(blocks[id]);
+
+            for &value in &block.insts {
+                self.emit_value(func, value);
+            }
+
+            /*
+                Jumps to the block right after this one fall through instead.
+            */
+            let jump = |this: &mut Self, target: &Target| {
+                if target.block.0 as usize != id + 1 {
+                    this.asm.jmp(blocks[target.block.0 as usize]);
+                }
+            };
+
+            match &block.term {
+                Terminator::Jump(target) => {
+                    self.emit_copies(func, target);
+                    jump(self, target);
+                }
+                Terminator::Branch(cond, taken, next) => {
+                    let reg = self.reg(func, *cond, Reg::Rax);
+                    self.asm.test_rr(reg, reg);
+
+                    if self.copies(func, next).is_empty() {
+                        self.asm.jcc(Cond::E, blocks[next.block.0 as usize]);
+                        self.emit_copies(func, taken);
+                        jump(self, taken);
+                    } else {
+                        let skip = self.asm.new_label();
+                        self.asm.jcc(Cond::E, skip);
+                        self.emit_copies(func, taken);
+                        self.asm.jmp(blocks[taken.block.0 as usize]);
+                        self.asm.bind(skip);
+                        self.emit_copies(func, next);
+                        jump(self, next);
+                    }
+                }
+                Terminator::Return(value) => {
+                    self.emit_operand(func, Reg::Rax, *value);
+                    if id != last {
+                        self.asm.jmp(exit);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn slot(&self, slot: u32) -> Mem {
+        Mem::new(Reg::Rbp, -(self.slots + 8 * (slot as i32 + 1)))
+    }
+
+    /*
+        Load a value into a register.
+    */
+    fn emit_operand(&mut self, func: &Func, reg: Reg, value: Value) {
+        match (func.value(value).op, self.alloc.loc(value)) {
+            (Op::Const(c), _) => self.asm.mov_ri(reg, c),
+            (_, Some(Loc::Reg(r))) => {
+                if r != reg {
+                    self.asm.mov_rr(reg, r);
+                }
+            }
+            (_, Some(Loc::Slot(slot))) => self.asm.mov_rm(reg, self.slot(slot)),
+            (_, None) => unreachable!("{} has no location", value),
+        }
+    }
+
+    /*
+        The register a value is in, loading it into `scratch` if it is not in one.
+    */
+    fn reg(&mut self, func: &Func, value: Value, scratch: Reg) -> Reg {
+        match (func.value(value).op, self.alloc.loc(value)) {
+            (Op::Const(_), _) | (_, Some(Loc::Slot(_)) | None) => {
+                self.emit_operand(func, scratch, value);
+                scratch
+            }
+            (_, Some(Loc::Reg(reg))) => reg,
+        }
+    }
+
+    /*
+        The register to compute a value in, its own or rax for spilled ones,
+        and storing it once computed.
+    */
+    fn dst(&self, value: Value) -> Reg {
+        match self.alloc.loc(value) {
+            Some(Loc::Reg(reg)) => reg,
+            _ => Reg::Rax,
+        }
+    }
+
+    fn emit_result(&mut self, value: Value, reg: Reg) {
+        match self.alloc.loc(value) {
+            Some(Loc::Reg(r)) if r != reg => self.asm.mov_rr(r, reg),
+            Some(Loc::Slot(slot)) => self.asm.mov_mr(self.slot(slot), reg),
+            _ => {}
+        }
+    }
+
+    fn emit_value(&mut self, func: &Func, value: Value) {
+        let dst = self.dst(value);
+
+        match func.value(value).op {
+            Op::Param | Op::Const(_) => {}
+
+            Op::Unary(op @ (UnOp::FNeg | UnOp::FSqrt | UnOp::IToF | UnOp::FToI), a) => {
+                let a = self.reg(func, a, Reg::Rax);
+                self.emit_float_unary(float_unary(op), dst, a);
+                self.emit_result(value, dst);
+            }
+
+            Op::Unary(op, a) => {
+                self.emit_operand(func, dst, a);
+                match op {
+                    UnOp::Neg => self.asm.neg(dst),
+                    UnOp::Bnot => self.asm.not(dst),
+                    UnOp::Not => {
+                        self.asm.test_rr(dst, dst);
+                        self.asm.setcc(Cond::E, dst.low8());
+                        self.asm.movzx_r8(dst, dst.low8());
+                    }
+                    _ => unreachable!(),
+                }
+                self.emit_result(value, dst);
+            }
+
+            /*
+                The dividend goes in rax and its top half in rdx, so a divisor
+                in either of them moves to r11 first. The count of a shift has
+                to be in cl, and the shifted value is moved out of rcx before
+                the count goes in.
+            */
+            Op::Binary(op @ (BinOp::Div | BinOp::Mod | BinOp::UDiv | BinOp::UMod), a, b) => {
+                let divisor = match self.reg(func, b, Reg::R11) {
+                    Reg::Rax | Reg::Rdx => {
+                        self.emit_operand(func, Reg::R11, b);
+                        Reg::R11
+                    }
+                    reg => reg,
+                };
+
+                self.emit_operand(func, Reg::Rax, a);
+                if matches!(op, BinOp::UDiv | BinOp::UMod) {
+                    self.asm.mov_ri(Reg::Rdx, 0);
+                    self.asm.div(divisor);
+                } else {
+                    self.asm.cqo();
+                    self.asm.idiv(divisor);
+                }
+                self.emit_result(value, if matches!(op, BinOp::Div | BinOp::UDiv) { Reg::Rax } else { Reg::Rdx });
+            }
+
+            Op::Binary(op @ (BinOp::Shl | BinOp::Shr | BinOp::LShr), a, b) => {
+                self.emit_operand(func, Reg::Rax, a);
+                self.emit_operand(func, Reg::Rcx, b);
+                match op {
+                    BinOp::Shl => self.asm.shl_cl(Reg::Rax),
+                    BinOp::Shr => self.asm.sar_cl(Reg::Rax),
+                    _ => self.asm.shr_cl(Reg::Rax),
+                }
+                self.emit_result(value, Reg::Rax);
+            }
+
+            /*
+                Floats go through xmm0/xmm1, so the operands can be anywhere.
+            */
+            Op::Binary(op @ (BinOp::FAdd | BinOp::FSub | BinOp::FMul | BinOp::FDiv), a, b) => {
+                let a = self.reg(func, a, Reg::Rax);
+                let b = self.reg(func, b, Reg::R11);
+                self.emit_float_binop(float_binop(op), dst, a, b);
+                self.emit_result(value, dst);
+            }
+
+            /*
+                dst = a; dst op= b, unless b is in dst already, being read for the last time.
+            */
+            Op::Binary(op, a, b) => {
+                let b = self.reg(func, b, Reg::R11);
+                let dst = if b == dst { Reg::Rax } else { dst };
+
+                self.emit_operand(func, dst, a);
+                match op {
+                    BinOp::Add => self.asm.add_rr(dst, b),
+                    BinOp::Sub => self.asm.sub_rr(dst, b),
+                    BinOp::Mul => self.asm.imul_rr(dst, b),
+                    BinOp::And => self.asm.and_rr(dst, b),
+                    BinOp::Or => self.asm.or_rr(dst, b),
+                    BinOp::Xor => self.asm.xor_rr(dst, b),
+                    _ => unreachable!(),
+                }
+                self.emit_result(value, dst);
+            }
+
+            Op::Cmp(op @ (CmpOp::FEq | CmpOp::FNe | CmpOp::FLt | CmpOp::FGt | CmpOp::FLte | CmpOp::FGte), a, b) => {
+                let a = self.reg(func, a, Reg::Rax);
+                let b = self.reg(func, b, Reg::R11);
+                self.emit_float_cmp(float_cmp(op), dst, a, b, Reg::R11);
+                self.emit_result(value, dst);
+            }
+
+            Op::Cmp(op, a, b) => {
+                let a = self.reg(func, a, Reg::Rax);
+                let b = self.reg(func, b, Reg::R11);
+                self.asm.cmp_rr(a, b);
+                self.asm.setcc(cmp_cond(op), dst.low8());
+                self.asm.movzx_r8(dst, dst.low8());
+                self.emit_result(value, dst);
+            }
+
+            Op::LoadVar(id) => match self.vars.get(&id) {
+                Some(&var) => self.emit_result(value, var),
+                None => {
+                    self.asm.mov_rm(dst, var_slot(id));
+                    self.emit_result(value, dst);
+                }
+            },
+
+            Op::StoreVar(id, a) => match self.vars.get(&id) {
+                Some(&var) => self.emit_operand(func, var, a),
+                None => {
+                    let reg = self.reg(func, a, Reg::Rax);
+                    self.asm.mov_mr(var_slot(id), reg);
+                }
+            },
+        }
+    }
+
+    /*
+        The (parameter, argument) pairs a jump has to copy, leaving out
+        parameters which already hold their argument or are never used, and
+        so have no location. Constants have no location either, so they are
+        always copied.
+    */
+    fn copies(&self, func: &Func, target: &Target) -> Vec<(Value, Value)> {
+        func.block(target.block)
+            .params
+            .iter()
+            .copied()
+            .zip(target.args.iter().copied())
+            .filter(|&(param, arg)| self.alloc.loc(param).is_some() && self.alloc.loc(param) != self.alloc.loc(arg))
+            .collect()
+    }
+
+    /*
+        Copy the arguments of a jump into the parameters of its target.
+    */
+    fn emit_copies(&mut self, func: &Func, target: &Target) {
+        let copies = self.copies(func, target);
+        let overlap = copies.iter().any(|&(_, arg)| copies.iter().any(|&(param, _)| self.alloc.loc(param) == self.alloc.loc(arg)));
+
+        if !overlap {
+            for &(param, arg) in &copies {
+                self.emit_copy(func, param, arg);
+            }
+            return;
+        }
+
+        for &(_, arg) in &copies {
+            let reg = self.reg(func, arg, Reg::Rax);
+            self.asm.push(reg);
+        }
+
+        for &(param, _) in copies.iter().rev() {
+            let reg = self.dst(param);
+            self.asm.pop(reg);
+            self.emit_result(param, reg);
+        }
+    }
+
+    fn emit_copy(&mut self, func: &Func, param: Value, arg: Value) {
+        match self.alloc.loc(param) {
+            Some(Loc::Reg(reg)) => self.emit_operand(func, reg, arg),
+            _ => {
+                let reg = self.reg(func, arg, Reg::Rax);
+                self.emit_result(param, reg);
+            }
+        }
+    }
+}
+
+fn var_slot(id: u32) -> Mem {
+    Mem::new(Reg::Rbp, -var_offset(id).expect("frame_size checks every variable"))
+}
+
+fn cmp_cond(op: CmpOp) -> Cond {
+    match op {
+        CmpOp::Eq => Cond::E,
+        CmpOp::Ne => Cond::Ne,
+        CmpOp::Lt => Cond::L,
+        CmpOp::Gt => Cond::G,
+        CmpOp::Lte => Cond::Le,
+        CmpOp::Gte => Cond::Ge,
+        CmpOp::ULt => Cond::B,
+        CmpOp::UGt => Cond::A,
+        CmpOp::ULte => Cond::Be,
+        CmpOp::UGte => Cond::Ae,
+        _ => unreachable!(),
+    }
+}
+
+/*
+    The names `float` knows the float operations by.
+*/
+fn float_unary(op: UnOp) -> &'static str {
+    match op {
+        UnOp::FNeg => "fneg",
+        UnOp::FSqrt => "fsqrt",
+        UnOp::IToF => "itof",
+        UnOp::FToI => "ftoi",
+        _ => unreachable!(),
+    }
+}
+
+fn float_binop(op: BinOp) -> &'static str {
+    match op {
+        BinOp::FAdd => "fadd",
+        BinOp::FSub => "fsub",
+        BinOp::FMul => "fmul",
+        BinOp::FDiv => "fdiv",
+        _ => unreachable!(),
+    }
+}
+
+fn float_cmp(op: CmpOp) -> &'static str {
+    match op {
+        CmpOp::FEq => "feq",
+        CmpOp::FNe => "fne",
+        CmpOp::FLt => "flt",
+        CmpOp::FGt => "fgt",
+        CmpOp::FLte => "flte",
+        CmpOp::FGte => "fgte",
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,81 @@
+use super::CodegenMode;
+
+/*
+    How hard the compiler tries. Each level does everything the one before does.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum OptLevel {
+    O0,         /* Every instruction compiled on its own, as plainly as possible, for debugging */
+    #[default]
+    O1,         /* Peephole optimiser, compare and branch fusion, cheaper arithmetic by constants, variables in registers */
+    O2,         /* Constant folding ahead of code generation, and the top of the stack cached in registers */
+    O3,         /* The SSA pipeline, with its own passes and register allocator */
+}
+
+impl OptLevel {
+    /*
+        The code generation mode used when the options don't pick one.
+    */
+    pub fn mode(self) -> CodegenMode {
+        match self {
+            OptLevel::O0 | OptLevel::O1 => CodegenMode::Stack,
+            OptLevel::O2 => CodegenMode::Cached,
+            OptLevel::O3 => CodegenMode::Ssa,
+        }
+    }
+}
+
+/*
+    Everything that can be configured about a `Compiler`.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CompileOptions {
+    pub opt_level: OptLevel,
+    pub mode: Option<CodegenMode>,  /* None leaves it to the level */
+    pub verify: bool,               /* Reject programs which could underflow the stack, or leave it at different depths on different paths */
+    pub emit_source_map: bool,      /* Record where each bytecode instruction's code starts */
+}
+
+impl CompileOptions {
+    pub fn mode(&self) -> CodegenMode {
+        self.mode.unwrap_or(self.opt_level.mode())
+    }
+}
+
+/*
+    Where the machine code for each bytecode instruction starts.
+
+    Instructions with no code of their own, like labels, start where the
+    next one does. Code from the SSA form doesn't follow the order of the
+    bytecode, so it has an empty map, unless the program went the `Cached`
+    way for having an unbalanced stack.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    entries: Vec<(usize, usize)>,   /* (instruction index, code offset), in order of both */
+}
+
+impl SourceMap {
+    pub(super) fn new(entries: Vec<(usize, usize)>) -> Self {
+        Self { entries }
+    }
+
+    pub fn entries(&self) -> &[(usize, usize)] {
+        &self.entries
+    }
+
+    /*
+        Where the code for instruction `pc` starts.
+    */
+    pub fn offset(&self, pc: usize) -> Option<usize> {
+        self.entries.iter().find(|&&(p, _)| p == pc).map(|&(_, offset)| offset)
+    }
+
+    /*
+        The instruction the byte at `offset` was generated for, the last one
+        starting at or before it. Offsets in the prologue map to nothing.
+    */
+    pub fn instruction(&self, offset: usize) -> Option<usize> {
+        self.entries.iter().rev().find(|&&(_, o)| o <= offset).map(|&(pc, _)| pc)
+    }
+}
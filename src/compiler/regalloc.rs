@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+
+use crate::assembler::Reg;
+use crate::ir::{BinOp, Func, Op, Type, Value};
+
+/*
+    Registers SSA values can live in. rax and r11 are left out as scratch
+    registers for the lowering, r12-r15 are for variables, and rcx/rdx can
+    only hold values which don't live across a shift or a division, which
+    need them for the count and for the top half of the dividend.
+
+    rsi and rdi are callee saved on Windows and the prologue doesn't save
+    them, so they're only handed out elsewhere.
+*/
+#[cfg(not(windows))]
+pub(super) const VALUE_REGS: [Reg; 8] = [
+    Reg::Rbx, Reg::Rsi, Reg::Rdi, Reg::R8, Reg::R9, Reg::R10, Reg::Rcx, Reg::Rdx,
+];
+#[cfg(windows)]
+pub(super) const VALUE_REGS: [Reg; 6] = [
+    Reg::Rbx, Reg::R8, Reg::R9, Reg::R10, Reg::Rcx, Reg::Rdx,
+];
+
+/*
+    Where a value lives.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Loc {
+    Reg(Reg),
+    Slot(u32),      /* A spill slot in the frame */
+}
+
+/*
+    The range of positions a value is live in, from its definition to its last use.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Interval {
+    pub value: Value,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub(super) struct Allocation {
+    pub locs: Vec<Option<Loc>>,     /* Indexed by value, None for constants and removed values */
+    pub slots: u32,                 /* Spill slots used */
+}
+
+impl Allocation {
+    pub fn loc(&self, value: Value) -> Option<Loc> {
+        self.locs[value.0 as usize]
+    }
+}
+
+/*
+    Number every definition and use. Block parameters are defined at the
+    block's own position, each operation at the next one, and the
+    terminator reads its operands at the last.
+*/
+struct Positions {
+    blocks: Vec<(usize, usize)>,    /* (start, terminator) of each block */
+    defs: Vec<Option<usize>>,       /* Indexed by value */
+}
+
+fn number(func: &Func) -> Positions {
+    let mut pos = 0;
+    let mut blocks = vec![];
+    let mut defs = vec![None; func.values.len()];
+
+    for block in &func.blocks {
+        let start = pos;
+        for &param in &block.params {
+            defs[param.0 as usize] = Some(start);
+        }
+        for &value in &block.insts {
+            pos += 1;
+            defs[value.0 as usize] = Some(pos);
+        }
+        pos += 1;
+        blocks.push((start, pos));
+        pos += 1;
+    }
+
+    Positions { blocks, defs }
+}
+
+/*
+    The values live on entry to every block, to a fixed point.
+*/
+fn live_in(func: &Func) -> Vec<HashSet<Value>> {
+    let mut live_in: Vec<HashSet<Value>> = vec![HashSet::new(); func.blocks.len()];
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+
+        for (id, block) in func.blocks.iter().enumerate().rev() {
+            let mut live: HashSet<Value> = block.term.targets().iter().flat_map(|t| live_in[t.block.0 as usize].iter().copied()).collect();
+            live.extend(block.term.operands());
+
+            for &value in block.insts.iter().rev() {
+                live.remove(&value);
+                live.extend(func.value(value).op.operands());
+            }
+            for param in &block.params {
+                live.remove(param);
+            }
+
+            if live != live_in[id] {
+                live_in[id] = live;
+                changed = true;
+            }
+        }
+    }
+
+    live_in
+}
+
+/*
+    One interval per value which needs a location, sorted by start.
+    Constants, operations without a result and block parameters nothing
+    uses don't. Parameters all start at the same position, so an unused one
+    would end right where the others start and share a register with them,
+    which the jumps into the block would then overwrite. A value
+    which is live out of a block is live to the end of it and one which is
+    live into a block from the start, and the interval covers the whole
+    range, holes included.
+*/
+pub(super) fn intervals(func: &Func) -> Vec<Interval> {
+    let positions = number(func);
+    let live_in = live_in(func);
+    let mut ranges: Vec<Option<(usize, usize)>> = positions.defs.iter().map(|def| def.map(|pos| (pos, pos))).collect();
+
+    let mut extend = |value: Value, pos: usize| {
+        if let Some((start, end)) = &mut ranges[value.0 as usize] {
+            *start = (*start).min(pos);
+            *end = (*end).max(pos);
+        }
+    };
+
+    for (id, block) in func.blocks.iter().enumerate() {
+        let (start, term) = positions.blocks[id];
+
+        for &value in &live_in[id] {
+            extend(value, start);
+        }
+        for target in block.term.targets() {
+            for &value in &live_in[target.block.0 as usize] {
+                extend(value, term);
+            }
+        }
+        for (i, &value) in block.insts.iter().enumerate() {
+            for operand in func.value(value).op.operands() {
+                extend(operand, start + 1 + i);
+            }
+        }
+        for operand in block.term.operands() {
+            extend(operand, term);
+        }
+    }
+
+    let mut intervals: Vec<Interval> = ranges
+        .iter()
+        .enumerate()
+        .filter(|&(v, _)| !matches!(func.values[v].op, Op::Const(_)) && func.values[v].ty != Type::Unit)
+        .filter(|&(v, range)| !(matches!(func.values[v].op, Op::Param) && matches!(range, Some((start, end)) if start == end)))
+        .filter_map(|(v, range)| range.map(|(start, end)| Interval { value: Value(v as u32), start, end }))
+        .collect();
+
+    intervals.sort_by_key(|i| (i.start, i.value));
+    intervals
+}
+
+/*
+    Positions of the operations which need rcx and rdx for themselves.
+*/
+fn clobbers(func: &Func) -> (Vec<usize>, Vec<usize>) {
+    let positions = number(func);
+    let mut shifts = vec![];
+    let mut divisions = vec![];
+
+    for block in &func.blocks {
+        for &value in &block.insts {
+            let pos = positions.defs[value.0 as usize].unwrap();
+            match func.value(value).op {
+                Op::Binary(BinOp::Shl | BinOp::Shr | BinOp::LShr, ..) => shifts.push(pos),
+                Op::Binary(BinOp::Div | BinOp::Mod | BinOp::UDiv | BinOp::UMod, ..) => divisions.push(pos),
+                _ => {}
+            }
+        }
+    }
+
+    (shifts, divisions)
+}
+
+/*
+    Linear scan register allocation.
+
+    Intervals are visited by start, and each one takes a register nobody
+    active holds. When there is none, whichever of it and the active
+    intervals ends last is spilled to a slot of its own, so the registers
+    go to the values needed soonest.
+
+    An interval can't take rcx if a shift happens strictly inside it, or
+    rdx for a division, as those registers are overwritten there. Operands
+    and results may still use them, the lowering moves them out of the way.
+*/
+pub(super) fn allocate(func: &Func) -> Allocation {
+    let (shifts, divisions) = clobbers(func);
+    let inside = |positions: &[usize], i: &Interval| positions.iter().any(|&p| i.start < p && p < i.end);
+    let allowed = |reg: Reg, i: &Interval| match reg {
+        Reg::Rcx => !inside(&shifts, i),
+        Reg::Rdx => !inside(&divisions, i),
+        _ => true,
+    };
+
+    let mut locs = vec![None; func.values.len()];
+    let mut active: Vec<(Interval, Reg)> = vec![];
+    let mut slots = 0;
+    let mut spill = |locs: &mut Vec<Option<Loc>>, value: Value| {
+        locs[value.0 as usize] = Some(Loc::Slot(slots));
+        slots += 1;
+    };
+
+    for interval in intervals(func) {
+        /*
+            An interval ending where this one starts is only read there,
+            before this one is written, so its register is free again.
+        */
+        active.retain(|(i, _)| i.end > interval.start);
+
+        let free = VALUE_REGS
+            .into_iter()
+            .find(|&reg| allowed(reg, &interval) && active.iter().all(|&(_, r)| r != reg));
+
+        if let Some(reg) = free {
+            locs[interval.value.0 as usize] = Some(Loc::Reg(reg));
+            active.push((interval, reg));
+            continue;
+        }
+
+        let victim = active
+            .iter()
+            .enumerate()
+            .filter(|(_, (i, reg))| i.end > interval.end && allowed(*reg, &interval))
+            .max_by_key(|(_, (i, _))| i.end)
+            .map(|(n, _)| n);
+
+        match victim {
+            Some(n) => {
+                let (old, reg) = active.swap_remove(n);
+                spill(&mut locs, old.value);
+                locs[interval.value.0 as usize] = Some(Loc::Reg(reg));
+                active.push((interval, reg));
+            }
+            None => spill(&mut locs, interval.value),
+        }
+    }
+
+    Allocation { locs, slots }
+}
+
+#[cfg(test)]
+mod tests;
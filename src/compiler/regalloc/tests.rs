@@ -0,0 +1,130 @@
+use super::*;
+use crate::ir::build;
+use crate::program::Instruction::*;
+use crate::program::{Instruction, Program};
+
+fn func(insts: Vec<Instruction>) -> Func {
+    build(&Program::new(insts)).unwrap()
+}
+
+/*
+    Every interval with its register, or None if it was spilled.
+*/
+fn regs(func: &Func, alloc: &Allocation) -> Vec<(Interval, Option<Reg>)> {
+    intervals(func)
+        .into_iter()
+        .map(|i| match alloc.loc(i.value) {
+            Some(Loc::Reg(reg)) => (i, Some(reg)),
+            _ => (i, None),
+        })
+        .collect()
+}
+
+#[test]
+fn straight_line() {
+    let func = func(vec![Load(1), LoadVar(0), Add, Store(1), LoadVar(1), Ret]);
+
+    assert_eq!(intervals(&func), [
+        Interval { value: Value(1), start: 2, end: 3 },
+        Interval { value: Value(2), start: 3, end: 4 },
+        Interval { value: Value(4), start: 5, end: 6 },
+    ]);
+
+    /*
+        v1 is last read where v2 is written, so they can share
+    */
+    let alloc = allocate(&func);
+    assert_eq!(alloc.loc(Value(0)), None);
+    assert_eq!(alloc.loc(Value(1)), Some(Loc::Reg(Reg::Rbx)));
+    assert_eq!(alloc.loc(Value(2)), Some(Loc::Reg(Reg::Rbx)));
+    assert_eq!(alloc.slots, 0);
+}
+
+/*
+    A value defined before a loop and used after it is live all the way round.
+*/
+#[test]
+fn loops_extend_intervals() {
+    let func = func(vec![
+        LoadVar(0), Load(0),
+        Label(1), Load(1), Add, Dup, Load(10), Lt, JmpIf(1),
+        Add, Ret,
+    ]);
+    let intervals = intervals(&func);
+    let outer = intervals.iter().find(|i| i.value == Value(2)).unwrap();       /* b1's first parameter */
+    let counter = intervals.iter().find(|i| i.value == Value(3)).unwrap();
+
+    assert_eq!(func.value(Value(2)).op, Op::Param);
+    assert!(outer.end > counter.end, "{:?} {:?}", outer, counter);
+}
+
+/*
+    Twelve values at once, for eight registers, six on Windows.
+*/
+#[test]
+fn spills_the_furthest_use() {
+    let mut insts: Vec<Instruction> = (0..12).map(LoadVar).collect();
+    insts.extend([Add; 11]);
+    insts.push(Ret);
+
+    let func = func(insts);
+    let alloc = allocate(&func);
+    let spilled = 12 - VALUE_REGS.len() as u32;
+    assert_eq!(alloc.slots, spilled);
+
+    /*
+        The first values loaded are the last ones added
+    */
+    for v in 0..spilled {
+        assert!(matches!(alloc.loc(Value(v)), Some(Loc::Slot(_))), "v{} {:?}", v, alloc.loc(Value(v)));
+    }
+
+    let regs = regs(&func, &alloc);
+    for (a, reg) in &regs {
+        let clash = regs.iter().find(|(b, r)| a != b && reg.is_some() && r == reg && a.start < b.end && b.start < a.end);
+        assert!(clash.is_none(), "{:?} and {:?} share {:?}", a, clash, reg);
+    }
+}
+
+/*
+    Nine values live across a division and a shift, so some of them would
+    get rcx and rdx if it wasn't for the constraints.
+*/
+#[test]
+fn rcx_and_rdx_are_not_live_across_their_uses() {
+    let mut insts: Vec<Instruction> = (0..9).map(LoadVar).collect();
+    insts.extend([LoadVar(9), LoadVar(10), Div, LoadVar(11), Shl, Store(12)]);
+    insts.extend([Add; 8]);
+    insts.push(Ret);
+
+    let func = func(insts);
+    let alloc = allocate(&func);
+    let (shifts, divisions) = clobbers(&func);
+    assert_eq!((shifts.len(), divisions.len()), (1, 1));
+
+    for (i, reg) in regs(&func, &alloc) {
+        let across = |p: usize| i.start < p && p < i.end;
+        if reg == Some(Reg::Rcx) {
+            assert!(!across(shifts[0]), "{:?} lives across a shift in rcx", i);
+        }
+        if reg == Some(Reg::Rdx) {
+            assert!(!across(divisions[0]), "{:?} lives across a division in rdx", i);
+        }
+    }
+    assert!(alloc.slots > 0);
+}
+
+/*
+    Both values on the stack at the jump become parameters of the next
+    block, which only returns the top one.
+*/
+#[test]
+fn unused_params_get_no_location() {
+    let func = func(vec![LoadVar(0), LoadVar(1), Jmp(1), Label(1), Ret]);
+    let params = &func.blocks.last().unwrap().params;
+    assert_eq!(params.len(), 2);
+
+    let alloc = allocate(&func);
+    assert_eq!(alloc.loc(params[0]), None);
+    assert!(matches!(alloc.loc(params[1]), Some(Loc::Reg(_))));
+}
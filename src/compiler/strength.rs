@@ -0,0 +1,190 @@
+use crate::assembler::Reg;
+use crate::program::Instruction;
+
+use super::Compiler;
+
+/*
+    Strength reduction of Mul, Div and Mod by a constant.
+
+    A `Load(c)` right before one of them means the right operand is known
+    while compiling, so the imul/idiv can be swapped for cheaper instructions:
+
+        x * 2^k     ->  shl x, k
+        x * 3/5/9   ->  lea x, [x + x * 2/4/8]
+        x * c       ->  imul x, x, c
+        x / 2^k     ->  sar x, k, after biasing negative x so it rounds towards zero
+        x / c       ->  the high half of x times a magic number, plus a fixup
+        x % c       ->  x - (x / c) * c
+
+    Dividing by 0, or by -1 which overflows for i64::MIN, is left to idiv so
+    those still trap.
+*/
+impl Compiler {
+    /*
+        Emit `op` with `c` as its right operand. Emits nothing and returns false
+        when `op` is not worth reducing, so the caller can compile it as usual.
+    */
+    pub(super) fn emit_binop_const(&mut self, op: Instruction, c: i64) -> bool {
+        match op {
+            Instruction::Mul => self.emit_mul_const(c),
+            Instruction::Div => self.emit_div_const(c, false),
+            Instruction::Mod => self.emit_div_const(c, true),
+            _ => false,
+        }
+    }
+
+    fn emit_mul_const(&mut self, c: i64) -> bool {
+        let pow2 = c > 0 && (c as u64).is_power_of_two();
+        let imm = i32::try_from(c);
+        if imm.is_err() && !pow2 {
+            return false;
+        }
+
+        let x = self.pop_slot();
+
+        match c {
+            0 => self.asm.mov_ri(x, 0),
+            1 => {}
+            -1 => self.asm.neg(x),
+            3 | 5 | 9 => self.asm.lea(x, x, x, (c - 1) as u8),
+            _ if pow2 => self.asm.shl_ri(x, c.trailing_zeros() as u8),
+            _ => self.asm.imul_ri(x, x, imm.unwrap()),
+        }
+
+        self.push_slot(x);
+        true
+    }
+
+    fn emit_div_const(&mut self, c: i64, rem: bool) -> bool {
+        if matches!(c, 0 | -1 | i64::MIN) {
+            return false;
+        }
+
+        let x = self.pop_slot();
+
+        if c == 1 {
+            if rem {
+                self.asm.mov_ri(x, 0);
+            }
+            self.push_slot(x);
+            return true;
+        }
+
+        let d = c.unsigned_abs();
+        if d.is_power_of_two() {
+            /*
+                sar rounds towards minus infinity, adding 2^k - 1 to a negative
+                x first makes it round towards zero like idiv. The bias is the
+                sign bits of x shifted down to k ones, or zero.
+            */
+            let k = d.trailing_zeros() as u8;
+            self.asm.mov_rr(Reg::Rcx, x);
+            self.asm.sar_ri(Reg::Rcx, 63);
+            self.asm.shr_ri(Reg::Rcx, 64 - k);
+            self.asm.add_rr(Reg::Rcx, x);
+            self.asm.sar_ri(Reg::Rcx, k);           /* x / 2^k */
+
+            if rem {
+                self.asm.shl_ri(Reg::Rcx, k);
+                self.asm.sub_rr(x, Reg::Rcx);       /* the sign of c makes no difference to the remainder */
+            } else {
+                if c < 0 {
+                    self.asm.neg(Reg::Rcx);
+                }
+                self.asm.mov_rr(x, Reg::Rcx);
+            }
+
+            self.push_slot(x);
+            return true;
+        }
+
+        /*
+            The one operand imul works on rax and leaves the high half in rdx,
+            so anything else cached in rax has to go to the stack first. x
+            waits in r11, the scratch register nothing lives in across instructions.
+        */
+        if self.cache.contains(&Reg::Rax) {
+            self.flush();
+        }
+
+        let (magic, shift) = magic(c);
+        self.asm.mov_rr(Reg::R11, x);
+        self.asm.mov_ri(Reg::Rax, magic);
+        self.asm.imul_wide(Reg::R11);
+
+        if c > 0 && magic < 0 {
+            self.asm.add_rr(Reg::Rdx, Reg::R11);
+        } else if c < 0 && magic > 0 {
+            self.asm.sub_rr(Reg::Rdx, Reg::R11);
+        }
+
+        if shift > 0 {
+            self.asm.sar_ri(Reg::Rdx, shift);
+        }
+
+        self.asm.mov_rr(Reg::Rax, Reg::Rdx);
+        self.asm.shr_ri(Reg::Rax, 63);
+        self.asm.add_rr(Reg::Rdx, Reg::Rax);        /* plus one when negative, rounding towards zero */
+
+        if rem {
+            match i32::try_from(c) {
+                Ok(imm) => self.asm.imul_ri(Reg::Rdx, Reg::Rdx, imm),
+                Err(_) => {
+                    self.asm.mov_ri(Reg::Rax, c);
+                    self.asm.imul_rr(Reg::Rdx, Reg::Rax);
+                }
+            }
+            self.asm.mov_rr(Reg::Rax, Reg::R11);
+            self.asm.sub_rr(Reg::Rax, Reg::Rdx);
+        } else {
+            self.asm.mov_rr(Reg::Rax, Reg::Rdx);
+        }
+
+        self.push_slot(Reg::Rax);
+        true
+    }
+}
+
+/*
+    The magic number and shift for signed division by `d`, which must not be
+    0, 1, -1 or a power of two. From Hacker's Delight, figure 10-1.
+*/
+pub(super) fn magic(d: i64) -> (i64, u8) {
+    const TWO63: u64 = 1 << 63;
+
+    let ad = d.unsigned_abs();
+    let t = TWO63 + ((d as u64) >> 63);
+    let anc = t - 1 - t % ad;                   /* Absolute value of nc */
+    let mut p = 63;
+    let (mut q1, mut r1) = (TWO63 / anc, TWO63 % anc);
+    let (mut q2, mut r2) = (TWO63 / ad, TWO63 % ad);
+
+    loop {
+        p += 1;
+
+        q1 = q1.wrapping_mul(2);
+        r1 = r1.wrapping_mul(2);
+        if r1 >= anc {
+            q1 = q1.wrapping_add(1);
+            r1 -= anc;
+        }
+
+        q2 = q2.wrapping_mul(2);
+        r2 = r2.wrapping_mul(2);
+        if r2 >= ad {
+            q2 = q2.wrapping_add(1);
+            r2 -= ad;
+        }
+
+        let delta = ad - r2;
+        if !(q1 < delta || (q1 == delta && r1 == 0)) {
+            break;
+        }
+    }
+
+    let magic = q2.wrapping_add(1) as i64;
+    (if d < 0 { magic.wrapping_neg() } else { magic }, p - 64)
+}
+
+#[cfg(test)]
+mod tests;
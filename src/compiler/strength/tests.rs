@@ -0,0 +1,13 @@
+use super::*;
+
+/*
+    Values from the tables in Hacker's Delight.
+*/
+#[test]
+fn magic_numbers() {
+    assert_eq!(magic(3), (0x5555555555555556, 0));
+    assert_eq!(magic(5), (0x6666666666666667, 1));
+    assert_eq!(magic(7), (0x4924924924924925, 1));
+    assert_eq!(magic(-5), (0x9999999999999999_u64 as i64, 1));
+    assert_eq!(magic(-7), (0xB6DB6DB6DB6DB6DB_u64 as i64, 1));
+}
@@ -0,0 +1,352 @@
+use super::*;
+use crate::program::Instruction::*;
+
+/*
+    Run code straight out of the compiler.
+*/
+fn execute(code: &[u8]) -> Result<i64, crate::InvokeError> {
+    unsafe { crate::Invoker::new().execute(code) }
+}
+
+#[test]
+fn prologue_and_epilogue() {
+    let code = Compiler::new().compile(&Program::new(vec![])).unwrap();
+    assert_eq!(code, [
+        0x53,                                       /* push rbx */
+        0x55,                                       /* push rbp */
+        0x48,0x89,0xE5,                             /* mov rbp, rsp */
+        0x48,0x81,0xEC,0x00,0x04,0x00,0x00,         /* sub rsp, 1024 */
+        0x31,0xC0,                                  /* xor eax, eax, nothing on the stack returns 0 */
+        0x48,0x89,0xEC,                             /* mov rsp, rbp */
+        0x5D,                                       /* pop rbp */
+        0x5B,                                       /* pop rbx */
+        0xC3,                                       /* ret */
+    ]);
+}
+
+#[test]
+fn load_encodes_imm64() {
+    let code = Compiler::new().compile(&Program::new(vec![Load(0x0102_0304_0506_0708)])).unwrap();
+    assert_eq!(&code[12..23], [0x48,0xB8,0x08,0x07,0x06,0x05,0x04,0x03,0x02,0x01,0x50]);
+}
+
+#[test]
+fn load_picks_the_shortest_encoding() {
+    let load = |mode, v| Compiler::with_mode(mode).compile(&Program::new(vec![Load(v)])).unwrap()[12..].to_vec();
+
+    assert_eq!(load(CodegenMode::Stack, 0)[..2], [0x6A,0x00]);
+    assert_eq!(load(CodegenMode::Stack, -5)[..2], [0x6A,0xFB]);
+    assert_eq!(load(CodegenMode::Stack, 1000)[..5], [0x68,0xE8,0x03,0x00,0x00]);
+    assert_eq!(load(CodegenMode::Stack, 0xFFFF_FFFF)[..6], [0xB8,0xFF,0xFF,0xFF,0xFF,0x50]);
+    assert_eq!(load(CodegenMode::Cached, 0)[..2], [0x31,0xC0]);
+    assert_eq!(load(CodegenMode::Cached, 7)[..5], [0xB8,0x07,0x00,0x00,0x00]);
+    assert_eq!(load(CodegenMode::Cached, -7)[..7], [0x48,0xC7,0xC0,0xF9,0xFF,0xFF,0xFF]);
+}
+
+#[test]
+fn div_encodes_idiv_rbx() {
+    let code = Compiler::new().compile(&Program::new(vec![Div])).unwrap();
+    assert_eq!(&code[12..19], [0x5B,0x58,0x48,0x99,0x48,0xF7,0xFB]);
+}
+
+#[test]
+fn compare_and_branch_are_fused() {
+    let code = Compiler::new().compile(&Program::new(vec![Lt, JmpIf(1), Label(1)])).unwrap();
+    assert_eq!(&code[12..19], [0x5B,0x58,0x48,0x39,0xD8,0x7C,0x00]);
+
+    let code = Compiler::new().compile(&Program::new(vec![Lt, JmpIfNot(1), Label(1)])).unwrap();
+    assert_eq!(&code[12..19], [0x5B,0x58,0x48,0x39,0xD8,0x7D,0x00]);
+}
+
+#[test]
+fn multiply_by_a_power_of_two_is_a_shift() {
+    let code = Compiler::new().compile(&Program::new(vec![Load(8), Mul])).unwrap();
+    assert_eq!(&code[12..18], [0x58,0x48,0xC1,0xE0,0x03,0x50]);
+}
+
+#[test]
+fn divide_by_a_constant_skips_idiv() {
+    let code = Compiler::new().compile(&Program::new(vec![Load(10), Div])).unwrap();
+    assert!(!code.windows(2).any(|w| w == [0x48,0x99]), "{:02X?}", code);
+}
+
+/*
+    The README's counter, i lives in r12 so nothing touches the frame.
+*/
+#[test]
+fn variables_live_in_registers() {
+    let program = Program::new(vec![
+        Load(0), Store(0),
+        Label(1), LoadVar(0), Load(10), Gte, JmpIf(2),
+        LoadVar(0), Load(1), Add, Store(0), Jmp(1),
+        Label(2), LoadVar(0), Ret,
+    ]);
+
+    for mode in [CodegenMode::Stack, CodegenMode::Cached] {
+        let code = Compiler::with_mode(mode).compile(&program).unwrap();
+        assert_eq!(code[..3], [0x53,0x41,0x54], "push rbx; push r12");
+        assert_eq!(code[code.len() - 4..], [0x41,0x5C,0x5B,0xC3], "pop r12; pop rbx; ret");
+
+        let frame = code.windows(2).any(|w| matches!(w[0], 0x89 | 0x8B) && w[1] & 0xC7 == 0x45);
+        assert!(!frame, "{:02X?}", code);
+        assert_eq!(execute(&code), Ok(10));
+    }
+}
+
+#[test]
+fn compiling_twice_is_identical() {
+    let program = Program::new(vec![Load(1), JmpIf(1), Load(2), Label(1), Ret]);
+    let mut compiler = Compiler::new();
+    assert_eq!(compiler.compile(&program), compiler.compile(&program));
+}
+
+/*
+    (10 + 5) * 3 - 2, every push is popped straight back off.
+*/
+#[test]
+fn peephole_removes_stack_traffic() {
+    let mut compiler = Compiler::new();
+    let program = Program::new(vec![Load(10), Load(5), Add, Load(3), Mul, Load(2), Sub, Ret]);
+    let code = compiler.compile(&program).unwrap();
+    let stats = compiler.peephole_stats();
+
+    assert_eq!(stats.after, code.len());
+    assert!(stats.after < stats.before, "{:?}", stats);
+    assert_eq!(execute(&code), Ok(43));
+}
+
+/*
+    The same arithmetic, with the operands never leaving rax/rbx.
+*/
+#[test]
+fn cached_mode_keeps_operands_in_registers() {
+    let program = Program::new(vec![Load(1000), Load(500), Add, Load(300), Mul, Dup, Add, Load(200), Sub, Ret]);
+    let stack = Compiler::new().compile(&program).unwrap();
+    let cached = Compiler::with_mode(CodegenMode::Cached).compile(&program).unwrap();
+
+    assert!(cached.len() < stack.len(), "{} >= {}", cached.len(), stack.len());
+    assert!(!cached[12..cached.len() - 6].iter().any(|&b| b == 0x50 || b == 0x53), "{:02X?}", cached);
+    assert_eq!(execute(&stack), Ok(899_800));
+    assert_eq!(execute(&cached), Ok(899_800));
+}
+
+/*
+    Slots under the top are read where they are, not popped off and pushed back.
+*/
+#[test]
+fn pick_reads_the_stack_in_place() {
+    let program = Program::new(vec![Load(1), Load(2), Load(3), Load(4), Pick(3), Ret]);
+    let code = Compiler::new().compile(&program).unwrap();
+
+    assert!(code.windows(5).any(|w| w == [0x48, 0x8B, 0x44, 0x24, 0x18]), "no mov rax, [rsp+24] in {:02X?}", code);
+    assert_eq!(execute(&code), Ok(1));
+}
+
+#[test]
+fn halt_stops_compilation() {
+    let mut compiler = Compiler::new();
+    let halted = compiler.compile(&Program::new(vec![Load(1), Ret, Halt]));
+    let trailing = compiler.compile(&Program::new(vec![Load(1), Ret, Halt, Load(2), Add, Ret]));
+    assert_eq!(halted, trailing);
+}
+
+#[test]
+fn exit_label_is_reserved() {
+    let err = Compiler::new().compile(&Program::new(vec![Label(EXIT_LABEL)]));
+    assert_eq!(err, Err(CompileError::ReservedLabel(EXIT_LABEL)));
+}
+
+#[test]
+fn duplicate_label() {
+    let err = Compiler::new().compile(&Program::new(vec![Label(1), Label(1), Ret]));
+    assert_eq!(err, Err(CompileError::DuplicateLabel(1)));
+}
+
+#[test]
+fn undefined_label() {
+    let err = Compiler::new().compile(&Program::new(vec![Load(1), JmpIf(3), Ret]));
+    assert_eq!(err, Err(CompileError::UndefinedLabel(3)));
+}
+
+/*
+    Variables sit at [rbp - (id + 1) * 8], which runs out of displacement long before ids do.
+*/
+#[test]
+fn oversized_frame() {
+    for id in [1 << 28, u32::MAX] {
+        let program = Program::new(vec![Load(1), Store(id), LoadVar(id), Ret]);
+        for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+            let options = CompileOptions { opt_level, ..CompileOptions::default() };
+            assert_eq!(Compiler::with_options(options).compile(&program), Err(CompileError::FrameTooLarge));
+        }
+    }
+
+    let id = (i32::MAX as u32 - 15) / 8 - 1;
+    assert!(Compiler::new().compile(&Program::new(vec![Load(1), Store(id), LoadVar(id), Ret])).is_ok());
+}
+
+#[test]
+fn compiler_is_reusable_after_an_error() {
+    let mut compiler = Compiler::new();
+    assert!(compiler.compile(&Program::new(vec![Jmp(3)])).is_err());
+    assert!(compiler.compile(&Program::new(vec![Load(1), Ret])).is_ok());
+}
+
+/*
+    The Swap stops the bytecode folder, the SSA form doesn't care.
+*/
+#[test]
+fn ssa_mode_folds_through_the_stack() {
+    let program = Program::new(vec![Load(200), Load(300), Swap, Sub, Dup, Mul, Ret]);
+    let cached = Compiler::with_mode(CodegenMode::Cached).compile(&program).unwrap();
+    let ssa = Compiler::with_mode(CodegenMode::Ssa).compile(&program).unwrap();
+
+    assert!(ssa.len() < cached.len(), "{} >= {}", ssa.len(), cached.len());
+    assert!(ssa.windows(5).any(|w| w == [0xB8, 0x10, 0x27, 0x00, 0x00]), "mov eax, 10000: {:02X?}", ssa);
+    assert_eq!(execute(&ssa), Ok(10_000));
+}
+
+/*
+    Every time round the loop leaves one more value on the stack.
+*/
+#[test]
+fn ssa_mode_falls_back_on_unbalanced_stacks() {
+    let program = Program::new(vec![Load(3), Label(1), Dup, Load(1), Sub, Dup, JmpIf(1), Add, Add, Add, Ret]);
+    let cached = Compiler::with_mode(CodegenMode::Cached).compile(&program);
+    let ssa = Compiler::with_mode(CodegenMode::Ssa).compile(&program);
+
+    assert_eq!(ssa, cached);
+    assert_eq!(execute(&ssa.unwrap()), Ok(6));
+}
+
+/*
+    Nothing but the variables' own registers and the values' registers,
+    no stack traffic and no frame slots.
+*/
+#[test]
+fn ssa_values_live_in_registers() {
+    let program = Program::new(vec![
+        Load(7), Store(0), Load(3), Store(1),
+        LoadVar(0), LoadVar(1), Mul, LoadVar(0), LoadVar(1), Sub, Dup, Mul, Add, Ret,
+    ]);
+    let code = Compiler::with_mode(CodegenMode::Ssa).compile(&program).unwrap();
+    let body = &code[8..code.len() - 8];
+
+    let frame = code.windows(2).any(|w| matches!(w[0], 0x89 | 0x8B) && w[1] & 0xC7 == 0x45);
+    assert!(!frame, "{:02X?}", code);
+    assert!(!body.iter().any(|&b| (0x50..0x60).contains(&b)), "push/pop in {:02X?}", body);
+    assert_eq!(execute(&code), Ok(37));
+}
+
+fn with_level(opt_level: OptLevel) -> Compiler {
+    Compiler::with_options(CompileOptions { opt_level, ..CompileOptions::default() })
+}
+
+/*
+    No shift for the multiply, no peephole, every push and pop where the bytecode put it.
+*/
+#[test]
+fn o0_compiles_every_instruction_on_its_own() {
+    let program = Program::new(vec![Load(6), Load(8), Mul, Ret]);
+    let mut compiler = with_level(OptLevel::O0);
+    let plain = compiler.compile(&program).unwrap();
+    let stats = compiler.peephole_stats();
+    let optimised = Compiler::new().compile(&program).unwrap();
+
+    assert_eq!((stats.before, stats.after, stats.removed), (plain.len(), plain.len(), 0));
+    assert!(plain.len() > optimised.len(), "{} <= {}", plain.len(), optimised.len());
+    assert!(!plain.windows(3).any(|w| w == [0x48, 0xC1, 0xE0]), "shl rax in {:02X?}", plain);
+    assert!(plain.windows(2).any(|w| w == [0x6A, 0x08]), "push 8 in {:02X?}", plain);
+    assert_eq!(execute(&plain), Ok(48));
+}
+
+#[test]
+fn o0_keeps_variables_in_the_frame() {
+    let program = Program::new(vec![Load(5), Store(0), LoadVar(0), LoadVar(0), Add, Ret]);
+    let code = with_level(OptLevel::O0).compile(&program).unwrap();
+
+    assert_eq!(code[..2], [0x53, 0x55], "only rbx saved: {:02X?}", code);
+    assert_eq!(execute(&code), Ok(10));
+}
+
+/*
+    Folded ahead of time the whole program is a single constant.
+*/
+#[test]
+fn o2_folds_constants_before_compiling() {
+    let program = Program::new(vec![Load(10), Load(5), Add, Load(3), Mul, Ret]);
+    let folded = with_level(OptLevel::O2).compile(&program).unwrap();
+    let cached = Compiler::with_mode(CodegenMode::Cached).compile(&program).unwrap();
+
+    assert!(folded.len() < cached.len(), "{} >= {}", folded.len(), cached.len());
+    assert!(folded.windows(5).any(|w| w == [0xB8, 45, 0x00, 0x00, 0x00]), "mov eax, 45: {:02X?}", folded);
+    assert_eq!(execute(&folded), Ok(45));
+}
+
+#[test]
+fn levels_pick_a_mode_unless_told_otherwise() {
+    assert_eq!(Compiler::new().mode(), CodegenMode::Stack);
+    assert_eq!(with_level(OptLevel::O2).mode(), CodegenMode::Cached);
+    assert_eq!(with_level(OptLevel::O3).mode(), CodegenMode::Ssa);
+
+    let options = CompileOptions { opt_level: OptLevel::O3, mode: Some(CodegenMode::Stack), ..CompileOptions::default() };
+    assert_eq!(Compiler::with_options(options).mode(), CodegenMode::Stack);
+}
+
+#[test]
+fn verify_rejects_bad_stacks() {
+    let verify = |insts| {
+        let options = CompileOptions { verify: true, ..CompileOptions::default() };
+        Compiler::with_options(options).compile(&Program::new(insts))
+    };
+
+    assert_eq!(verify(vec![Load(1), Add, Ret]), Err(CompileError::StackUnderflow(1)));
+    assert_eq!(
+        verify(vec![Load(3), Label(1), Dup, Load(1), Sub, Dup, JmpIf(1), Add, Ret]),
+        Err(CompileError::UnbalancedStack(1)),
+    );
+    assert_eq!(verify(vec![Jmp(3)]), Err(CompileError::UndefinedLabel(3)));
+    assert!(verify(vec![Load(1), Load(2), Add, Ret]).is_ok());
+    assert_eq!(verify(vec![Load(1), Load(2), Pick(2), Ret]), Err(CompileError::StackUnderflow(2)));
+    assert!(verify(vec![Load(1), Load(2), Pick(1), Ret]).is_ok());
+
+    /* Without verification the compiler takes what it is given */
+    assert!(Compiler::new().compile(&Program::new(vec![Load(1), Add, Ret])).is_ok());
+}
+
+/*
+    A branch at the very end can fall off it, which is fine.
+*/
+#[test]
+fn verify_accepts_a_branch_at_the_end() {
+    let program = Program::new(vec![Load(7), Label(0), Load(0), JmpIf(0)]);
+
+    for opt_level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+        let options = CompileOptions { opt_level, verify: true, ..CompileOptions::default() };
+        let code = Compiler::with_options(options).compile(&program).unwrap();
+        assert_eq!(execute(&code), Ok(7), "{:?}", opt_level);
+    }
+}
+
+#[test]
+fn source_map_follows_the_bytecode() {
+    let options = CompileOptions { emit_source_map: true, ..CompileOptions::default() };
+    let mut compiler = Compiler::with_options(options);
+    let code = compiler.compile(&Program::new(vec![Load(1), Load(2), Lt, JmpIf(1), Label(1), Load(7), Ret])).unwrap();
+    let map = compiler.source_map().unwrap();
+    let pcs: Vec<usize> = map.entries().iter().map(|&(pc, _)| pc).collect();
+
+    assert_eq!(pcs, [0, 1, 2, 3, 4, 5, 6]);
+    assert!(map.entries().windows(2).all(|w| w[0].1 <= w[1].1), "{:?}", map);
+    assert_eq!(map.offset(0), Some(12));                   /* right after the prologue */
+    assert_eq!(map.offset(2), map.offset(3));               /* compare and branch are fused */
+    assert_eq!(map.offset(4), map.offset(5));               /* labels have no code */
+    assert_eq!(map.instruction(map.offset(6).unwrap()), Some(6));
+    assert_eq!(map.instruction(0), None);
+    assert!(map.offset(6).unwrap() < code.len());
+    assert_eq!(execute(&code), Ok(7));
+
+    compiler.compile(&Program::new(vec![Load(1), Ret])).unwrap();
+    assert_eq!(compiler.source_map().unwrap().entries().len(), 2);
+    assert!(Compiler::new().source_map().is_none());
+}
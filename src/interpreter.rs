@@ -0,0 +1,330 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::compiler::{CompileError, EXIT_LABEL};
+use crate::program::{Instruction, Program};
+
+/*
+    Everything that stops an interpreted program early. The native backend
+    raises a hardware exception in the same situations instead.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    DivideByZero,       /* Div, Mod, UDiv or UMod by zero */
+    Overflow,           /* i64::MIN / -1, which does not fit */
+    StackUnderflow,     /* Popped more values than were pushed */
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trap::DivideByZero => write!(f, "division by zero"),
+            Trap::Overflow => write!(f, "division overflow"),
+            Trap::StackUnderflow => write!(f, "operand stack underflow"),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/*
+    A program with its labels resolved, ready to be interpreted.
+*/
+#[derive(Debug, Clone)]
+pub struct Bytecode {
+    insts: Vec<Instruction>,
+    labels: HashMap<u32, usize>,    /* Map labels to the index of the instruction after them */
+}
+
+/*
+    A structure to represent the interpreter, the portable alternative to `Compiler` + `Invoker`.
+*/
+pub struct Interpreter;
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /*
+        Resolve the labels of a program, rejecting the same programs the compiler rejects.
+        The compiler stops at the first `Halt`, so everything after it is dropped here too.
+    */
+    pub fn prepare(&mut self, program: &Program) -> Result<Bytecode, CompileError> {
+        let insts = program.insts();
+        let end = insts.iter().position(|i| matches!(i, Instruction::Halt)).unwrap_or(insts.len());
+        let insts = insts[..end].to_vec();
+
+        let mut labels = HashMap::new();
+        for (pc, i) in insts.iter().enumerate() {
+            if let Instruction::Label(id) = i {
+                if *id == EXIT_LABEL {
+                    return Err(CompileError::ReservedLabel(*id));
+                }
+
+                if labels.insert(*id, pc + 1).is_some() {
+                    return Err(CompileError::DuplicateLabel(*id));
+                }
+            }
+        }
+
+        for i in &insts {
+            if let Instruction::Jmp(id) | Instruction::JmpIf(id) | Instruction::JmpIfNot(id) = i
+                && !labels.contains_key(id)
+            {
+                return Err(CompileError::UndefinedLabel(*id));
+            }
+        }
+
+        Ok(Bytecode { insts, labels })
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bytecode {
+    /*
+        Run the program and return what it returned. Running off the end
+        returns the top of the stack, like the compiled code returns rax.
+    */
+    pub fn run(&self) -> Result<i64, Trap> {
+        self.run_counting(&mut 0)
+    }
+
+    /*
+        Like `run`, adding the number of jumps taken back to an earlier label,
+        the way loops go round, to `back_edges`.
+    */
+    pub(crate) fn run_counting(&self, back_edges: &mut u64) -> Result<i64, Trap> {
+        let mut stack: Vec<i64> = vec![];
+        let mut vars: HashMap<u32, i64> = HashMap::new();
+        let mut pc = 0;
+
+        macro_rules! pop {
+            () => {
+                stack.pop().ok_or(Trap::StackUnderflow)?
+            };
+        }
+
+        macro_rules! jump {
+            ($label:expr) => {{
+                let target = self.labels[$label];
+                if target < pc {
+                    *back_edges += 1;
+                }
+                pc = target;
+            }};
+        }
+
+        while let Some(inst) = self.insts.get(pc) {
+            pc += 1;
+
+            match inst {
+                Instruction::Load(v) => stack.push(*v),
+                Instruction::LoadF(v) => stack.push(v.to_bits() as i64),
+                Instruction::Dup => {
+                    let a = *stack.last().ok_or(Trap::StackUnderflow)?;
+                    stack.push(a);
+                }
+                Instruction::Pop => {
+                    pop!();
+                }
+                Instruction::Swap => {
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(b);
+                    stack.push(a);
+                }
+                Instruction::Over => pick(&mut stack, 1)?,
+                Instruction::Rot => roll(&mut stack, 2)?,
+                Instruction::Nip => {
+                    let b = pop!();
+                    pop!();
+                    stack.push(b);
+                }
+                Instruction::Tuck => {
+                    let b = pop!();
+                    let a = pop!();
+                    stack.extend([b, a, b]);
+                }
+                Instruction::Pick(n) => pick(&mut stack, *n)?,
+                Instruction::Roll(n) => roll(&mut stack, *n)?,
+                Instruction::Neg | Instruction::Not | Instruction::Bnot |
+                Instruction::FNeg | Instruction::FSqrt | Instruction::IToF | Instruction::FToI => {
+                    let a = pop!();
+                    stack.push(eval_unary(inst, a).unwrap());
+                }
+                Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div | Instruction::Mod |
+                Instruction::Eq | Instruction::Ne | Instruction::Lt | Instruction::Gt | Instruction::Lte | Instruction::Gte |
+                Instruction::And | Instruction::Or | Instruction::Band | Instruction::Bor | Instruction::Bxor |
+                Instruction::Shl | Instruction::Shr |
+                Instruction::UDiv | Instruction::UMod | Instruction::LShr |
+                Instruction::ULt | Instruction::UGt | Instruction::ULte | Instruction::UGte |
+                Instruction::FAdd | Instruction::FSub | Instruction::FMul | Instruction::FDiv |
+                Instruction::FEq | Instruction::FNe | Instruction::FLt | Instruction::FGt | Instruction::FLte | Instruction::FGte => {
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(eval_binop(inst, a, b).unwrap()?);
+                }
+                Instruction::Store(id) => {
+                    let a = pop!();
+                    vars.insert(*id, a);
+                }
+                Instruction::LoadVar(id) => stack.push(vars.get(id).copied().unwrap_or(0)),
+                Instruction::Jmp(label) => jump!(label),
+                Instruction::JmpIf(label) => {
+                    if pop!() != 0 {
+                        jump!(label);
+                    }
+                }
+                Instruction::JmpIfNot(label) => {
+                    if pop!() == 0 {
+                        jump!(label);
+                    }
+                }
+                Instruction::Label(_) => {}
+                /* TODO: write like the compiled code will, which right now is not at all */
+                Instruction::Write | Instruction::WriteChar => {}
+                Instruction::Read | Instruction::Call(_) => {}
+                Instruction::Ret => return Ok(pop!()),
+                Instruction::Halt => unreachable!("dropped by prepare"),
+            }
+        }
+
+        Ok(stack.last().copied().unwrap_or(0))
+    }
+}
+
+/*
+    The result of a binary instruction on `a` and `b` (`b` being the top of the stack),
+    or None if `inst` is not a binary instruction. This is what every backend must compute.
+*/
+pub(crate) fn eval_binop(inst: &Instruction, a: i64, b: i64) -> Option<Result<i64, Trap>> {
+    Some(Ok(match inst {
+        Instruction::Add => a.wrapping_add(b),
+        Instruction::Sub => a.wrapping_sub(b),
+        Instruction::Mul => a.wrapping_mul(b),
+        Instruction::Div => return Some(divide(a, b, i64::checked_div)),
+        Instruction::Mod => return Some(divide(a, b, i64::checked_rem)),
+        Instruction::Eq => (a == b) as i64,
+        Instruction::Ne => (a != b) as i64,
+        Instruction::Lt => (a < b) as i64,
+        Instruction::Gt => (a > b) as i64,
+        Instruction::Lte => (a <= b) as i64,
+        Instruction::Gte => (a >= b) as i64,
+        Instruction::And | Instruction::Band => a & b,      /* And/Or are compiled as bitwise too */
+        Instruction::Or | Instruction::Bor => a | b,
+        Instruction::Bxor => a ^ b,
+        Instruction::Shl => a.wrapping_shl(b as u32),       /* Count masked to 6 bits, like cl */
+        Instruction::Shr => a.wrapping_shr(b as u32),
+        Instruction::UDiv => return Some(divide_unsigned(a, b, u64::checked_div)),
+        Instruction::UMod => return Some(divide_unsigned(a, b, u64::checked_rem)),
+        Instruction::LShr => (a as u64).wrapping_shr(b as u32) as i64,
+        Instruction::ULt => ((a as u64) < b as u64) as i64,
+        Instruction::UGt => (a as u64 > b as u64) as i64,
+        Instruction::ULte => (a as u64 <= b as u64) as i64,
+        Instruction::UGte => (a as u64 >= b as u64) as i64,
+        Instruction::FAdd => float_result(float(a) + float(b), a, b),
+        Instruction::FSub => float_result(float(a) - float(b), a, b),
+        Instruction::FMul => float_result(float(a) * float(b), a, b),
+        Instruction::FDiv => float_result(float(a) / float(b), a, b),
+        Instruction::FEq => (float(a) == float(b)) as i64,
+        Instruction::FNe => (float(a) != float(b)) as i64,
+        Instruction::FLt => (float(a) < float(b)) as i64,
+        Instruction::FGt => (float(a) > float(b)) as i64,
+        Instruction::FLte => (float(a) <= float(b)) as i64,
+        Instruction::FGte => (float(a) >= float(b)) as i64,
+        _ => return None,
+    }))
+}
+
+/*
+    The result of a unary instruction, or None if `inst` is not a unary instruction.
+*/
+pub(crate) fn eval_unary(inst: &Instruction, a: i64) -> Option<i64> {
+    match inst {
+        Instruction::Neg => Some(a.wrapping_neg()),
+        Instruction::Not => Some((a == 0) as i64),
+        Instruction::Bnot => Some(!a),
+        Instruction::FNeg => Some(a ^ i64::MIN),                /* Flip the sign bit, NaNs included */
+        Instruction::FSqrt => Some(float_result(float(a).sqrt(), a, a)),
+        Instruction::IToF => Some((a as f64).to_bits() as i64),
+        Instruction::FToI => {
+            let f = float(a);
+            if f.is_nan() || !(i64::MIN as f64..-(i64::MIN as f64)).contains(&f) {
+                Some(i64::MIN)                                  /* What cvttsd2si gives for anything which doesn't fit */
+            } else {
+                Some(f as i64)
+            }
+        }
+        _ => None,
+    }
+}
+
+/*
+    The default NaN SSE2 produces for invalid operations like 0 / 0 or sqrt(-1).
+*/
+const DEFAULT_NAN: i64 = 0xFFF8_0000_0000_0000_u64 as i64;
+
+fn float(bits: i64) -> f64 {
+    f64::from_bits(bits as u64)
+}
+
+/*
+    The bits of a float result. Which NaN comes out isn't up to IEEE 754, so
+    this does what SSE2 does: a NaN operand is passed on quieted, `a` first,
+    and otherwise it's the default NaN.
+*/
+fn float_result(r: f64, a: i64, b: i64) -> i64 {
+    if !r.is_nan() {
+        r.to_bits() as i64
+    } else if float(a).is_nan() {
+        a | 1 << 51
+    } else if float(b).is_nan() {
+        b | 1 << 51
+    } else {
+        DEFAULT_NAN
+    }
+}
+
+/*
+    Signed division, trapping where idiv would.
+*/
+fn divide(a: i64, b: i64, f: fn(i64, i64) -> Option<i64>) -> Result<i64, Trap> {
+    if b == 0 {
+        return Err(Trap::DivideByZero);
+    }
+
+    f(a, b).ok_or(Trap::Overflow)
+}
+
+/*
+    Where the value `n` below the top of the stack is.
+*/
+fn slot(stack: &[i64], n: u32) -> Result<usize, Trap> {
+    stack.len().checked_sub(n as usize + 1).ok_or(Trap::StackUnderflow)
+}
+
+fn pick(stack: &mut Vec<i64>, n: u32) -> Result<(), Trap> {
+    let i = slot(stack, n)?;
+    stack.push(stack[i]);
+    Ok(())
+}
+
+fn roll(stack: &mut Vec<i64>, n: u32) -> Result<(), Trap> {
+    let i = slot(stack, n)?;
+    let v = stack.remove(i);
+    stack.push(v);
+    Ok(())
+}
+
+/*
+    Unsigned division never overflows, zero is the only thing to check for.
+*/
+fn divide_unsigned(a: i64, b: i64, f: fn(u64, u64) -> Option<u64>) -> Result<i64, Trap> {
+    f(a as u64, b as u64).map(|r| r as i64).ok_or(Trap::DivideByZero)
+}
@@ -0,0 +1,201 @@
+#[cfg(windows)]
+use winapi::um::{
+    memoryapi::{VirtualAlloc, VirtualFree, VirtualProtect},
+    winnt::{MEM_COMMIT, MEM_RELEASE, MEM_RESERVE, PAGE_READWRITE, PAGE_EXECUTE_READ},
+};
+
+#[cfg(windows)]
+use winapi::shared::minwindef::DWORD;
+
+#[cfg(windows)]
+use std::ptr;
+
+use std::fmt;
+
+/*
+    Everything that can go wrong while preparing code for execution.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum InvokeError {
+    AllocFailed,        /* Could not allocate memory for the code */
+    ProtectFailed,      /* Could not make the allocated memory executable */
+}
+
+impl fmt::Display for InvokeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvokeError::AllocFailed => write!(f, "failed to allocate memory for Invoker"),
+            InvokeError::ProtectFailed => write!(f, "failed to make memory executable for Invoker"),
+        }
+    }
+}
+
+impl std::error::Error for InvokeError {}
+
+/*
+    A structure to represent an invoker, this will handle the execution of the bytecode generated from the compiler.
+*/
+pub struct Invoker;
+
+/*
+    Machine code loaded into executable memory, which can be called any
+    number of times. The memory is released when this is dropped.
+*/
+pub struct Function {
+    ptr: *mut u8,       /* Start of the executable mapping */
+    size: usize,        /* Size of the mapping, whole pages */
+}
+
+impl Invoker {
+    pub fn new() -> Self {
+        Self
+    }
+
+    /**
+        Finally, execute the code from the compiler.
+
+        # Safety
+        `code` is run as is, so it must be the output of `Compiler::compile`,
+        byte for byte, for a program which passes verification: compiled with
+        `CompileOptions::verify` on, or one which would have passed it. The
+        compiled code trusts the operand stack never to underflow or differ in
+        depth between paths, and where it does it overwrites the frame and the
+        return address. Anything else can do whatever it likes to the process.
+    */
+    pub unsafe fn execute(&mut self, code: &[u8]) -> Result<i64, InvokeError> {
+        Ok(unsafe { self.load(code) }?.call())
+    }
+
+    /**
+        Copy code into executable memory once, to call it many times.
+
+        # Safety
+        The same as for `execute`, calling the function runs `code`.
+    */
+    pub unsafe fn load(&mut self, code: &[u8]) -> Result<Function, InvokeError> {
+        let psize = 4096;       /* Page size */
+        let csize = (code.len().max(1) + psize - 1) & !(psize - 1);    /* Calculate the size of the generate code */
+
+        unsafe {
+            /*
+                Load the code for UNIX systems.
+            */
+            #[cfg(unix)]
+            {
+                /*
+                    Pointer to the allocated memory
+                */
+                let p = libc::mmap(
+                    std::ptr::null_mut(),
+                    csize,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0
+                );
+
+                if p == libc::MAP_FAILED {
+                    return Err(InvokeError::AllocFailed);
+                }
+
+                /*
+                    Then copy the code to memory whichi is executable
+                */
+                std::ptr::copy_nonoverlapping(code.as_ptr(), p as *mut u8, code.len());
+
+                /*
+                    And then obviously, make it executable.
+                */
+                if libc::mprotect(p, csize, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                    libc::munmap(p, csize);
+                    return Err(InvokeError::ProtectFailed);
+                }
+
+                Ok(Function { ptr: p as *mut u8, size: csize })
+            }
+
+            /*
+                Load the code for Windows systems.
+            */
+            #[cfg(windows)]
+            {
+                /*
+                    Pointer to the allocated memory
+                */
+                let p = VirtualAlloc(
+                    ptr::null_mut(),
+                    csize,
+                    MEM_COMMIT | MEM_RESERVE,
+                    PAGE_READWRITE
+                );
+
+                if p.is_null() {
+                    return Err(InvokeError::AllocFailed);
+                }
+
+                /*
+                    Then copy the code to memory whichi is executable
+                */
+                std::ptr::copy_nonoverlapping(code.as_ptr(), p as *mut u8, code.len());
+
+                /*
+                    And then obviously, make it executable.
+                */
+                let mut old: DWORD = 0;
+                if VirtualProtect(p, csize, PAGE_EXECUTE_READ, &mut old) == 0 {
+                    VirtualFree(p, 0, MEM_RELEASE);
+                    return Err(InvokeError::ProtectFailed);
+                }
+
+                Ok(Function { ptr: p as *mut u8, size: csize })
+            }
+        }
+    }
+}
+
+impl Function {
+    /*
+        Cast the function pointer from the executable memory
+        and then, at last, execute.
+    */
+    pub fn call(&self) -> i64 {
+        unsafe {
+            let f: extern "C" fn() -> i64 = std::mem::transmute(self.ptr);
+            f()
+        }
+    }
+
+    /*
+        Bytes of executable memory held.
+    */
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Function {
+    /*
+        Cleanup allocated memory
+    */
+    fn drop(&mut self) {
+        unsafe {
+            #[cfg(unix)]
+            libc::munmap(self.ptr as *mut libc::c_void, self.size);
+
+            #[cfg(windows)]
+            VirtualFree(self.ptr as _, 0, MEM_RELEASE);
+        }
+    }
+}
+
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Function").field("ptr", &self.ptr).field("size", &self.size).finish()
+    }
+}
+
+impl Default for Invoker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use super::{BinOp, Block, BlockData, CmpOp, Func, Op, Target, Terminator, Type, UnOp, Value, ValueData};
+use crate::cfg::{BlockId, Cfg};
+use crate::compiler::CompileError;
+use crate::program::{Instruction, Program};
+
+/*
+    Programs which cannot be turned into SSA.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub enum BuildError {
+    Invalid(CompileError),      /* Rejected by the backends too */
+    StackUnderflow(usize),      /* The instruction at this index pops more than was pushed */
+    UnbalancedStack(usize),     /* Paths meet at the block starting at this index with different stack depths */
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Invalid(e) => write!(f, "{}", e),
+            BuildError::StackUnderflow(pc) => write!(f, "instruction {} underflows the operand stack", pc),
+            BuildError::UnbalancedStack(pc) => write!(f, "stack depth differs between paths into instruction {}", pc),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/*
+    Translate a program into SSA.
+
+    Blocks come from the control flow graph, minus the unreachable ones.
+    Each block is run on a simulated operand stack holding values instead of
+    numbers: a `Load` pushes a new constant, an `Add` pops two values and
+    pushes the value of their sum and so on. A block starts with one
+    parameter per slot on the stack when it is entered, so every path into
+    a block must agree on how deep the stack is.
+
+    `Ret`, `Halt` and running off the end return the top of the stack, or 0
+    if it is empty, like the interpreter.
+*/
+pub fn build(program: &Program) -> Result<Func, BuildError> {
+    let cfg = Cfg::build(program).map_err(BuildError::Invalid)?;
+    let order = cfg.reverse_post_order();
+    let index: HashMap<BlockId, Block> = order.iter().enumerate().map(|(i, &id)| (id, Block(i as u32))).collect();
+
+    let mut builder = Builder {
+        cfg: &cfg,
+        index,
+        depths: HashMap::new(),
+        exits: vec![],
+        func: Func { blocks: vec![], values: vec![] },
+    };
+
+    if let Some(&entry) = order.first() {
+        builder.depths.insert(entry, 0);
+    }
+
+    for &id in &order {
+        builder.block(id)?;
+    }
+
+    for depth in std::mem::take(&mut builder.exits) {
+        let params: Vec<Value> = (0..depth).map(|_| builder.value(Op::Param, Type::I64)).collect();
+        let mut insts = vec![];
+        let term = builder.ret(&params, &mut insts);
+        builder.func.blocks.push(BlockData { params, insts, term });
+    }
+
+    if builder.func.blocks.is_empty() {
+        let mut insts = vec![];
+        let term = builder.ret(&[], &mut insts);
+        builder.func.blocks.push(BlockData { params: vec![], insts, term });
+    }
+
+    Ok(builder.func)
+}
+
+struct Builder<'a> {
+    cfg: &'a Cfg,
+    index: HashMap<BlockId, Block>,     /* Cfg block to SSA block */
+    depths: HashMap<BlockId, usize>,    /* Stack depth on entry, known once any predecessor is built */
+    exits: Vec<usize>,                  /* Stack depths of the blocks returning for branches off the end */
+    func: Func,
+}
+
+impl Builder<'_> {
+    fn value(&mut self, op: Op, ty: Type) -> Value {
+        let value = Value(self.func.values.len() as u32);
+        let block = Block(self.func.blocks.len() as u32);
+        self.func.values.push(ValueData { op, ty, block });
+        value
+    }
+
+    /*
+        A target for jumping to `id` with `stack` on the operand stack.
+    */
+    fn target(&mut self, id: BlockId, stack: &[Value]) -> Result<Target, BuildError> {
+        match self.depths.insert(id, stack.len()) {
+            Some(depth) if depth != stack.len() => Err(BuildError::UnbalancedStack(self.cfg.block(id).range.start)),
+            _ => Ok(Target { block: self.index[&id], args: stack.to_vec() }),
+        }
+    }
+
+    /*
+        A target for a branch not taken at the very end of the program, which
+        runs off the end. It gets a block of its own returning the top of the
+        stack, built after every other one.
+    */
+    fn exit(&mut self, stack: &[Value]) -> Target {
+        let block = Block((self.index.len() + self.exits.len()) as u32);
+        self.exits.push(stack.len());
+        Target { block, args: stack.to_vec() }
+    }
+
+    /*
+        Build the block for `id`, which must be the next one in reverse post order.
+    */
+    fn block(&mut self, id: BlockId) -> Result<(), BuildError> {
+        let range = self.cfg.block(id).range.clone();
+        let depth = self.depths[&id];
+        let params: Vec<Value> = (0..depth).map(|_| self.value(Op::Param, Type::I64)).collect();
+        let mut stack = params.clone();
+        let mut insts = vec![];
+        let mut term = None;
+
+        for (pc, inst) in range.clone().zip(self.cfg.insts(id)) {
+            macro_rules! pop {
+                () => {
+                    stack.pop().ok_or(BuildError::StackUnderflow(pc))?
+                };
+            }
+
+            let mut emit = |this: &mut Self, op: Op, ty: Type, stack: &mut Vec<Value>| {
+                let v = this.value(op, ty);
+                insts.push(v);
+                if ty != Type::Unit {
+                    stack.push(v);
+                }
+            };
+
+            match *inst {
+                Instruction::Load(c) => emit(self, Op::Const(c), Type::I64, &mut stack),
+                Instruction::LoadF(c) => emit(self, Op::Const(c.to_bits() as i64), Type::F64, &mut stack),
+                Instruction::Dup => {
+                    let a = *stack.last().ok_or(BuildError::StackUnderflow(pc))?;
+                    stack.push(a);
+                }
+                Instruction::Pop => {
+                    pop!();
+                }
+                Instruction::Swap => {
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(b);
+                    stack.push(a);
+                }
+                Instruction::Over | Instruction::Pick(_) => {
+                    let n = if let Instruction::Pick(n) = *inst { n } else { 1 };
+                    let i = slot(&stack, n).ok_or(BuildError::StackUnderflow(pc))?;
+                    stack.push(stack[i]);
+                }
+                Instruction::Rot | Instruction::Roll(_) => {
+                    let n = if let Instruction::Roll(n) = *inst { n } else { 2 };
+                    let i = slot(&stack, n).ok_or(BuildError::StackUnderflow(pc))?;
+                    let a = stack.remove(i);
+                    stack.push(a);
+                }
+                Instruction::Nip => {
+                    let b = pop!();
+                    pop!();
+                    stack.push(b);
+                }
+                Instruction::Tuck => {
+                    let b = pop!();
+                    let a = pop!();
+                    stack.extend([b, a, b]);
+                }
+                Instruction::Neg | Instruction::Not | Instruction::Bnot |
+                Instruction::FNeg | Instruction::FSqrt | Instruction::IToF | Instruction::FToI => {
+                    let a = pop!();
+                    let (op, ty) = unary(inst);
+                    emit(self, Op::Unary(op, a), ty, &mut stack);
+                }
+                Instruction::Eq | Instruction::Ne | Instruction::Lt | Instruction::Gt | Instruction::Lte | Instruction::Gte |
+                Instruction::FEq | Instruction::FNe | Instruction::FLt | Instruction::FGt | Instruction::FLte | Instruction::FGte |
+                Instruction::ULt | Instruction::UGt | Instruction::ULte | Instruction::UGte => {
+                    let b = pop!();
+                    let a = pop!();
+                    emit(self, Op::Cmp(cmp(inst), a, b), Type::Bool, &mut stack);
+                }
+                Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div | Instruction::Mod |
+                Instruction::And | Instruction::Or | Instruction::Band | Instruction::Bor | Instruction::Bxor |
+                Instruction::Shl | Instruction::Shr |
+                Instruction::UDiv | Instruction::UMod | Instruction::LShr => {
+                    let b = pop!();
+                    let a = pop!();
+                    emit(self, Op::Binary(binary(inst), a, b), Type::I64, &mut stack);
+                }
+                Instruction::FAdd | Instruction::FSub | Instruction::FMul | Instruction::FDiv => {
+                    let b = pop!();
+                    let a = pop!();
+                    emit(self, Op::Binary(binary(inst), a, b), Type::F64, &mut stack);
+                }
+                Instruction::Store(var) => {
+                    let a = pop!();
+                    emit(self, Op::StoreVar(var, a), Type::Unit, &mut stack);
+                }
+                Instruction::LoadVar(var) => emit(self, Op::LoadVar(var), Type::I64, &mut stack),
+                Instruction::Label(_) => {}
+                Instruction::Write | Instruction::WriteChar | Instruction::Read | Instruction::Call(_) => {}
+                Instruction::Jmp(label) => {
+                    let target = self.cfg.label(label).unwrap();
+                    term = Some(Terminator::Jump(self.target(target, &stack)?));
+                }
+                Instruction::JmpIf(label) | Instruction::JmpIfNot(label) => {
+                    let cond = pop!();
+                    let taken = self.target(self.cfg.label(label).unwrap(), &stack)?;
+                    let next = if id + 1 < self.cfg.blocks().len() {
+                        self.target(id + 1, &stack)?
+                    } else {
+                        self.exit(&stack)
+                    };
+
+                    term = Some(match inst {
+                        Instruction::JmpIf(_) => Terminator::Branch(cond, taken, next),
+                        _ => Terminator::Branch(cond, next, taken),
+                    });
+                }
+                Instruction::Ret => term = Some(Terminator::Return(pop!())),
+                Instruction::Halt => term = Some(self.ret(&stack, &mut insts)),
+            }
+        }
+
+        /*
+            No jump at the end, so it falls through to the next block, or off the end of the program.
+        */
+        let term = match term {
+            Some(term) => term,
+            None if id + 1 < self.cfg.blocks().len() => Terminator::Jump(self.target(id + 1, &stack)?),
+            None => self.ret(&stack, &mut insts),
+        };
+
+        self.func.blocks.push(BlockData { params, insts, term });
+        Ok(())
+    }
+
+    /*
+        Return the top of the stack, or 0.
+    */
+    fn ret(&mut self, stack: &[Value], insts: &mut Vec<Value>) -> Terminator {
+        match stack.last() {
+            Some(&top) => Terminator::Return(top),
+            None => {
+                let zero = self.value(Op::Const(0), Type::I64);
+                insts.push(zero);
+                Terminator::Return(zero)
+            }
+        }
+    }
+}
+
+/*
+    Where the value `n` below the top of the stack is, if it's that deep.
+*/
+fn slot(stack: &[Value], n: u32) -> Option<usize> {
+    stack.len().checked_sub(n as usize + 1)
+}
+
+fn unary(inst: &Instruction) -> (UnOp, Type) {
+    match inst {
+        Instruction::Neg => (UnOp::Neg, Type::I64),
+        Instruction::Not => (UnOp::Not, Type::Bool),
+        Instruction::Bnot => (UnOp::Bnot, Type::I64),
+        Instruction::FNeg => (UnOp::FNeg, Type::F64),
+        Instruction::FSqrt => (UnOp::FSqrt, Type::F64),
+        Instruction::IToF => (UnOp::IToF, Type::F64),
+        Instruction::FToI => (UnOp::FToI, Type::I64),
+        _ => unreachable!(),
+    }
+}
+
+fn cmp(inst: &Instruction) -> CmpOp {
+    match inst {
+        Instruction::Eq => CmpOp::Eq,
+        Instruction::Ne => CmpOp::Ne,
+        Instruction::Lt => CmpOp::Lt,
+        Instruction::Gt => CmpOp::Gt,
+        Instruction::Lte => CmpOp::Lte,
+        Instruction::Gte => CmpOp::Gte,
+        Instruction::FEq => CmpOp::FEq,
+        Instruction::FNe => CmpOp::FNe,
+        Instruction::FLt => CmpOp::FLt,
+        Instruction::FGt => CmpOp::FGt,
+        Instruction::FLte => CmpOp::FLte,
+        Instruction::FGte => CmpOp::FGte,
+        Instruction::ULt => CmpOp::ULt,
+        Instruction::UGt => CmpOp::UGt,
+        Instruction::ULte => CmpOp::ULte,
+        Instruction::UGte => CmpOp::UGte,
+        _ => unreachable!(),
+    }
+}
+
+/*
+    And/Or are bitwise at runtime, so they are the same operation as Band/Bor.
+*/
+fn binary(inst: &Instruction) -> BinOp {
+    match inst {
+        Instruction::Add => BinOp::Add,
+        Instruction::Sub => BinOp::Sub,
+        Instruction::Mul => BinOp::Mul,
+        Instruction::Div => BinOp::Div,
+        Instruction::Mod => BinOp::Mod,
+        Instruction::And | Instruction::Band => BinOp::And,
+        Instruction::Or | Instruction::Bor => BinOp::Or,
+        Instruction::Bxor => BinOp::Xor,
+        Instruction::Shl => BinOp::Shl,
+        Instruction::Shr => BinOp::Shr,
+        Instruction::FAdd => BinOp::FAdd,
+        Instruction::FSub => BinOp::FSub,
+        Instruction::FMul => BinOp::FMul,
+        Instruction::FDiv => BinOp::FDiv,
+        Instruction::UDiv => BinOp::UDiv,
+        Instruction::UMod => BinOp::UMod,
+        Instruction::LShr => BinOp::LShr,
+        _ => unreachable!(),
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{HashMap, HashSet};
+
+use super::loops::{find_loops, Loop};
+use super::{BinOp, Block, BlockData, Func, Op, Target, Terminator, Type, Value, ValueData};
+
+/*
+    Loop invariant code motion.
+
+    Operations inside a loop which compute the same thing every time round
+    are moved into a preheader, a new block right before the loop header
+    which every entry into the loop goes through, so they run once instead.
+    Invariant are constants, loads of variables the loop never stores to,
+    header parameters every back edge passes back unchanged, and anything
+    computed only from those. Division is only hoisted by a constant which
+    can't trap, as the loop body might never have run it.
+
+    Inner loops are done first, so what they hoist can then leave the outer
+    loop too. Returns how many operations were hoisted.
+*/
+pub fn hoist_invariants(func: &mut Func) -> usize {
+    let mut loops = find_loops(func);
+    let mut hoisted = 0;
+
+    while !loops.is_empty() {
+        let l = loops.remove(0);
+        let Some(preheader) = hoist(func, &l) else {
+            continue;
+        };
+
+        hoisted += func.blocks[preheader.0 as usize].insts.len();
+
+        /*
+            Block ids from the preheader on went up by one, and the
+            preheader is part of every loop around this one.
+        */
+        for outer in &mut loops {
+            let inside = outer.contains(l.header);
+            for b in outer.latches.iter_mut().chain(outer.body.iter_mut()).chain([&mut outer.header]) {
+                if *b >= preheader {
+                    b.0 += 1;
+                }
+            }
+            if inside {
+                outer.body.push(preheader);
+                outer.body.sort();
+            }
+        }
+    }
+
+    hoisted
+}
+
+/*
+    Hoist the invariant operations of one loop, returning its new preheader
+    or None if there was nothing to hoist.
+*/
+fn hoist(func: &mut Func, l: &Loop) -> Option<Block> {
+    let header = l.header;
+    let stored: HashSet<u32> = l
+        .body
+        .iter()
+        .flat_map(|&b| func.block(b).insts.iter())
+        .filter_map(|&v| match func.value(v).op {
+            Op::StoreVar(id, _) => Some(id),
+            _ => None,
+        })
+        .collect();
+
+    /*
+        Loop values known to be invariant, and what stands for them in the
+        preheader. A header parameter stands for the preheader's own
+        parameter in the same position, a parameter which only ever gets one
+        invariant value for what that stands for, and a hoisted operation
+        for itself.
+    */
+    let params: Vec<Value> = (0..func.block(header).params.len()).map(|_| new_param(func, header)).collect();
+    let mut invariant: HashMap<Value, Value> = HashMap::new();
+    let mut moved: Vec<Value> = vec![];
+
+    for (i, &param) in func.block(header).params.iter().enumerate() {
+        let unchanged = l.latches.iter().all(|&latch| args_to(func, latch, header).all(|args| args[i] == param));
+        if unchanged {
+            invariant.insert(param, params[i]);
+        }
+    }
+
+    for &b in &l.body {
+        if b != header {
+            let preds = func.preds(b);
+            for (i, &param) in func.block(b).params.iter().enumerate() {
+                let incoming: HashSet<Option<Value>> = preds
+                    .iter()
+                    .flat_map(|&p| args_to(func, p, b).map(move |args| args[i]).collect::<Vec<_>>())
+                    .filter(|&arg| arg != param)
+                    .map(|arg| lookup(func, l, &invariant, arg))
+                    .collect();
+
+                if let [Some(value)] = incoming.into_iter().collect::<Vec<_>>()[..] {
+                    invariant.insert(param, value);
+                }
+            }
+        }
+
+        for &value in &func.block(b).insts {
+            let op = func.value(value).op;
+            let operands: Option<Vec<Value>> = op.operands().into_iter().map(|v| lookup(func, l, &invariant, v)).collect();
+
+            let hoistable = match op {
+                Op::Param | Op::StoreVar(..) => false,
+                Op::LoadVar(id) => !stored.contains(&id),
+                Op::Binary(BinOp::Div | BinOp::Mod, _, divisor) => {
+                    matches!(func.value(divisor).op, Op::Const(c) if c != 0 && c != -1) && operands.is_some()
+                }
+                Op::Binary(BinOp::UDiv | BinOp::UMod, _, divisor) => {
+                    matches!(func.value(divisor).op, Op::Const(c) if c != 0) && operands.is_some()
+                }
+                _ => operands.is_some(),
+            };
+
+            if hoistable {
+                invariant.insert(value, value);
+                moved.push(value);
+            }
+        }
+    }
+
+    if moved.is_empty() {
+        func.values.truncate(func.values.len() - params.len());
+        return None;
+    }
+
+    /*
+        Move the operations over, reading the preheader's versions of their operands.
+    */
+    let hoisted: HashSet<Value> = moved.iter().copied().collect();
+    for &b in &l.body {
+        func.blocks[b.0 as usize].insts.retain(|v| !hoisted.contains(v));
+    }
+    for &value in &moved {
+        let data = &mut func.values[value.0 as usize];
+        data.op = rename(data.op, |v| invariant.get(&v).copied().unwrap_or(v));
+    }
+
+    let preheader = insert_block(func, header, BlockData {
+        params: params.clone(),
+        insts: moved,
+        term: Terminator::Jump(Target { block: Block(header.0 + 1), args: params }),
+    });
+
+    /*
+        Entries into the loop now go through the preheader.
+    */
+    let header = Block(header.0 + 1);
+    for (id, block) in func.blocks.iter_mut().enumerate() {
+        let latch = l.latches.iter().any(|b| (if *b >= preheader { b.0 + 1 } else { b.0 }) == id as u32);
+        if id as u32 == preheader.0 || latch {
+            continue;
+        }
+
+        match &mut block.term {
+            Terminator::Jump(t) => retarget(t, header, preheader),
+            Terminator::Branch(_, a, b) => {
+                retarget(a, header, preheader);
+                retarget(b, header, preheader);
+            }
+            Terminator::Return(_) => {}
+        }
+    }
+
+    let block = &func.blocks[preheader.0 as usize];
+    for v in block.params.iter().chain(&block.insts) {
+        func.values[v.0 as usize].block = preheader;
+    }
+
+    Some(preheader)
+}
+
+/*
+    What stands for `value` in the preheader, if it is invariant in the loop.
+    Values from outside the loop stand for themselves.
+*/
+fn lookup(func: &Func, l: &Loop, invariant: &HashMap<Value, Value>, value: Value) -> Option<Value> {
+    match invariant.get(&value) {
+        Some(&v) => Some(v),
+        None if !l.contains(func.value(value).block) => Some(value),
+        None => None,
+    }
+}
+
+/*
+    The arguments `from` passes to `to`, once per edge between them.
+*/
+fn args_to(func: &Func, from: Block, to: Block) -> impl Iterator<Item = &[Value]> {
+    func.block(from).term.targets().into_iter().filter(move |t| t.block == to).map(|t| t.args.as_slice())
+}
+
+/*
+    A parameter for the preheader about to be inserted at `at`.
+*/
+fn new_param(func: &mut Func, at: Block) -> Value {
+    let value = Value(func.values.len() as u32);
+    func.values.push(ValueData { op: Op::Param, ty: Type::I64, block: at });
+    value
+}
+
+fn rename(op: Op, f: impl Fn(Value) -> Value) -> Op {
+    match op {
+        Op::Unary(u, a) => Op::Unary(u, f(a)),
+        Op::Binary(b, x, y) => Op::Binary(b, f(x), f(y)),
+        Op::Cmp(c, x, y) => Op::Cmp(c, f(x), f(y)),
+        Op::StoreVar(id, a) => Op::StoreVar(id, f(a)),
+        op => op,
+    }
+}
+
+fn retarget(target: &mut Target, from: Block, to: Block) {
+    if target.block == from {
+        target.block = to;
+    }
+}
+
+/*
+    Insert a block at `at`, moving every block from there on up by one.
+    The new block's own targets are taken as they are.
+*/
+fn insert_block(func: &mut Func, at: Block, data: BlockData) -> Block {
+    let shift = |b: &mut Block| {
+        if *b >= at {
+            b.0 += 1;
+        }
+    };
+
+    for block in &mut func.blocks {
+        match &mut block.term {
+            Terminator::Jump(t) => shift(&mut t.block),
+            Terminator::Branch(_, a, b) => {
+                shift(&mut a.block);
+                shift(&mut b.block);
+            }
+            Terminator::Return(_) => {}
+        }
+    }
+    for value in &mut func.values {
+        shift(&mut value.block);
+    }
+
+    func.blocks.insert(at.0 as usize, data);
+    at
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,146 @@
+use super::*;
+use crate::ir::build;
+use crate::program::Instruction::*;
+use crate::program::{Instruction, Program};
+
+fn func(insts: Vec<Instruction>) -> Func {
+    build(&Program::new(insts)).unwrap()
+}
+
+fn text(lines: &[&str]) -> String {
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+/*
+    acc += x * y, ten times.
+*/
+#[test]
+fn hoists_loads_and_arithmetic() {
+    let mut func = func(vec![
+        Load(0), Store(0), Load(0),
+        Label(1),
+        LoadVar(1), LoadVar(2), Mul, Add,
+        LoadVar(0), Load(1), Add, Dup, Store(0), Load(10), Lt, JmpIf(1),
+        Ret,
+    ]);
+
+    assert_eq!(hoist_invariants(&mut func), 5);
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v0: i64 = const 0",
+        "  store_var 0, v0",
+        "  v2: i64 = const 0",
+        "  jump b1(v2)",
+        "b1(v15):",
+        "  v4: i64 = load_var 1",
+        "  v5: i64 = load_var 2",
+        "  v6: i64 = mul v4, v5",
+        "  v9: i64 = const 1",
+        "  v12: i64 = const 10",
+        "  jump b2(v15)",
+        "b2(v3):",
+        "  v7: i64 = add v3, v6",
+        "  v8: i64 = load_var 0",
+        "  v10: i64 = add v8, v9",
+        "  store_var 0, v10",
+        "  v13: bool = lt v10, v12",
+        "  branch v13, b2(v7), b3(v7)",
+        "b3(v14):",
+        "  return v14",
+    ]));
+}
+
+/*
+    x stays on the stack all the way round, so x * x is the same every time.
+*/
+#[test]
+fn parameters_passed_back_unchanged_are_invariant() {
+    let mut func = func(vec![
+        LoadVar(1),
+        Label(1),
+        Dup, Dup, Mul, Store(2),
+        LoadVar(0), Load(1), Sub, Dup, Store(0), JmpIf(1),
+        Ret,
+    ]);
+
+    assert_eq!(hoist_invariants(&mut func), 2);
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v0: i64 = load_var 1",
+        "  jump b1(v0)",
+        "b1(v9):",
+        "  v2: i64 = mul v9, v9",
+        "  v5: i64 = const 1",
+        "  jump b2(v9)",
+        "b2(v1):",
+        "  store_var 2, v2",
+        "  v4: i64 = load_var 0",
+        "  v6: i64 = sub v4, v5",
+        "  store_var 0, v6",
+        "  branch v6, b2(v1), b3(v1)",
+        "b3(v8):",
+        "  return v8",
+    ]));
+}
+
+/*
+    Dividing by a variable could trap, and might not have happened at all
+    if the loop left before getting to it. Dividing by 3 can't trap.
+*/
+#[test]
+fn only_safe_divisions_are_hoisted() {
+    let mut func = func(vec![
+        Label(1),
+        LoadVar(1), LoadVar(2), Div, Store(3),
+        LoadVar(1), Load(3), Mod, Store(4),
+        LoadVar(0), Load(1), Sub, Dup, Store(0), JmpIf(1),
+        LoadVar(3), Ret,
+    ]);
+
+    hoist_invariants(&mut func);
+    let preheader = &func.blocks[0];
+    let ops: Vec<Op> = preheader.insts.iter().map(|&v| func.value(v).op).collect();
+
+    assert!(ops.iter().any(|op| matches!(op, Op::Binary(BinOp::Mod, ..))), "{}", func);
+    assert!(!ops.iter().any(|op| matches!(op, Op::Binary(BinOp::Div, ..))), "{}", func);
+}
+
+/*
+    Variables stored to anywhere in the loop stay put.
+*/
+#[test]
+fn stored_variables_are_not_invariant() {
+    let mut func = func(vec![
+        Label(1),
+        LoadVar(0), Load(1), Sub, Dup, Store(0), JmpIf(1),
+        Load(0), Ret,
+    ]);
+
+    assert_eq!(hoist_invariants(&mut func), 1);     /* just the 1 */
+    assert!(matches!(func.value(func.blocks[1].insts[0]).op, Op::LoadVar(0)));
+}
+
+/*
+    x * y is hoisted out of the inner loop into its preheader, and from
+    there out of the outer loop too.
+*/
+#[test]
+fn nested_loops_hoist_all_the_way_out() {
+    let mut func = func(vec![
+        Load(3), Store(0),
+        Label(1),
+        Load(3), Store(1),
+        Label(2),
+        LoadVar(5), LoadVar(6), Mul, Store(7),
+        LoadVar(1), Load(1), Sub, Dup, Store(1), JmpIf(2),
+        LoadVar(0), Load(1), Sub, Dup, Store(0), JmpIf(1),
+        LoadVar(7), Ret,
+    ]);
+
+    assert!(hoist_invariants(&mut func) > 0);
+
+    let mul = (0..func.values.len() as u32).map(Value).find(|&v| matches!(func.value(v).op, Op::Binary(BinOp::Mul, ..))).unwrap();
+    let block = func.value(mul).block;
+    assert!(func.blocks[block.0 as usize].insts.contains(&mul));
+    assert!(find_loops(&func).iter().all(|l| !l.contains(block)), "{}", func);
+}
@@ -0,0 +1,141 @@
+use super::{Block, Func};
+
+/*
+    A natural loop: the blocks which can reach one of the back edges to its
+    header without going through the header, and the header itself.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    pub header: Block,
+    pub latches: Vec<Block>,        /* Blocks with a back edge to the header */
+    pub body: Vec<Block>,           /* Sorted, header included */
+}
+
+impl Loop {
+    pub fn contains(&self, block: Block) -> bool {
+        self.body.binary_search(&block).is_ok()
+    }
+}
+
+/*
+    The immediate dominator of every block, None for the entry and for
+    unreachable blocks.
+
+    Cooper, Harvey and Kennedy's "A Simple, Fast Dominance Algorithm",
+    which relies on the blocks being in reverse post order.
+*/
+pub fn dominators(func: &Func) -> Vec<Option<Block>> {
+    let preds: Vec<Vec<Block>> = (0..func.blocks.len() as u32).map(|b| func.preds(Block(b))).collect();
+    let mut idom: Vec<Option<Block>> = vec![None; func.blocks.len()];
+    if func.blocks.is_empty() {
+        return idom;
+    }
+
+    idom[0] = Some(Block(0));
+    let mut changed = true;
+
+    while changed {
+        changed = false;
+
+        for b in 1..func.blocks.len() {
+            let mut new = None;
+            for &p in preds[b].iter().filter(|p| idom[p.0 as usize].is_some()) {
+                new = Some(match new {
+                    None => p,
+                    Some(other) => intersect(&idom, p, other),
+                });
+            }
+
+            if new.is_some() && new != idom[b] {
+                idom[b] = new;
+                changed = true;
+            }
+        }
+    }
+
+    idom[0] = None;
+    idom
+}
+
+/*
+    The closest common dominator of two blocks, walking up from whichever
+    comes later in reverse post order.
+*/
+fn intersect(idom: &[Option<Block>], mut a: Block, mut b: Block) -> Block {
+    while a != b {
+        while a > b {
+            a = idom[a.0 as usize].unwrap();
+        }
+        while b > a {
+            b = idom[b.0 as usize].unwrap();
+        }
+    }
+    a
+}
+
+/*
+    Whether `a` dominates `b`.
+*/
+pub fn dominates(idom: &[Option<Block>], a: Block, mut b: Block) -> bool {
+    loop {
+        if a == b {
+            return true;
+        }
+        match idom[b.0 as usize] {
+            Some(up) => b = up,
+            None => return false,
+        }
+    }
+}
+
+/*
+    Every natural loop, innermost first. A back edge is an edge to a block
+    which dominates its source, and back edges to the same header make up
+    a single loop.
+*/
+pub fn find_loops(func: &Func) -> Vec<Loop> {
+    let idom = dominators(func);
+    let reachable = |b: Block| b.0 == 0 || idom[b.0 as usize].is_some();
+    let mut loops: Vec<Loop> = vec![];
+
+    for (id, block) in func.blocks.iter().enumerate() {
+        let latch = Block(id as u32);
+        if !reachable(latch) {
+            continue;
+        }
+
+        for target in block.term.targets() {
+            let header = target.block;
+            if !dominates(&idom, header, latch) {
+                continue;
+            }
+
+            match loops.iter_mut().find(|l| l.header == header) {
+                Some(l) if l.latches.contains(&latch) => {}
+                Some(l) => l.latches.push(latch),
+                None => loops.push(Loop { header, latches: vec![latch], body: vec![] }),
+            }
+        }
+    }
+
+    for l in &mut loops {
+        let mut body = vec![l.header];
+        let mut work = l.latches.clone();
+
+        while let Some(b) = work.pop() {
+            if !body.contains(&b) {
+                body.push(b);
+                work.extend(func.preds(b).into_iter().filter(|&p| reachable(p)));
+            }
+        }
+
+        body.sort();
+        l.body = body;
+    }
+
+    loops.sort_by_key(|l| (l.body.len(), l.header));
+    loops
+}
+
+#[cfg(test)]
+mod tests;
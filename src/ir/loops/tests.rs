@@ -0,0 +1,69 @@
+use super::*;
+use crate::ir::build;
+use crate::program::Instruction::*;
+use crate::program::{Instruction, Program};
+
+fn func(insts: Vec<Instruction>) -> Func {
+    build(&Program::new(insts)).unwrap()
+}
+
+/*
+    if (x) { 1 } else { 2 }
+*/
+#[test]
+fn diamond_has_no_loops() {
+    let func = func(vec![LoadVar(0), JmpIfNot(1), Load(1), Jmp(2), Label(1), Load(2), Label(2), Ret]);
+    let idom = dominators(&func);
+
+    assert_eq!(func.blocks.len(), 4);
+    assert_eq!(idom, [None, Some(Block(0)), Some(Block(0)), Some(Block(0))]);
+    assert!(find_loops(&func).is_empty());
+}
+
+/*
+    The README's loop, a header testing the counter and a body jumping back to it.
+*/
+#[test]
+fn counting_loop() {
+    let func = func(vec![
+        Load(0), Store(0),
+        Label(1), LoadVar(0), Load(10), Gte, JmpIf(2),
+        LoadVar(0), Load(1), Add, Store(0), Jmp(1),
+        Label(2), LoadVar(0), Ret,
+    ]);
+    let loops = find_loops(&func);
+
+    assert_eq!(loops, [Loop { header: Block(1), latches: vec![Block(2)], body: vec![Block(1), Block(2)] }]);
+    assert!(dominates(&dominators(&func), Block(1), Block(3)));
+    assert!(!dominates(&dominators(&func), Block(2), Block(3)));
+}
+
+#[test]
+fn nested_loops_innermost_first() {
+    let func = func(vec![
+        Load(3), Store(0),
+        Label(1),
+        Load(3), Store(1),
+        Label(2),
+        LoadVar(1), Load(1), Sub, Dup, Store(1), JmpIf(2),
+        LoadVar(0), Load(1), Sub, Dup, Store(0), JmpIf(1),
+        Load(7), Ret,
+    ]);
+    let loops = find_loops(&func);
+
+    assert_eq!(loops.len(), 2);
+    assert_eq!(loops[0].header, Block(2));
+    assert_eq!(loops[0].latches, [Block(2)]);
+    assert_eq!(loops[1].header, Block(1));
+    assert!(loops[1].contains(Block(2)) && loops[1].contains(Block(3)));
+}
+
+#[test]
+fn unreachable_blocks_have_no_dominator() {
+    let mut func = func(vec![Load(1), JmpIf(1), Label(2), Jmp(2), Label(1), Load(5), Ret]);
+    crate::ir::fold_constants(&mut func);
+
+    let idom = dominators(&func);
+    let dead = (0..func.blocks.len()).find(|&b| idom[b].is_none() && b != 0).unwrap();
+    assert!(find_loops(&func).iter().all(|l| !l.contains(Block(dead as u32))));
+}
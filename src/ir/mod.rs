@@ -0,0 +1,245 @@
+/*
+    A mid-level SSA representation, between the stack bytecode and machine code.
+
+    Every value is defined exactly once, by an operation in a block or as a
+    parameter of one. The operand stack is gone: `build` simulates it, so
+    operations name their operands directly and whatever is left on the
+    stack at the end of a block is handed to the next one as block arguments.
+    Variables are still loaded and stored, in program order.
+
+    Bool values are 0 or 1 at runtime, so they can be used wherever an I64 can.
+*/
+
+use std::fmt;
+
+mod build;
+mod licm;
+mod loops;
+mod passes;
+
+pub use build::{build, BuildError};
+pub use licm::hoist_invariants;
+pub use loops::{dominates, dominators, find_loops, Loop};
+pub use passes::{fold_constants, remove_dead_values};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Value(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Block(pub u32);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Type {
+    I64,
+    Bool,
+    F64,        /* The bit pattern of an f64, in an integer register like any other value */
+    Unit,       /* Operations only done for their effect, like StoreVar */
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,        /* Logical, 1 for zero and 0 for anything else */
+    Bnot,
+    FNeg, FSqrt,
+    IToF,       /* i64 to the nearest f64 */
+    FToI,       /* f64 to i64 towards zero, i64::MIN if it doesn't fit */
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add, Sub, Mul, Div, Mod, And, Or, Xor, Shl, Shr,
+    FAdd, FSub, FMul, FDiv,
+    UDiv, UMod,
+    LShr,       /* Shifting in zeroes, Shr shifts in the sign bit */
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq, Ne, Lt, Gt, Lte, Gte,
+    FEq, FNe, FLt, FGt, FLte, FGte,
+    ULt, UGt, ULte, UGte,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Param,                          /* A parameter of the block it is in */
+    Const(i64),
+    Unary(UnOp, Value),
+    Binary(BinOp, Value, Value),
+    Cmp(CmpOp, Value, Value),
+    LoadVar(u32),
+    StoreVar(u32, Value),
+}
+
+impl Op {
+    /*
+        The values this operation reads.
+    */
+    pub fn operands(&self) -> Vec<Value> {
+        match *self {
+            Op::Param | Op::Const(_) | Op::LoadVar(_) => vec![],
+            Op::Unary(_, a) | Op::StoreVar(_, a) => vec![a],
+            Op::Binary(_, a, b) | Op::Cmp(_, a, b) => vec![a, b],
+        }
+    }
+
+    /*
+        Whether the operation has to happen even if nothing uses its result.
+        Division can trap, so it counts.
+    */
+    pub fn has_effects(&self) -> bool {
+        matches!(self, Op::StoreVar(..) | Op::Binary(BinOp::Div | BinOp::Mod | BinOp::UDiv | BinOp::UMod, ..))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueData {
+    pub op: Op,
+    pub ty: Type,
+    pub block: Block,
+}
+
+/*
+    A block to go to, and the values for its parameters.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub block: Block,
+    pub args: Vec<Value>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Terminator {
+    Jump(Target),
+    Branch(Value, Target, Target),  /* The first target when the value is non-zero */
+    Return(Value),
+}
+
+impl Terminator {
+    pub fn targets(&self) -> Vec<&Target> {
+        match self {
+            Terminator::Jump(t) => vec![t],
+            Terminator::Branch(_, a, b) => vec![a, b],
+            Terminator::Return(_) => vec![],
+        }
+    }
+
+    /*
+        The values this terminator reads, including block arguments.
+    */
+    pub fn operands(&self) -> Vec<Value> {
+        let mut values = match self {
+            Terminator::Branch(cond, ..) | Terminator::Return(cond) => vec![*cond],
+            Terminator::Jump(_) => vec![],
+        };
+        values.extend(self.targets().iter().flat_map(|t| t.args.iter().copied()));
+        values
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockData {
+    pub params: Vec<Value>,
+    pub insts: Vec<Value>,          /* In execution order */
+    pub term: Terminator,
+}
+
+/*
+    A whole program. Block 0 is the entry, and blocks are in reverse post
+    order, so every value is defined in an earlier block than its uses, or
+    earlier in the same block, unless it comes round a loop as a parameter.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Func {
+    pub blocks: Vec<BlockData>,
+    pub values: Vec<ValueData>,
+}
+
+impl Func {
+    pub fn block(&self, block: Block) -> &BlockData {
+        &self.blocks[block.0 as usize]
+    }
+
+    pub fn value(&self, value: Value) -> &ValueData {
+        &self.values[value.0 as usize]
+    }
+
+    /*
+        Blocks which can branch to `block`.
+    */
+    pub fn preds(&self, block: Block) -> Vec<Block> {
+        (0..self.blocks.len() as u32)
+            .map(Block)
+            .filter(|&b| self.block(b).term.targets().iter().any(|t| t.block == block))
+            .collect()
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "b{}", self.0)
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}({})", self.block, list(&self.args))
+    }
+}
+
+fn list(values: &[Value]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(", ")
+}
+
+/*
+    One line per operation, like
+
+        b1(v3):
+          v4: bool = lt v3, v2
+          branch v4, b2(v3), b3()
+*/
+impl fmt::Display for Func {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (id, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "b{}({}):", id, list(&block.params))?;
+
+            for &v in &block.insts {
+                let data = self.value(v);
+                let op = match data.op {
+                    Op::Param => "param".to_string(),
+                    Op::Const(c) => format!("const {}", c),
+                    Op::Unary(op, a) => format!("{} {}", format!("{:?}", op).to_lowercase(), a),
+                    Op::Binary(op, a, b) => format!("{} {}, {}", format!("{:?}", op).to_lowercase(), a, b),
+                    Op::Cmp(op, a, b) => format!("{} {}, {}", format!("{:?}", op).to_lowercase(), a, b),
+                    Op::LoadVar(id) => format!("load_var {}", id),
+                    Op::StoreVar(id, a) => format!("store_var {}, {}", id, a),
+                };
+
+                match data.ty {
+                    Type::Unit => writeln!(f, "  {}", op)?,
+                    Type::I64 => writeln!(f, "  {}: i64 = {}", v, op)?,
+                    Type::Bool => writeln!(f, "  {}: bool = {}", v, op)?,
+                    Type::F64 => writeln!(f, "  {}: f64 = {}", v, op)?,
+                }
+            }
+
+            match &block.term {
+                Terminator::Jump(t) => writeln!(f, "  jump {}", t)?,
+                Terminator::Branch(cond, a, b) => writeln!(f, "  branch {}, {}, {}", cond, a, b)?,
+                Terminator::Return(v) => writeln!(f, "  return {}", v)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests;
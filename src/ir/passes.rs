@@ -0,0 +1,154 @@
+use super::{BinOp, CmpOp, Func, Op, Terminator, UnOp, Value};
+use crate::interpreter::{eval_binop, eval_unary};
+use crate::program::Instruction;
+
+/*
+    Constant folding.
+
+    Operations whose operands are all constants become constants themselves,
+    and a branch on a constant becomes a jump. Unlike the bytecode version
+    operands don't have to be right next to the operation, anything that
+    `build` could trace back to a `Load` counts, even across blocks.
+    Operations which would trap are left for the runtime to trap on.
+
+    Returns how many operations were folded.
+*/
+pub fn fold_constants(func: &mut Func) -> usize {
+    let mut folded = 0;
+
+    /*
+        Blocks are in reverse post order, so operands not coming in as
+        parameters are folded before the operations using them.
+    */
+    for block in 0..func.blocks.len() {
+        for i in 0..func.blocks[block].insts.len() {
+            let value = func.blocks[block].insts[i];
+            let constant = |v: Value| match func.value(v).op {
+                Op::Const(c) => Some(c),
+                _ => None,
+            };
+
+            let result = match func.value(value).op {
+                Op::Unary(op, a) => constant(a).and_then(|a| eval_unary(&unary(op), a)),
+                Op::Binary(op, a, b) => match (constant(a), constant(b)) {
+                    (Some(a), Some(b)) => eval_binop(&binary(op), a, b).unwrap().ok(),
+                    _ => None,
+                },
+                Op::Cmp(op, a, b) => match (constant(a), constant(b)) {
+                    (Some(a), Some(b)) => eval_binop(&cmp(op), a, b).unwrap().ok(),
+                    _ => None,
+                },
+                _ => None,
+            };
+
+            if let Some(c) = result {
+                func.values[value.0 as usize].op = Op::Const(c);
+                folded += 1;
+            }
+        }
+
+        if let Terminator::Branch(cond, taken, next) = &func.blocks[block].term
+            && let Op::Const(c) = func.value(*cond).op
+        {
+            let target = if c != 0 { taken.clone() } else { next.clone() };
+            func.blocks[block].term = Terminator::Jump(target);
+            folded += 1;
+        }
+    }
+
+    folded
+}
+
+/*
+    Dead value elimination.
+
+    Removes operations whose results are never used and which have no
+    effect of their own. A value is used if a terminator or an operation
+    with effects reads it, or if a used value does. Block parameters stay,
+    since every jump to the block passes them.
+
+    Returns how many operations were removed.
+*/
+pub fn remove_dead_values(func: &mut Func) -> usize {
+    let mut live = vec![false; func.values.len()];
+    let mut work: Vec<Value> = vec![];
+
+    for block in &func.blocks {
+        work.extend(block.term.operands());
+        work.extend(block.insts.iter().copied().filter(|&v| func.value(v).op.has_effects()));
+    }
+
+    while let Some(v) = work.pop() {
+        if !std::mem::replace(&mut live[v.0 as usize], true) {
+            work.extend(func.value(v).op.operands());
+        }
+    }
+
+    let mut removed = 0;
+    for block in &mut func.blocks {
+        let before = block.insts.len();
+        block.insts.retain(|v| live[v.0 as usize]);
+        removed += before - block.insts.len();
+    }
+
+    removed
+}
+
+/*
+    The bytecode instructions the operations came from, to evaluate them
+    exactly like the interpreter.
+*/
+fn unary(op: UnOp) -> Instruction {
+    match op {
+        UnOp::Neg => Instruction::Neg,
+        UnOp::Not => Instruction::Not,
+        UnOp::Bnot => Instruction::Bnot,
+        UnOp::FNeg => Instruction::FNeg,
+        UnOp::FSqrt => Instruction::FSqrt,
+        UnOp::IToF => Instruction::IToF,
+        UnOp::FToI => Instruction::FToI,
+    }
+}
+
+fn binary(op: BinOp) -> Instruction {
+    match op {
+        BinOp::Add => Instruction::Add,
+        BinOp::Sub => Instruction::Sub,
+        BinOp::Mul => Instruction::Mul,
+        BinOp::Div => Instruction::Div,
+        BinOp::Mod => Instruction::Mod,
+        BinOp::And => Instruction::Band,
+        BinOp::Or => Instruction::Bor,
+        BinOp::Xor => Instruction::Bxor,
+        BinOp::Shl => Instruction::Shl,
+        BinOp::Shr => Instruction::Shr,
+        BinOp::FAdd => Instruction::FAdd,
+        BinOp::FSub => Instruction::FSub,
+        BinOp::FMul => Instruction::FMul,
+        BinOp::FDiv => Instruction::FDiv,
+        BinOp::UDiv => Instruction::UDiv,
+        BinOp::UMod => Instruction::UMod,
+        BinOp::LShr => Instruction::LShr,
+    }
+}
+
+fn cmp(op: CmpOp) -> Instruction {
+    match op {
+        CmpOp::Eq => Instruction::Eq,
+        CmpOp::Ne => Instruction::Ne,
+        CmpOp::Lt => Instruction::Lt,
+        CmpOp::Gt => Instruction::Gt,
+        CmpOp::Lte => Instruction::Lte,
+        CmpOp::Gte => Instruction::Gte,
+        CmpOp::FEq => Instruction::FEq,
+        CmpOp::FNe => Instruction::FNe,
+        CmpOp::FLt => Instruction::FLt,
+        CmpOp::FGt => Instruction::FGt,
+        CmpOp::FLte => Instruction::FLte,
+        CmpOp::FGte => Instruction::FGte,
+        CmpOp::ULt => Instruction::ULt,
+        CmpOp::UGt => Instruction::UGt,
+        CmpOp::ULte => Instruction::ULte,
+        CmpOp::UGte => Instruction::UGte,
+    }
+}
@@ -0,0 +1,194 @@
+use super::*;
+use crate::compiler::CompileError;
+use crate::program::Instruction::*;
+use crate::program::{Instruction, Program};
+
+fn ir(insts: Vec<Instruction>) -> Func {
+    build(&Program::new(insts)).unwrap()
+}
+
+fn text(lines: &[&str]) -> String {
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+#[test]
+fn empty() {
+    assert_eq!(ir(vec![]).to_string(), text(&["b0():", "  v0: i64 = const 0", "  return v0"]));
+}
+
+#[test]
+fn straight_line() {
+    let func = ir(vec![Load(2), Load(3), Swap, Sub, Dup, Mul, Ret]);
+
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v0: i64 = const 2",
+        "  v1: i64 = const 3",
+        "  v2: i64 = sub v1, v0",
+        "  v3: i64 = mul v2, v2",
+        "  return v3",
+    ]));
+}
+
+#[test]
+fn floats_are_typed() {
+    let func = ir(vec![LoadF(1.5), Load(2), IToF, FMul, Dup, FLt, Ret]);
+
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v0: f64 = const 4609434218613702656",
+        "  v1: i64 = const 2",
+        "  v2: f64 = itof v1",
+        "  v3: f64 = fmul v0, v2",
+        "  v4: bool = flt v3, v3",
+        "  return v4",
+    ]));
+}
+
+/*
+    What is left on the stack at a jump is passed to the next block.
+*/
+#[test]
+fn stack_becomes_block_arguments() {
+    let func = ir(vec![
+        Load(10), LoadVar(0), JmpIf(1),
+        Load(1), Add, Ret,
+        Label(1), Load(2), Lt, Ret,
+    ]);
+
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v0: i64 = const 10",
+        "  v1: i64 = load_var 0",
+        "  branch v1, b2(v0), b1(v0)",
+        "b1(v2):",
+        "  v3: i64 = const 1",
+        "  v4: i64 = add v2, v3",
+        "  return v4",
+        "b2(v5):",
+        "  v6: i64 = const 2",
+        "  v7: bool = lt v5, v6",
+        "  return v7",
+    ]));
+}
+
+#[test]
+fn loop_carries_its_counter() {
+    let func = ir(vec![
+        Load(0),
+        Label(1), Load(1), Add, Dup, Load(5), Lt, JmpIf(1),
+        Halt,
+    ]);
+
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v0: i64 = const 0",
+        "  jump b1(v0)",
+        "b1(v1):",
+        "  v2: i64 = const 1",
+        "  v3: i64 = add v1, v2",
+        "  v4: i64 = const 5",
+        "  v5: bool = lt v3, v4",
+        "  branch v5, b1(v3), b2(v3)",
+        "b2(v6):",
+        "  return v6",
+    ]));
+}
+
+/*
+    Not taking the branch at the very end runs off the end of the program.
+*/
+#[test]
+fn branch_off_the_end() {
+    let func = ir(vec![Load(7), Label(0), Load(0), JmpIf(0)]);
+
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v0: i64 = const 7",
+        "  jump b1(v0)",
+        "b1(v1):",
+        "  v2: i64 = const 0",
+        "  branch v2, b1(v1), b2(v1)",
+        "b2(v3):",
+        "  return v3",
+    ]));
+}
+
+#[test]
+fn stores_stay_in_order() {
+    let func = ir(vec![Load(1), Store(0), LoadVar(0), Not, Write]);
+
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v0: i64 = const 1",
+        "  store_var 0, v0",
+        "  v2: i64 = load_var 0",
+        "  v3: bool = not v2",
+        "  return v3",
+    ]));
+}
+
+#[test]
+fn unreachable_blocks_are_left_out() {
+    let func = ir(vec![Load(1), Ret, Load(2), Ret]);
+    assert_eq!(func.blocks.len(), 1);
+}
+
+#[test]
+fn invalid_programs() {
+    let build = |insts| build(&Program::new(insts));
+
+    assert_eq!(build(vec![Jmp(3)]), Err(BuildError::Invalid(CompileError::UndefinedLabel(3))));
+    assert_eq!(build(vec![Load(1), Add]), Err(BuildError::StackUnderflow(1)));
+    assert_eq!(build(vec![Ret]), Err(BuildError::StackUnderflow(0)));
+    assert_eq!(build(vec![Load(1), Over]), Err(BuildError::StackUnderflow(1)));
+    assert_eq!(build(vec![Load(1), Load(2), Roll(2)]), Err(BuildError::StackUnderflow(2)));
+
+    /*
+        One more value on the stack each time round
+    */
+    assert_eq!(build(vec![Label(1), Load(1), Jmp(1)]), Err(BuildError::UnbalancedStack(0)));
+}
+
+#[test]
+fn folds_constants() {
+    let mut func = ir(vec![
+        Load(6), Jmp(1),
+        Label(1), Load(7), Mul, Dup, Load(42), Eq, JmpIfNot(2),
+        Ret,
+        Label(2), Load(0), Div, Ret,
+    ]);
+
+    /*
+        v0 reaches b1 as a parameter, so only the comparison's
+        operands are constant from b1's point of view
+    */
+    assert_eq!(fold_constants(&mut func), 0);
+
+    let mut func = ir(vec![Load(6), Load(7), Mul, Dup, Load(42), Eq, JmpIfNot(2), Ret, Label(2), Load(0), Div, Ret]);
+    assert_eq!(fold_constants(&mut func), 3);           /* mul, eq and the branch */
+    assert_eq!(remove_dead_values(&mut func), 4);       /* 6, 7, eq and the 42 it compared with */
+    assert_eq!(func.value(Value(2)).op, Op::Const(42));
+    assert!(matches!(func.blocks[0].term, Terminator::Jump(Target { block: Block(1), .. })));
+}
+
+#[test]
+fn trapping_operations_are_not_folded() {
+    let mut func = ir(vec![Load(1), Load(0), Div, Pop, Load(i64::MIN), Load(-1), Mod, Ret]);
+
+    assert_eq!(fold_constants(&mut func), 0);
+    assert_eq!(remove_dead_values(&mut func), 0);       /* the division still has to trap */
+}
+
+#[test]
+fn dead_values_are_removed() {
+    let mut func = ir(vec![LoadVar(0), LoadVar(1), Add, Pop, LoadVar(2), Neg, Ret]);
+
+    assert_eq!(remove_dead_values(&mut func), 3);
+    assert_eq!(func.to_string(), text(&[
+        "b0():",
+        "  v3: i64 = load_var 2",
+        "  v4: i64 = neg v3",
+        "  return v4",
+    ]));
+}
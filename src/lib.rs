@@ -0,0 +1,33 @@
+/*
+    cjit, a proof of concept JIT compiler.
+
+    Build a `Program` out of `Instruction`s, turn it into machine code with a
+    `Compiler` and run that code with an `Invoker`. Or go through a `Backend`,
+    which picks between the compiler and the portable `Interpreter` at runtime,
+    or a `TieredProgram`, which interprets a program until it gets hot. A
+    `CodeCache` keeps compiled programs loaded, to skip compiling them again.
+    `cfg` has the control flow graph of a program, `opt` the passes over one
+    and `ir` the SSA form the compiler can optimise and lower from.
+*/
+
+mod assembler;
+mod backend;
+mod cache;
+mod compiler;
+mod interpreter;
+mod invoker;
+mod program;
+mod tiered;
+
+pub mod cfg;
+pub mod ir;
+pub mod opt;
+
+pub use assembler::PeepholeStats;
+pub use backend::{native, select, Artifact, Backend, BackendKind, NativeCode, RunError};
+pub use cache::{CacheError, CacheStats, CodeCache};
+pub use compiler::{CodegenMode, CompileError, CompileOptions, Compiler, OptLevel, SourceMap, EXIT_LABEL};
+pub use interpreter::{Bytecode, Interpreter, Trap};
+pub use invoker::{Function, InvokeError, Invoker};
+pub use program::{Instruction, Program};
+pub use tiered::{Tier, TierOptions, TieredProgram};
@@ -0,0 +1,117 @@
+use std::collections::HashSet;
+
+use crate::cfg::{BlockId, Cfg};
+use crate::program::{Instruction, Program};
+
+/*
+    Dead code elimination.
+
+    Removes, in this order
+        blocks which cannot be reached from the start of the program, or
+            from a subroutine some reachable `Call` runs,
+        labels which nothing jumps to or calls any more,
+        stores to variables which are never loaded, which become a `Pop`,
+        pushes which are popped straight back off, `Load(1), Pop`.
+
+    Returns the new program and how many instructions were removed. Programs
+    the backends would reject come back as they are, so the backend still
+    gets to report what is wrong with them.
+*/
+pub fn eliminate_dead_code(program: &Program) -> (Program, usize) {
+    let Ok(cfg) = Cfg::build(program) else {
+        return (program.clone(), 0);
+    };
+
+    /*
+        Subroutines usually sit after the `Halt`, which the Cfg stops at like
+        the backends do, so look at the whole program with each `Halt` as a
+        `Ret` instead. If what comes after the `Halt` doesn't hold together,
+        stick to the part before it.
+    */
+    let whole: Vec<Instruction> = program
+        .insts()
+        .iter()
+        .map(|inst| match inst {
+            Instruction::Halt => Instruction::Ret,
+            _ => *inst,
+        })
+        .collect();
+    let cfg = Cfg::build(&Program::new(whole)).unwrap_or(cfg);
+
+    let mut reachable = reachable(&cfg);
+    reachable.sort();
+    let mut insts: Vec<Instruction> =
+        reachable.iter().flat_map(|&id| program.insts()[cfg.block(id).range.clone()].iter().copied()).collect();
+
+    let targets: HashSet<u32> = insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Instruction::Jmp(label) | Instruction::JmpIf(label) | Instruction::JmpIfNot(label) | Instruction::Call(label) => {
+                Some(*label)
+            }
+            _ => None,
+        })
+        .collect();
+    insts.retain(|inst| !matches!(inst, Instruction::Label(label) if !targets.contains(label)));
+
+    let loaded: HashSet<u32> = insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Instruction::LoadVar(id) => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    let mut out: Vec<Instruction> = Vec::with_capacity(insts.len());
+    for inst in insts {
+        let inst = match inst {
+            Instruction::Store(id) if !loaded.contains(&id) => Instruction::Pop,
+            _ => inst,
+        };
+
+        match (out.last(), inst) {
+            (Some(Instruction::Load(_) | Instruction::LoadF(_) | Instruction::LoadVar(_)), Instruction::Pop) => {
+                out.pop();
+            }
+            _ => out.push(inst),
+        }
+    }
+
+    let removed = program.insts().len() - out.len();
+    (Program::new(out), removed)
+}
+
+/*
+    Every block reachable from the entry, following jumps and falling through
+    as well as into the subroutine of every `Call`, since a subroutine sits
+    after a `Ret` and nothing ever jumps or falls into it.
+*/
+fn reachable(cfg: &Cfg) -> Vec<BlockId> {
+    let mut seen = vec![false; cfg.blocks().len()];
+    let mut work = vec![];
+    if !seen.is_empty() {
+        seen[0] = true;
+        work.push(0);
+    }
+
+    let mut out = vec![];
+    while let Some(id) = work.pop() {
+        out.push(id);
+
+        let calls = cfg.insts(id).iter().filter_map(|inst| match inst {
+            Instruction::Call(label) => cfg.label(*label),
+            _ => None,
+        });
+        for next in cfg.block(id).succs.iter().copied().chain(calls) {
+            if !seen[next] {
+                seen[next] = true;
+                work.push(next);
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests;
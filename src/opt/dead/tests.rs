@@ -0,0 +1,100 @@
+use super::*;
+use crate::program::Instruction::*;
+
+fn dce(insts: Vec<Instruction>) -> (Vec<Instruction>, usize) {
+    let (program, removed) = eliminate_dead_code(&Program::new(insts));
+    (program.insts().to_vec(), removed)
+}
+
+#[test]
+fn nothing_to_remove() {
+    let insts = vec![Load(0), Store(0), Label(1), LoadVar(0), JmpIf(1), Ret];
+    assert_eq!(dce(insts.clone()), (insts, 0));
+}
+
+#[test]
+fn code_after_ret_and_jmp() {
+    assert_eq!(dce(vec![Load(1), Ret, Load(2), Add, Ret]), (vec![Load(1), Ret], 3));
+    assert_eq!(
+        dce(vec![Jmp(1), Load(2), Label(2), Ret, Label(1), Load(3), Ret]),
+        (vec![Jmp(1), Label(1), Load(3), Ret], 3)
+    );
+}
+
+#[test]
+fn code_after_halt() {
+    assert_eq!(dce(vec![Load(1), Halt, Load(2), Ret]), (vec![Load(1), Halt], 2));
+}
+
+/*
+    The code after the jump is unreachable, but a later jump comes back into it.
+*/
+#[test]
+fn labelled_code_is_kept_when_jumped_to() {
+    let insts = vec![Jmp(1), Label(2), Load(1), Ret, Label(1), Jmp(2)];
+    assert_eq!(dce(insts.clone()), (insts, 0));
+}
+
+#[test]
+fn unused_labels() {
+    assert_eq!(dce(vec![Label(1), Load(1), Label(2), Ret]), (vec![Load(1), Ret], 2));
+}
+
+/*
+    A jump from unreachable code does not keep its label alive.
+*/
+#[test]
+fn labels_only_jumped_to_from_dead_code() {
+    assert_eq!(dce(vec![Load(1), Ret, Jmp(1), Label(1), Ret]), (vec![Load(1), Ret], 3));
+}
+
+#[test]
+fn dead_stores() {
+    assert_eq!(dce(vec![Load(1), Store(0), Load(2), Ret]), (vec![Load(2), Ret], 2));
+    assert_eq!(dce(vec![LoadVar(1), Store(0), Load(2), Ret]), (vec![Load(2), Ret], 2));
+
+    /* The value still has to come off the stack */
+    let (insts, removed) = dce(vec![Load(1), Load(2), Add, Store(0), Load(3), Ret]);
+    assert_eq!(insts, [Load(1), Load(2), Add, Pop, Load(3), Ret]);
+    assert_eq!(removed, 0);
+}
+
+#[test]
+fn live_stores() {
+    let insts = vec![Load(1), Store(0), LoadVar(0), Ret];
+    assert_eq!(dce(insts.clone()), (insts, 0));
+}
+
+#[test]
+fn invalid_programs_are_left_alone() {
+    let insts = vec![Ret, Load(1), Jmp(9)];
+    assert_eq!(dce(insts.clone()), (insts, 0));
+}
+
+/*
+    Nothing jumps or falls into a subroutine, but the calls keep it and its label.
+*/
+#[test]
+fn called_subroutines_are_kept() {
+    let insts = vec![Load(3), Call(10), Halt, Label(10), Dup, Mul, Ret];
+    assert_eq!(dce(insts.clone()), (insts, 0));
+
+    let insts = vec![Load(3), Call(10), Ret, Label(10), Dup, Mul, Ret];
+    assert_eq!(dce(insts.clone()), (insts, 0));
+
+    /* Unless the call is dead itself */
+    assert_eq!(dce(vec![Load(3), Ret, Call(10), Halt, Label(10), Dup, Ret]), (vec![Load(3), Ret], 5));
+}
+
+#[test]
+fn dead_code_then_inlining() {
+    let (program, removed) = eliminate_dead_code(&Program::new(vec![
+        Load(3), Call(10), Halt, Load(4), Ret,
+        Label(10), Dup, Mul, Ret,
+    ]));
+    assert_eq!(removed, 2);
+
+    let (program, inlined) = crate::opt::inline_calls(&program, &crate::opt::InlineOptions::default());
+    assert_eq!(inlined, 1);
+    assert_eq!(crate::Interpreter::new().prepare(&program).unwrap().run(), Ok(9));
+}
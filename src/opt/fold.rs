@@ -0,0 +1,90 @@
+use crate::interpreter::{eval_binop, eval_unary};
+use crate::program::{Instruction, Program};
+
+/*
+    Constant folding.
+
+    Operations whose operands are all pushed by the `Load`s right before
+    them are replaced by a single `Load` of the result, so
+    `Load(10), Load(5), Add, Load(3), Mul` becomes `Load(45)`. Only
+    neighbouring instructions are looked at, and a `Label` is never folded
+    away, so nothing can jump into the middle of a folded sequence.
+
+    Results wrap exactly as they do at runtime, and operations which would
+    trap (division by zero, i64::MIN / -1) are left alone for the runtime
+    to trap on. A `LoadF` is a `Load` of its bits, so folded floats come
+    out as `Load`s too.
+*/
+pub fn fold_constants(program: &Program) -> Program {
+    let mut out: Vec<Instruction> = Vec::with_capacity(program.insts().len());
+
+    for inst in program.insts() {
+        let n = out.len();
+
+        /*
+            The values pushed by the last one and two instructions, if they are `Load`s.
+        */
+        let top = out.last().and_then(constant);
+        let top2 = match (n >= 2).then(|| &out[n - 2..]) {
+            Some([a, b]) => constant(a).zip(constant(b)),
+            _ => None,
+        };
+
+        match (inst, top, top2) {
+            (Instruction::Neg | Instruction::Not | Instruction::Bnot |
+             Instruction::FNeg | Instruction::FSqrt | Instruction::IToF | Instruction::FToI, Some(a), _) => {
+                out[n - 1] = Instruction::Load(eval_unary(inst, a).unwrap());
+            }
+
+            (Instruction::Dup, Some(a), _) => out.push(Instruction::Load(a)),
+
+            (Instruction::Pop, Some(_), _) => {
+                out.pop();
+            }
+
+            (Instruction::Swap, _, Some((a, b))) => {
+                out[n - 2] = Instruction::Load(b);
+                out[n - 1] = Instruction::Load(a);
+            }
+
+            (Instruction::Over, _, Some((a, _))) => out.push(Instruction::Load(a)),
+
+            (Instruction::Nip, _, Some((_, b))) => {
+                out.pop();
+                out[n - 2] = Instruction::Load(b);
+            }
+
+            (Instruction::Tuck, _, Some((a, b))) => {
+                out[n - 2] = Instruction::Load(b);
+                out[n - 1] = Instruction::Load(a);
+                out.push(Instruction::Load(b));
+            }
+
+            (_, _, Some((a, b))) => match eval_binop(inst, a, b) {
+                Some(Ok(v)) => {
+                    out.truncate(n - 2);
+                    out.push(Instruction::Load(v));
+                }
+                Some(Err(_)) | None => out.push(*inst),  /* Would trap, or not a binop */
+            },
+
+            _ => out.push(*inst),
+        }
+    }
+
+    Program::new(out)
+}
+
+/*
+    The value a `Load` or `LoadF` pushes, floats as their bits.
+*/
+fn constant(inst: &Instruction) -> Option<i64> {
+    match *inst {
+        Instruction::Load(v) => Some(v),
+        Instruction::LoadF(v) => Some(v.to_bits() as i64),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests;
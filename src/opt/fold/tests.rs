@@ -0,0 +1,78 @@
+use super::*;
+use crate::program::Instruction::*;
+
+fn fold(insts: Vec<Instruction>) -> Vec<Instruction> {
+    fold_constants(&Program::new(insts)).insts().to_vec()
+}
+
+#[test]
+fn arithmetic() {
+    assert_eq!(fold(vec![Load(10), Load(5), Add, Load(3), Mul, Load(2), Sub, Ret]), [Load(43), Ret]);
+    assert_eq!(fold(vec![Load(7), Load(2), Div, Ret]), [Load(3), Ret]);
+    assert_eq!(fold(vec![Load(-7), Load(2), Mod, Ret]), [Load(-1), Ret]);
+}
+
+#[test]
+fn wraps_like_the_runtime() {
+    assert_eq!(fold(vec![Load(i64::MAX), Load(1), Add]), [Load(i64::MIN)]);
+    assert_eq!(fold(vec![Load(i64::MIN), Neg]), [Load(i64::MIN)]);
+    assert_eq!(fold(vec![Load(1), Load(65), Shl]), [Load(2)]);
+    assert_eq!(fold(vec![Load(-16), Load(2), Shr]), [Load(-4)]);
+}
+
+#[test]
+fn comparisons_and_bitwise() {
+    assert_eq!(fold(vec![Load(1), Load(5), Lte]), [Load(1)]);
+    assert_eq!(fold(vec![Load(1), Load(5), Gt]), [Load(0)]);
+    assert_eq!(fold(vec![Load(5), Load(2), Shl, Load(3), Load(7), Band, Bor]), [Load(23)]);
+    assert_eq!(fold(vec![Load(0), Not, Load(0), Bnot, Bxor]), [Load(-2)]);
+    assert_eq!(fold(vec![Load(-1), Load(1), ULt]), [Load(0)]);
+    assert_eq!(fold(vec![Load(-16), Load(60), LShr]), [Load(15)]);
+    assert_eq!(fold(vec![Load(-1), Load(2), UDiv, Load(-1), Load(10), UMod]), [Load(i64::MAX), Load(5)]);
+}
+
+#[test]
+fn floats() {
+    let bits = |f: f64| Load(f.to_bits() as i64);
+
+    assert_eq!(fold(vec![LoadF(1.5), LoadF(2.0), FMul, Ret]), [bits(3.0), Ret]);
+    assert_eq!(fold(vec![LoadF(0.0), LoadF(0.0), FDiv]), [Load(0xFFF8_0000_0000_0000_u64 as i64)]);
+    assert_eq!(fold(vec![Load(3), IToF, FSqrt, FNeg, FToI]), [Load(-1)]);
+    assert_eq!(fold(vec![LoadF(f64::NAN), Dup, FEq]), [Load(0)]);
+    assert_eq!(fold(vec![LoadF(1.0), LoadVar(0), FAdd]), [LoadF(1.0), LoadVar(0), FAdd]);
+}
+
+#[test]
+fn stack_shuffles() {
+    assert_eq!(fold(vec![Load(21), Dup, Add]), [Load(42)]);
+    assert_eq!(fold(vec![Load(10), Load(3), Swap, Sub]), [Load(-7)]);
+    assert_eq!(fold(vec![Load(1), Load(2), Pop]), [Load(1)]);
+    assert_eq!(fold(vec![Load(1), Load(2), Over, Sub, Mul]), [Load(1)]);
+    assert_eq!(fold(vec![Load(1), Load(2), Nip]), [Load(2)]);
+    assert_eq!(fold(vec![Load(1), Load(2), Tuck]), [Load(2), Load(1), Load(2)]);
+    assert_eq!(fold(vec![Load(1), Load(2), Load(3), Rot]), [Load(1), Load(2), Load(3), Rot]);
+}
+
+#[test]
+fn traps_are_left_alone() {
+    assert_eq!(fold(vec![Load(1), Load(0), Div, Ret]), [Load(1), Load(0), Div, Ret]);
+    assert_eq!(fold(vec![Load(1), Load(0), Mod, Ret]), [Load(1), Load(0), Mod, Ret]);
+    assert_eq!(fold(vec![Load(i64::MIN), Load(-1), Div]), [Load(i64::MIN), Load(-1), Div]);
+    assert_eq!(fold(vec![Load(1), Load(0), UMod]), [Load(1), Load(0), UMod]);
+}
+
+#[test]
+fn partially_constant() {
+    assert_eq!(fold(vec![LoadVar(0), Load(2), Load(3), Mul, Add]), [LoadVar(0), Load(6), Add]);
+    assert_eq!(fold(vec![LoadVar(0), Load(2), Add]), [LoadVar(0), Load(2), Add]);
+    assert_eq!(fold(vec![LoadVar(0), Dup, Mul]), [LoadVar(0), Dup, Mul]);
+}
+
+#[test]
+fn labels_are_barriers() {
+    let insts = vec![Load(1), Label(1), Load(2), Add, Ret];
+    assert_eq!(fold(insts.clone()), insts);
+
+    let insts = vec![Load(1), Load(2), Label(1), Add, Ret];
+    assert_eq!(fold(insts.clone()), insts);
+}
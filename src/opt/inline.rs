@@ -0,0 +1,192 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::EXIT_LABEL;
+use crate::program::{Instruction, Program};
+
+/*
+    How much the inliner is allowed to do.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InlineOptions {
+    pub max_callee_size: usize,     /* Instructions in a subroutine, not counting labels and its Ret */
+    pub budget: usize,              /* Instructions the whole program may grow by, again not counting labels */
+}
+
+impl Default for InlineOptions {
+    fn default() -> Self {
+        Self { max_callee_size: 16, budget: 256 }
+    }
+}
+
+/*
+    Inlining of subroutines.
+
+    `Call(label)` runs the subroutine starting at `Label(label)` up to its
+    `Ret`, on the same operand stack, and then carries on after the call.
+    Each call to a small enough subroutine is replaced by a copy of its
+    body, with fresh labels so the copies don't clash with each other or
+    with the caller, and fresh variables, as every call gets variables of
+    its own. The backends don't implement calls yet and skip over them, so
+    until they do this is what gives calls their meaning.
+
+    A subroutine is only inlined if it is self contained: its body ends at
+    the first `Ret` after its label, only jumps to labels inside itself and
+    doesn't call anything, so recursion is left alone. Once all the calls in
+    a subroutine have been inlined it is self contained too, so this goes
+    round again until nothing changes or the budget runs out.
+
+    Returns the new program and how many calls were inlined.
+*/
+pub fn inline_calls(program: &Program, options: &InlineOptions) -> (Program, usize) {
+    let mut insts = program.insts().to_vec();
+    let mut budget = options.budget;
+    let mut inlined = 0;
+
+    let mut next_label = insts
+        .iter()
+        .filter_map(|inst| match *inst {
+            Instruction::Label(id) if id != EXIT_LABEL => Some(id + 1),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+    let mut next_var = insts
+        .iter()
+        .filter_map(|inst| match *inst {
+            Instruction::Store(id) | Instruction::LoadVar(id) => Some(id.saturating_add(1)),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(0);
+
+    loop {
+        let bodies: HashMap<u32, Vec<Instruction>> = subroutines(&insts)
+            .into_iter()
+            .filter(|(_, body)| size(body) <= options.max_callee_size)
+            .collect();
+
+        let mut out = Vec::with_capacity(insts.len());
+        let mut changed = false;
+
+        for inst in &insts {
+            let body = match inst {
+                Instruction::Call(label) => bodies.get(label).filter(|body| size(body) <= budget + 1),
+                _ => None,
+            };
+
+            let Some(body) = body else {
+                out.push(*inst);
+                continue;
+            };
+
+            /*
+                Leave the call alone if there aren't enough ids left for the
+                copy, label ids stopping short of the exit label.
+            */
+            let count = |f: fn(&Instruction) -> bool| body.iter().filter(|inst| f(inst)).count() as u32;
+            let labels = count(|inst| matches!(inst, Instruction::Label(_)));
+            let vars = count(|inst| matches!(inst, Instruction::Store(_) | Instruction::LoadVar(_)));
+            if next_label.checked_add(labels).is_none() || next_var.checked_add(vars).is_none() {
+                out.push(*inst);
+                continue;
+            }
+
+            let mut labels = HashMap::new();
+            let mut vars = HashMap::new();
+            for inst in body {
+                out.push(match *inst {
+                    Instruction::Label(id) => Instruction::Label(fresh(&mut labels, &mut next_label, id)),
+                    Instruction::Jmp(id) => Instruction::Jmp(fresh(&mut labels, &mut next_label, id)),
+                    Instruction::JmpIf(id) => Instruction::JmpIf(fresh(&mut labels, &mut next_label, id)),
+                    Instruction::JmpIfNot(id) => Instruction::JmpIfNot(fresh(&mut labels, &mut next_label, id)),
+                    Instruction::Store(id) => Instruction::Store(fresh(&mut vars, &mut next_var, id)),
+                    Instruction::LoadVar(id) => Instruction::LoadVar(fresh(&mut vars, &mut next_var, id)),
+                    inst => inst,
+                });
+            }
+
+            /*
+                The copy takes the call's place, so the program grows by one less than the body.
+            */
+            budget -= size(body).saturating_sub(1);
+            inlined += 1;
+            changed = true;
+        }
+
+        insts = out;
+        if !changed {
+            break;
+        }
+    }
+
+    (Program::new(insts), inlined)
+}
+
+/*
+    The bodies of the self contained subroutines: each label which is
+    called, through to the instruction before the first `Ret` after it,
+    label included so the body can jump back to its start.
+*/
+fn subroutines(insts: &[Instruction]) -> HashMap<u32, Vec<Instruction>> {
+    let called: HashSet<u32> = insts
+        .iter()
+        .filter_map(|inst| match *inst {
+            Instruction::Call(label) => Some(label),
+            _ => None,
+        })
+        .collect();
+
+    let mut bodies = HashMap::new();
+    for (start, inst) in insts.iter().enumerate() {
+        let Instruction::Label(label) = *inst else {
+            continue;
+        };
+        if !called.contains(&label) {
+            continue;
+        }
+
+        let Some(len) = insts[start..].iter().position(|inst| matches!(inst, Instruction::Ret)) else {
+            continue;
+        };
+        let body = &insts[start..start + len];
+
+        let defined: HashSet<u32> = body
+            .iter()
+            .filter_map(|inst| match *inst {
+                Instruction::Label(id) => Some(id),
+                _ => None,
+            })
+            .collect();
+        let contained = body.iter().all(|inst| match *inst {
+            Instruction::Jmp(id) | Instruction::JmpIf(id) | Instruction::JmpIfNot(id) => defined.contains(&id),
+            Instruction::Call(_) | Instruction::Halt => false,
+            _ => true,
+        });
+
+        if contained {
+            bodies.insert(label, body.to_vec());
+        }
+    }
+
+    bodies
+}
+
+/*
+    The id standing for `id` in this copy, taking the next unused one the first time.
+*/
+fn fresh(map: &mut HashMap<u32, u32>, next: &mut u32, id: u32) -> u32 {
+    *map.entry(id).or_insert_with(|| {
+        *next += 1;
+        *next - 1
+    })
+}
+
+/*
+    What a body costs, labels being free.
+*/
+fn size(body: &[Instruction]) -> usize {
+    body.iter().filter(|inst| !matches!(inst, Instruction::Label(_))).count()
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,107 @@
+use super::*;
+use crate::program::Instruction::*;
+
+fn inline(insts: Vec<Instruction>) -> (Vec<Instruction>, usize) {
+    let (program, inlined) = inline_calls(&Program::new(insts), &InlineOptions::default());
+    (program.insts().to_vec(), inlined)
+}
+
+#[test]
+fn nothing_to_inline() {
+    let insts = vec![Load(1), Label(1), Ret];
+    assert_eq!(inline(insts.clone()), (insts, 0));
+}
+
+/*
+    square(x) = x * x, called twice. The subroutine itself stays behind the Halt.
+*/
+#[test]
+fn inlines_every_call() {
+    let (insts, inlined) = inline(vec![
+        Load(3), Call(10), Call(10), Halt,
+        Label(10), Dup, Mul, Ret,
+    ]);
+
+    assert_eq!(inlined, 2);
+    assert_eq!(insts, [
+        Load(3), Label(11), Dup, Mul, Label(12), Dup, Mul, Halt,
+        Label(10), Dup, Mul, Ret,
+    ]);
+}
+
+/*
+    Every copy gets labels and variables of its own.
+*/
+#[test]
+fn renames_labels_and_variables() {
+    let (insts, _) = inline(vec![
+        Load(5), Store(0),
+        Call(1), Call(1), Halt,
+        Label(1), Store(0), Label(2), LoadVar(0), Load(1), Sub, Dup, Store(0), JmpIf(2), LoadVar(0), Ret,
+    ]);
+
+    assert_eq!(insts[..30], [
+        Load(5), Store(0),
+        Label(3), Store(1), Label(4), LoadVar(1), Load(1), Sub, Dup, Store(1), JmpIf(4), LoadVar(1),
+        Label(5), Store(2), Label(6), LoadVar(2), Load(1), Sub, Dup, Store(2), JmpIf(6), LoadVar(2),
+        Halt,
+        Label(1), Store(0), Label(2), LoadVar(0), Load(1), Sub, Dup,
+    ]);
+}
+
+#[test]
+fn large_subroutines_stay_calls() {
+    let mut insts = vec![Call(1), Halt, Label(1)];
+    insts.extend([Load(1), Pop].repeat(9));
+    insts.push(Ret);
+
+    assert_eq!(inline(insts.clone()), (insts.clone(), 0));
+
+    let options = InlineOptions { max_callee_size: 18, ..InlineOptions::default() };
+    assert_eq!(inline_calls(&Program::new(insts), &options).1, 1);
+}
+
+/*
+    Each copy grows the program by one, its label being free.
+*/
+#[test]
+fn budget_runs_out() {
+    let options = InlineOptions { budget: 2, ..InlineOptions::default() };
+    let program = Program::new(vec![Call(1), Call(1), Call(1), Halt, Label(1), Load(1), Load(2), Ret]);
+    let (program, inlined) = inline_calls(&program, &options);
+
+    assert_eq!(inlined, 2);
+    assert_eq!(program.insts()[6], Call(1));
+}
+
+/*
+    Recursion, a jump out of the subroutine and a subroutine without a Ret.
+*/
+#[test]
+fn only_self_contained_subroutines() {
+    for insts in [
+        vec![Call(1), Halt, Label(1), Call(1), Ret],
+        vec![Call(1), Label(2), Halt, Label(1), Jmp(2), Ret],
+        vec![Call(1), Halt, Label(1), Load(1)],
+    ] {
+        assert_eq!(inline(insts.clone()), (insts, 0));
+    }
+}
+
+/*
+    Once double is inlined into quadruple, quadruple can be inlined too.
+*/
+#[test]
+fn nested_calls() {
+    let (insts, inlined) = inline(vec![
+        Load(3), Call(1), Halt,
+        Label(1), Call(2), Call(2), Ret,
+        Label(2), Dup, Add, Ret,
+    ]);
+
+    assert_eq!(inlined, 3);
+    assert!(insts[..insts.iter().position(|i| *i == Halt).unwrap()].iter().all(|i| !matches!(i, Call(_))));
+
+    let code = crate::Compiler::new().compile(&Program::new(insts)).unwrap();
+    assert_eq!(unsafe { crate::Invoker::new().execute(&code) }, Ok(12));
+}
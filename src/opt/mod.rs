@@ -0,0 +1,12 @@
+/*
+    Optimisation passes over `Program`s. Each pass takes a program and
+    returns an equivalent one, so they can run before any backend.
+*/
+
+mod dead;
+mod fold;
+mod inline;
+
+pub use dead::eliminate_dead_code;
+pub use fold::fold_constants;
+pub use inline::{inline_calls, InlineOptions};
@@ -0,0 +1,92 @@
+/*
+    Updated Instruction set
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    Load(i64),      /* Load an immediate value onto the stack :D */
+    Dup,            /* Duplicate stack top value */
+    Pop,            /* Pop stack top value */
+    Swap,           /* Swap stack two top value */
+    Add,            /* Self explanatory, stack based, pop values, add them, push result */
+    Sub,            /* Self explanatory, stack based, pop values, sub them, push result */
+    Mul,            /* Self explanatory, stack based, pop values, mul them, push result */
+    Div,            /* Self explanatory, stack based, pop values, div them, push result */
+    Mod,            /* Self explanatory, stack based, pop values, mod them, push result */
+    Neg,            /* Negate the stack top value */
+    Eq,             /* Comparison, == */
+    Ne,             /* Comparison, != */
+    Lt,             /* Comparison, < */
+    Gt,             /* Comparison, > */
+    Lte,            /* Comparison, <= */
+    Gte,            /* Comparison, >= */
+    And,            /* Logical, && */
+    Or,             /* Logical, || */
+    Not,            /* Logical, ! or NOT */
+    Band,           /* Bit, and */
+    Bor,            /* Bit, or */
+    Bxor,           /* Bit, xor */
+    Bnot,           /* Bit, not */
+    Shl,            /* Bit, shift left */
+    Shr,            /* Bit, shift right */
+    Store(u32),     /* Variable, store top of stack to a labelled local variable */
+    LoadVar(u32),   /* Variable, load labelled local variable value to stack */
+    Jmp(u32),       /* Conditional, an unconditional jump to a label */
+    JmpIf(u32),     /* Conditional, a conditional jump to a label, condition is true if top stack value is not zero */
+    JmpIfNot(u32),  /* Conditional, a conditional jump to a label, condition is true if top stack value is zero */
+    Label(u32),     /* Control Flow?, define a label for jumps, funcstions etc */
+    Call(u32),      /* Control Flow, call a function via label */
+    Write,          /* Write the top of the stack value to the console */
+    WriteChar,      /* Write the top of the stack value as a char */
+    Read,           /* read an int from stdin */
+    Ret,            /* Return from function */
+    Halt,           /* Stop execution */
+    LoadF(f64),     /* Float, load an immediate f64, floats live on the stack as their bit patterns */
+    FAdd,           /* Float, pop two floats, add them, push result */
+    FSub,           /* Float, pop two floats, sub them, push result */
+    FMul,           /* Float, pop two floats, mul them, push result */
+    FDiv,           /* Float, pop two floats, div them, push result, never traps */
+    FNeg,           /* Float, negate the stack top float */
+    FSqrt,          /* Float, square root of the stack top float */
+    FEq,            /* Float comparison, ==, false for NaN */
+    FNe,            /* Float comparison, !=, true for NaN */
+    FLt,            /* Float comparison, < */
+    FGt,            /* Float comparison, > */
+    FLte,           /* Float comparison, <= */
+    FGte,           /* Float comparison, >= */
+    IToF,           /* Float, convert the stack top integer to a float */
+    FToI,           /* Float, convert the stack top float to an integer, towards zero, i64::MIN if it doesn't fit */
+    UDiv,           /* Unsigned, pop values, div them as u64s, push result */
+    UMod,           /* Unsigned, pop values, mod them as u64s, push result */
+    LShr,           /* Bit, logical shift right, shifting in zeroes */
+    ULt,            /* Unsigned comparison, < */
+    UGt,            /* Unsigned comparison, > */
+    ULte,           /* Unsigned comparison, <= */
+    UGte,           /* Unsigned comparison, >= */
+    Over,           /* Push a copy of the second value, a b -> a b a */
+    Rot,            /* Move the third value to the top, a b c -> b c a */
+    Nip,            /* Drop the second value, a b -> b */
+    Tuck,           /* Copy the top value below the second, a b -> b a b */
+    Pick(u32),      /* Push a copy of the value n below the top, Pick(0) is Dup */
+    Roll(u32),      /* Move the value n below the top to the top, Roll(1) is Swap and Roll(2) is Rot */
+}
+
+/*
+    A structure to represent a program in the form of bytecode instructions.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    insts: Vec<Instruction>,
+}
+
+impl Program {
+    pub fn new(insts: Vec<Instruction>) -> Self {
+        Self { insts }
+    }
+
+    /*
+        The instructions making up the program, in order.
+    */
+    pub fn insts(&self) -> &[Instruction] {
+        &self.insts
+    }
+}
@@ -0,0 +1,128 @@
+use crate::backend::{BackendKind, RunError};
+use crate::compiler::{CompileError, CompileOptions, Compiler};
+use crate::interpreter::{Bytecode, Interpreter};
+use crate::invoker::{Function, Invoker};
+use crate::program::Program;
+
+/*
+    When a program is hot enough to compile, and how.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TierOptions {
+    pub call_threshold: u64,        /* Runs after which a program is compiled */
+    pub back_edge_threshold: u64,   /* Loop iterations, over all runs, after which a program is compiled */
+    pub compile: CompileOptions,    /* Options the compiler is given for hot programs */
+}
+
+impl Default for TierOptions {
+    fn default() -> Self {
+        Self {
+            call_threshold: 8,
+            back_edge_threshold: 10_000,
+            compile: CompileOptions::default(),
+        }
+    }
+}
+
+/*
+    Where a program runs.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tier {
+    Interpreted,    /* Not hot yet, or can't be compiled */
+    Compiled,       /* Machine code, loaded once and called from then on */
+}
+
+/*
+    A program which starts out interpreted and is compiled once it gets hot.
+
+    Every run is counted, and so is every jump back to an earlier label the
+    interpreter takes. Once either count crosses its threshold the next run
+    compiles the program, loads it into executable memory and keeps calling
+    that. Programs can't be switched over in the middle of a run, so a long
+    first run is interpreted all the way through.
+
+    Programs have no input, so one which trapped will trap every time. Those
+    stay in the interpreter, where a trap is an error instead of a signal
+    taking the process down. So do programs the host can't compile for, or
+    the compiler turns down, and programs which don't pass verification, as
+    their code could underflow the machine stack.
+*/
+pub struct TieredProgram {
+    program: Program,
+    bytecode: Bytecode,
+    options: TierOptions,
+    calls: u64,                     /* Runs so far, in either tier */
+    back_edges: u64,                /* Backward jumps taken by the interpreter */
+    pinned: bool,                   /* Whether the program stays interpreted, for trapping or failing to verify or compile */
+    compiled: Option<Function>,
+}
+
+impl TieredProgram {
+    /*
+        Check the program like the compiler would, without compiling it yet.
+        It's always verified, and one which fails is an error if the compile
+        options ask for verification and interpreted for good otherwise.
+    */
+    pub fn new(program: Program, options: TierOptions) -> Result<Self, CompileError> {
+        let bytecode = Interpreter::new().prepare(&program)?;
+
+        let verified = crate::compiler::verify(&program);
+        if options.compile.verify {
+            verified.clone()?;
+        }
+
+        Ok(Self {
+            program,
+            bytecode,
+            options,
+            calls: 0,
+            back_edges: 0,
+            pinned: verified.is_err(),
+            compiled: None,
+        })
+    }
+
+    pub fn run(&mut self) -> Result<i64, RunError> {
+        if self.compiled.is_none() && self.is_hot() {
+            match Compiler::with_options(self.options.compile).compile(&self.program) {
+                Ok(code) => self.compiled = Some(unsafe { Invoker::new().load(&code) }?),     /* verified by `new`, or pinned */
+                Err(_) => self.pinned = true,
+            }
+        }
+
+        self.calls += 1;
+
+        if let Some(function) = &self.compiled {
+            return Ok(function.call());
+        }
+
+        let result = self.bytecode.run_counting(&mut self.back_edges);
+        self.pinned |= result.is_err();
+        Ok(result?)
+    }
+
+    pub fn tier(&self) -> Tier {
+        match self.compiled {
+            Some(_) => Tier::Compiled,
+            None => Tier::Interpreted,
+        }
+    }
+
+    pub fn calls(&self) -> u64 {
+        self.calls
+    }
+
+    pub fn back_edges(&self) -> u64 {
+        self.back_edges
+    }
+
+    fn is_hot(&self) -> bool {
+        !self.pinned
+            && BackendKind::X86_64.is_supported()
+            && (self.calls >= self.options.call_threshold || self.back_edges >= self.options.back_edge_threshold)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,157 @@
+use super::*;
+use crate::compiler::OptLevel;
+use crate::interpreter::Trap;
+use crate::program::Instruction::*;
+
+/*
+    Count i from 0 to n, returning n.
+*/
+fn counter(n: i64) -> Program {
+    Program::new(vec![
+        Load(0), Store(0),
+        Label(1),
+        LoadVar(0), Load(1), Add, Store(0),
+        LoadVar(0), Load(n), Lt, JmpIf(1),
+        LoadVar(0), Ret,
+    ])
+}
+
+fn tiered(program: Program, call_threshold: u64, back_edge_threshold: u64) -> TieredProgram {
+    let options = TierOptions { call_threshold, back_edge_threshold, ..TierOptions::default() };
+    TieredProgram::new(program, options).unwrap()
+}
+
+#[test]
+fn compiled_after_enough_calls() {
+    let mut program = tiered(Program::new(vec![Load(2), Load(3), Mul, Ret]), 3, u64::MAX);
+
+    for _ in 0..3 {
+        assert_eq!(program.run(), Ok(6));
+        assert_eq!(program.tier(), Tier::Interpreted);
+    }
+
+    assert_eq!(program.run(), Ok(6));
+    assert_eq!(program.tier(), Tier::Compiled);
+    assert_eq!(program.run(), Ok(6));
+    assert_eq!(program.calls(), 5);
+}
+
+#[test]
+fn loops_count_back_edges() {
+    let mut program = tiered(counter(100), u64::MAX, 150);
+
+    assert_eq!(program.run(), Ok(100));
+    assert_eq!(program.back_edges(), 99);
+    assert_eq!(program.tier(), Tier::Interpreted);
+
+    assert_eq!(program.run(), Ok(100));
+    assert_eq!(program.back_edges(), 198);
+
+    assert_eq!(program.run(), Ok(100));
+    assert_eq!(program.tier(), Tier::Compiled);
+    assert_eq!(program.back_edges(), 198);      /* compiled code isn't counted */
+}
+
+#[test]
+fn forward_jumps_are_not_back_edges() {
+    let mut program = tiered(Program::new(vec![Load(1), JmpIf(1), Load(5), Ret, Label(1), Load(7), Ret]), u64::MAX, 1);
+
+    assert_eq!(program.run(), Ok(7));
+    assert_eq!(program.back_edges(), 0);
+    assert_eq!(program.run(), Ok(7));
+    assert_eq!(program.tier(), Tier::Interpreted);
+}
+
+/*
+    Programs whose result once depended on the tier: an empty stack at the
+    end, and code after a Ret only reached by jumping.
+*/
+#[test]
+fn compiling_does_not_change_the_result() {
+    let programs = [
+        (vec![Load(1), Pop], 0),
+        (vec![Load(3), Load(1), JmpIf(0), Load(5), Ret, Label(0)], 3),
+        (vec![Load(7), Label(0), Load(0), JmpIf(0)], 7),
+    ];
+
+    for level in [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3] {
+        for (insts, expected) in &programs {
+            let compile = CompileOptions { opt_level: level, ..CompileOptions::default() };
+            let options = TierOptions { call_threshold: 2, compile, ..TierOptions::default() };
+            let mut program = TieredProgram::new(Program::new(insts.clone()), options).unwrap();
+
+            for _ in 0..4 {
+                assert_eq!(program.run(), Ok(*expected), "{:?} at {:?}", insts, level);
+            }
+            assert_eq!(program.tier(), Tier::Compiled);
+        }
+    }
+}
+
+#[test]
+fn trapping_programs_stay_interpreted() {
+    let mut program = tiered(Program::new(vec![Load(1), Load(0), Div, Ret]), 1, 1);
+
+    for _ in 0..3 {
+        assert_eq!(program.run(), Err(RunError::Trap(Trap::DivideByZero)));
+        assert_eq!(program.tier(), Tier::Interpreted);
+    }
+}
+
+/*
+    Compiled without verification, this would pop the return address and return into the loads.
+*/
+#[test]
+fn unverified_programs_stay_interpreted() {
+    let mut insts = vec![Pop; 300];
+    insts.extend([Load(0x4141); 300]);
+    insts.push(Ret);
+    let mut program = tiered(Program::new(insts), 0, 0);
+
+    for _ in 0..3 {
+        assert_eq!(program.run(), Err(RunError::Trap(Trap::StackUnderflow)));
+        assert_eq!(program.tier(), Tier::Interpreted);
+    }
+
+    let mut program = tiered(Program::new(vec![Load(1), Load(1), JmpIf(1), Load(2), Label(1), Ret]), 0, 0);
+    assert_eq!(program.run(), Ok(1));
+    assert_eq!(program.tier(), Tier::Interpreted);
+}
+
+/*
+    A variable read before it's stored to is 0 in every tier.
+*/
+#[test]
+fn uninitialised_variables_in_every_tier() {
+    let mut program = tiered(Program::new(vec![LoadVar(4), Load(38), Add]), 1, u64::MAX);
+
+    assert_eq!(program.run(), Ok(38));
+    assert_eq!(program.run(), Ok(38));
+    assert_eq!(program.tier(), Tier::Compiled);
+}
+
+#[test]
+fn hot_programs_use_the_compile_options() {
+    let compile = CompileOptions { opt_level: OptLevel::O3, ..CompileOptions::default() };
+    let options = TierOptions { call_threshold: 1, compile, ..TierOptions::default() };
+    let mut program = TieredProgram::new(counter(1000), options).unwrap();
+
+    assert_eq!(program.run(), Ok(1000));
+    assert_eq!(program.run(), Ok(1000));
+    assert_eq!(program.tier(), Tier::Compiled);
+}
+
+#[test]
+fn invalid_programs_are_rejected_up_front() {
+    assert!(matches!(
+        TieredProgram::new(Program::new(vec![Jmp(3)]), TierOptions::default()),
+        Err(CompileError::UndefinedLabel(3))
+    ));
+
+    let compile = CompileOptions { verify: true, ..CompileOptions::default() };
+    let options = TierOptions { compile, ..TierOptions::default() };
+    assert!(matches!(
+        TieredProgram::new(Program::new(vec![Add, Ret]), options),
+        Err(CompileError::StackUnderflow(0))
+    ));
+}
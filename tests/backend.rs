@@ -0,0 +1,114 @@
+/*
+    Tests for choosing and running backends.
+*/
+
+use cjit::Instruction::*;
+use cjit::{native, select, Artifact, Backend, BackendKind, CompileError, Compiler, Interpreter, Program, RunError, Trap};
+
+fn backends() -> Vec<Box<dyn Backend>> {
+    vec![Box::new(Compiler::new()), Box::new(Interpreter::new())]
+}
+
+#[test]
+fn parse_kind() {
+    assert_eq!(BackendKind::parse("x86_64"), Some(BackendKind::X86_64));
+    assert_eq!(BackendKind::parse("native"), Some(BackendKind::X86_64));
+    assert_eq!(BackendKind::parse("interp"), Some(BackendKind::Interpreter));
+    assert_eq!(BackendKind::parse("arm64"), None);
+}
+
+#[test]
+fn select_by_kind() {
+    assert_eq!(select(BackendKind::Interpreter).name(), "interpreter");
+
+    if cfg!(target_arch = "x86_64") {
+        assert_eq!(select(BackendKind::X86_64).name(), "x86_64");
+        assert_eq!(native().name(), "x86_64");
+    } else {
+        assert_eq!(native().name(), "interpreter");
+    }
+}
+
+#[test]
+fn backends_agree() {
+    let program = Program::new(vec![
+        Load(0), Store(0),
+        Label(1),
+        LoadVar(0), Load(10), Gte, JmpIf(2),
+        LoadVar(0), Load(1), Add, Store(0),
+        Jmp(1),
+        Label(2),
+        LoadVar(0), Ret,
+    ]);
+
+    for mut backend in backends() {
+        assert_eq!(backend.lower(&program).unwrap().run(), Ok(10), "{}", backend.name());
+    }
+}
+
+#[test]
+fn backends_reject_the_same_programs() {
+    let cases = [
+        (vec![Jmp(1), Ret], CompileError::UndefinedLabel(1)),
+        (vec![Label(1), Label(1)], CompileError::DuplicateLabel(1)),
+        (vec![Label(u32::MAX)], CompileError::ReservedLabel(u32::MAX)),
+    ];
+
+    for (insts, err) in cases {
+        for mut backend in backends() {
+            assert_eq!(backend.lower(&Program::new(insts.clone())).unwrap_err(), err, "{}", backend.name());
+        }
+    }
+}
+
+/*
+    Unverified, this would pop the return address and return into the loads.
+*/
+#[test]
+fn native_code_is_always_verified() {
+    let mut insts = vec![Pop; 300];
+    insts.extend([Load(0x4141); 300]);
+    insts.push(Ret);
+
+    let err = Compiler::new().lower(&Program::new(insts)).unwrap_err();
+    assert_eq!(err, CompileError::StackUnderflow(0));
+}
+
+#[test]
+fn running_off_the_end_returns_the_top_of_the_stack() {
+    for mut backend in backends() {
+        let result = backend.lower(&Program::new(vec![Load(1), Load(5)])).unwrap().run();
+        assert_eq!(result, Ok(5), "{}", backend.name());
+    }
+}
+
+#[test]
+fn halt_ends_the_program() {
+    for mut backend in backends() {
+        let result = backend.lower(&Program::new(vec![Load(5), Halt, Jmp(9), Load(1), Ret])).unwrap().run();
+        assert_eq!(result, Ok(5), "{}", backend.name());
+    }
+}
+
+#[test]
+fn interpreter_traps() {
+    let run = |insts| Interpreter::new().lower(&Program::new(insts)).unwrap().run();
+
+    assert_eq!(run(vec![Load(1), Load(0), Div, Ret]), Err(RunError::Trap(Trap::DivideByZero)));
+    assert_eq!(run(vec![Load(1), Load(0), Mod, Ret]), Err(RunError::Trap(Trap::DivideByZero)));
+    assert_eq!(run(vec![Load(i64::MIN), Load(-1), Div, Ret]), Err(RunError::Trap(Trap::Overflow)));
+    assert_eq!(run(vec![Load(i64::MIN), Load(-1), Mod, Ret]), Err(RunError::Trap(Trap::Overflow)));
+    assert_eq!(run(vec![Load(1), Add, Ret]), Err(RunError::Trap(Trap::StackUnderflow)));
+    assert_eq!(run(vec![Load(1), Load(2), Rot, Ret]), Err(RunError::Trap(Trap::StackUnderflow)));
+    assert_eq!(run(vec![Load(1), Pick(1), Ret]), Err(RunError::Trap(Trap::StackUnderflow)));
+    assert_eq!(run(vec![Load(1), Roll(u32::MAX), Ret]), Err(RunError::Trap(Trap::StackUnderflow)));
+}
+
+#[test]
+fn native_artifacts_hold_the_compiled_code() {
+    let program = Program::new(vec![Load(2), Load(3), Mul, Ret]);
+    let Artifact::Native(code) = Compiler::new().lower(&program).unwrap() else {
+        panic!("the compiler made bytecode");
+    };
+    assert_eq!(code.bytes(), Compiler::new().compile(&program).unwrap());
+}
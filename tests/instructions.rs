@@ -0,0 +1,880 @@
+/*
+    End to end tests, every instruction compiled and executed,
+    and interpreted to check both backends agree.
+*/
+
+use cjit::Instruction::*;
+use cjit::{CodegenMode, CompileOptions, Compiler, Instruction, Interpreter, Invoker, OptLevel, Program};
+
+const MODES: [CodegenMode; 3] = [CodegenMode::Stack, CodegenMode::Cached, CodegenMode::Ssa];
+const LEVELS: [OptLevel; 4] = [OptLevel::O0, OptLevel::O1, OptLevel::O2, OptLevel::O3];
+
+/*
+    Every code generation mode at every optimisation level.
+*/
+fn options() -> impl Iterator<Item = CompileOptions> {
+    LEVELS.into_iter().flat_map(|opt_level| {
+        MODES.into_iter().map(move |mode| CompileOptions { opt_level, mode: Some(mode), ..CompileOptions::default() })
+    })
+}
+
+/*
+    Compile and execute a program with every set of options, returning what it returned.
+*/
+fn run(insts: Vec<Instruction>) -> i64 {
+    let program = Program::new(insts);
+    let interpreted = Interpreter::new().prepare(&program).unwrap().run().unwrap();
+
+    for options in options() {
+        let code = Compiler::with_options(options).compile(&program).unwrap();
+        let native = unsafe { Invoker::new().execute(&code) }.unwrap();
+        assert_eq!(native, interpreted, "{:?} disagrees with the interpreter on {:?}", options, program);
+    }
+
+    interpreted
+}
+
+/*
+    Run a binary operation on two immediates.
+*/
+fn binop(a: i64, b: i64, op: Instruction) -> i64 {
+    run(vec![Load(a), Load(b), op, Ret])
+}
+
+fn unary(a: i64, op: Instruction) -> i64 {
+    run(vec![Load(a), op, Ret])
+}
+
+/*
+    A trapping program takes the whole test process down with it,
+    so run it in a child and check how the child died.
+*/
+#[cfg(unix)]
+fn traps(insts: Vec<Instruction>) -> bool {
+    killed_by(insts, libc::SIGFPE)
+}
+
+#[cfg(unix)]
+fn killed_by(insts: Vec<Instruction>, signal: i32) -> bool {
+    let program = Program::new(insts);
+    let trapped: Vec<bool> = options()
+        .map(|options| trapped(Compiler::with_options(options).compile(&program).unwrap(), signal))
+        .collect();
+    assert!(trapped.iter().all(|&t| t == trapped[0]), "options disagree on {:?}", program);
+    trapped[0]
+}
+
+#[cfg(unix)]
+fn trapped(code: Vec<u8>, signal: i32) -> bool {
+    unsafe {
+        let pid = libc::fork();
+        assert!(pid >= 0, "fork failed");
+
+        if pid == 0 {
+            let _ = Invoker::new().execute(&code);
+            libc::_exit(0);
+        }
+
+        let mut status = 0;
+        libc::waitpid(pid, &mut status, 0);
+        libc::WIFSIGNALED(status) && libc::WTERMSIG(status) == signal
+    }
+}
+
+#[test]
+fn load() {
+    assert_eq!(run(vec![Load(0), Ret]), 0);
+    assert_eq!(run(vec![Load(1), Ret]), 1);
+    assert_eq!(run(vec![Load(-1), Ret]), -1);
+    assert_eq!(run(vec![Load(i64::MIN), Ret]), i64::MIN);
+    assert_eq!(run(vec![Load(i64::MAX), Ret]), i64::MAX);
+    assert_eq!(run(vec![Load(0x1234_5678_9ABC_DEF0), Ret]), 0x1234_5678_9ABC_DEF0);
+}
+
+#[test]
+fn dup() {
+    assert_eq!(run(vec![Load(21), Dup, Add, Ret]), 42);
+    assert_eq!(run(vec![Load(i64::MIN), Dup, Sub, Ret]), 0);
+}
+
+#[test]
+fn pop() {
+    assert_eq!(run(vec![Load(1), Load(2), Pop, Ret]), 1);
+}
+
+#[test]
+fn swap() {
+    assert_eq!(run(vec![Load(1), Load(2), Swap, Ret]), 1);
+    assert_eq!(run(vec![Load(1), Load(2), Swap, Pop, Ret]), 2);
+    assert_eq!(run(vec![Load(10), Load(3), Swap, Sub, Ret]), -7);
+}
+
+#[test]
+fn over() {
+    assert_eq!(run(vec![Load(1), Load(2), Over, Ret]), 1);
+    assert_eq!(run(vec![Load(10), Load(3), Over, Sub, Sub, Ret]), 17);
+}
+
+#[test]
+fn rot() {
+    assert_eq!(run(vec![Load(1), Load(2), Load(3), Rot, Ret]), 1);
+    assert_eq!(run(vec![Load(1), Load(2), Load(3), Rot, Pop, Sub, Ret]), -1);
+}
+
+#[test]
+fn nip() {
+    assert_eq!(run(vec![Load(1), Load(2), Nip, Ret]), 2);
+    assert_eq!(run(vec![Load(7), Load(1), Load(2), Nip, Sub, Ret]), 5);
+}
+
+#[test]
+fn tuck() {
+    assert_eq!(run(vec![Load(1), Load(2), Tuck, Ret]), 2);
+    assert_eq!(run(vec![Load(1), Load(2), Tuck, Pop, Ret]), 1);
+    assert_eq!(run(vec![Load(1), Load(2), Tuck, Pop, Pop, Ret]), 2);
+}
+
+#[test]
+fn pick_and_roll() {
+    let five = || vec![Load(1), Load(2), Load(3), Load(4), Load(5)];
+
+    for n in 0..5 {
+        let mut insts = five();
+        insts.extend([Pick(n), Ret]);
+        assert_eq!(run(insts), 5 - n as i64, "Pick({})", n);
+
+        /* Whatever was rolled up, the rest keeps its order: 5 - 4 + 3 - 2 + 1 without it */
+        let mut insts = five();
+        insts.extend([Roll(n), Store(0), Sub, Sub, Sub, LoadVar(0), Ret]);
+        assert_eq!(run(insts), 5 - n as i64, "Roll({})", n);
+    }
+
+    assert_eq!(run(vec![Load(1), Load(2), Load(3), Roll(1), Sub, Sub, Ret]), 0);
+    assert_eq!(run(vec![Load(1), Load(2), Load(3), Roll(0), Ret]), 3);
+}
+
+/*
+    Deep enough for the moves of a Roll to be a loop.
+*/
+#[test]
+fn deep_roll() {
+    for n in [5, 9, 15] {
+        let mut insts: Vec<Instruction> = (1..=16).map(Load).collect();
+        insts.extend([Roll(n), Store(0)]);
+        insts.extend((0..14).flat_map(|_| [Load(3), Mul, Add]));
+        insts.extend([LoadVar(0), Load(1000), Mul, Add, Ret]);
+        run(insts);
+    }
+
+    let mut insts: Vec<Instruction> = (1..=10).map(Load).collect();
+    insts.extend([Label(1), Roll(9), Ret]);
+    assert_eq!(run(insts), 1);
+}
+
+/*
+    Reaching under the operand stack would read or overwrite the frame, the
+    saved registers and the return address, so it traps instead. The
+    interpreter traps on these too.
+*/
+#[cfg(unix)]
+#[test]
+fn shuffles_past_the_stack_trap() {
+    assert!(killed_by(vec![Load(1), Pick(1), Ret], libc::SIGILL));
+    assert!(killed_by(vec![Load(1), Load(2), Roll(2), Ret], libc::SIGILL));
+    assert!(killed_by(vec![Load(1), Roll(12), Ret], libc::SIGILL));
+    assert!(killed_by(vec![Load(1), Roll(u32::MAX), Ret], libc::SIGILL));
+    assert!(killed_by(vec![Pick(1 << 30), Ret], libc::SIGILL));
+    assert!(killed_by(vec![Load(1), Nip, Ret], libc::SIGILL));
+    assert!(!killed_by(vec![Load(1), Load(2), Roll(1), Ret], libc::SIGILL));
+}
+
+/*
+    After a label the cache is empty, so every slot is read and written
+    in memory.
+*/
+#[test]
+fn shuffles_across_labels() {
+    let after_label = |shuffle: Vec<Instruction>| {
+        let mut insts = vec![Load(1), Load(2), Load(3), Load(4), Label(1)];
+        insts.extend(shuffle);
+        insts.extend([Load(10), Mul, Add, Load(10), Mul, Add, Load(10), Mul, Add, Ret]);
+        run(insts)
+    };
+
+    assert_eq!(after_label(vec![]), 4321);
+    assert_eq!(after_label(vec![Rot]), 2431);
+    assert_eq!(after_label(vec![Roll(3)]), 1432);
+    assert_eq!(after_label(vec![Nip, Load(9)]), 9421);
+    assert_eq!(after_label(vec![Pick(3), Nip]), 1321);
+    assert_eq!(after_label(vec![Tuck, Nip]), 4421);
+    assert_eq!(after_label(vec![Over, Nip]), 3321);
+}
+
+#[test]
+fn add() {
+    assert_eq!(binop(100, 200, Add), 300);
+    assert_eq!(binop(-5, 3, Add), -2);
+    assert_eq!(binop(i64::MAX, 1, Add), i64::MIN);
+    assert_eq!(binop(i64::MIN, -1, Add), i64::MAX);
+}
+
+#[test]
+fn sub() {
+    assert_eq!(binop(10, 3, Sub), 7);
+    assert_eq!(binop(3, 10, Sub), -7);
+    assert_eq!(binop(i64::MIN, 1, Sub), i64::MAX);
+}
+
+#[test]
+fn mul() {
+    assert_eq!(binop(6, 7, Mul), 42);
+    assert_eq!(binop(-6, 7, Mul), -42);
+    assert_eq!(binop(-6, -7, Mul), 42);
+    assert_eq!(binop(i64::MAX, 2, Mul), -2);
+    assert_eq!(binop(i64::MIN, -1, Mul), i64::MIN);
+    assert_eq!(binop(0x1_0000_0000, 0x1_0000_0000, Mul), 0);
+}
+
+#[test]
+fn div() {
+    assert_eq!(binop(42, 6, Div), 7);
+    assert_eq!(binop(7, 2, Div), 3);
+    assert_eq!(binop(-7, 2, Div), -3);     /* Truncates towards zero */
+    assert_eq!(binop(7, -2, Div), -3);
+    assert_eq!(binop(i64::MIN, 1, Div), i64::MIN);
+    assert_eq!(binop(i64::MIN, 2, Div), i64::MIN / 2);
+}
+
+#[test]
+fn modulo() {
+    assert_eq!(binop(7, 3, Mod), 1);
+    assert_eq!(binop(-7, 3, Mod), -1);     /* Sign follows the dividend */
+    assert_eq!(binop(7, -3, Mod), 1);
+    assert_eq!(binop(i64::MIN, 3, Mod), i64::MIN % 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn division_traps() {
+    assert!(traps(vec![Load(1), Load(0), Div, Ret]));
+    assert!(traps(vec![Load(1), Load(0), Mod, Ret]));
+    assert!(traps(vec![Load(i64::MIN), Load(-1), Div, Ret]));
+    assert!(traps(vec![Load(i64::MIN), Load(-1), Mod, Ret]));
+    assert!(!traps(vec![Load(1), Load(1), Div, Ret]));
+
+    /* The divisor is not a constant here, so these go through idiv */
+    assert!(traps(vec![Load(0), Store(0), Load(1), LoadVar(0), Div, Ret]));
+    assert!(traps(vec![Load(-1), Store(0), Load(i64::MIN), LoadVar(0), Mod, Ret]));
+}
+
+#[test]
+fn neg() {
+    assert_eq!(unary(5, Neg), -5);
+    assert_eq!(unary(-5, Neg), 5);
+    assert_eq!(unary(0, Neg), 0);
+    assert_eq!(unary(i64::MIN, Neg), i64::MIN);
+}
+
+#[test]
+fn comparisons() {
+    let cases = [(1, 5), (5, 1), (3, 3), (-1, 1), (i64::MIN, i64::MAX), (i64::MAX, i64::MIN)];
+
+    for (a, b) in cases {
+        assert_eq!(binop(a, b, Eq), (a == b) as i64, "{} == {}", a, b);
+        assert_eq!(binop(a, b, Ne), (a != b) as i64, "{} != {}", a, b);
+        assert_eq!(binop(a, b, Lt), (a < b) as i64, "{} < {}", a, b);
+        assert_eq!(binop(a, b, Gt), (a > b) as i64, "{} > {}", a, b);
+        assert_eq!(binop(a, b, Lte), (a <= b) as i64, "{} <= {}", a, b);
+        assert_eq!(binop(a, b, Gte), (a >= b) as i64, "{} >= {}", a, b);
+    }
+}
+
+#[test]
+fn logical() {
+    for a in [0, 1] {
+        for b in [0, 1] {
+            assert_eq!(binop(a, b, And), a & b);
+            assert_eq!(binop(a, b, Or), a | b);
+        }
+    }
+
+    assert_eq!(unary(0, Not), 1);
+    assert_eq!(unary(1, Not), 0);
+    assert_eq!(unary(-1, Not), 0);
+    assert_eq!(unary(i64::MIN, Not), 0);
+}
+
+#[test]
+fn bitwise() {
+    assert_eq!(binop(0b1100, 0b1010, Band), 0b1000);
+    assert_eq!(binop(0b1100, 0b1010, Bor), 0b1110);
+    assert_eq!(binop(0b1100, 0b1010, Bxor), 0b0110);
+    assert_eq!(binop(-1, i64::MIN, Band), i64::MIN);
+    assert_eq!(unary(0, Bnot), -1);
+    assert_eq!(unary(i64::MAX, Bnot), i64::MIN);
+}
+
+#[test]
+fn shifts() {
+    assert_eq!(binop(5, 2, Shl), 20);
+    assert_eq!(binop(1, 63, Shl), i64::MIN);
+    assert_eq!(binop(1, 64, Shl), 1);      /* The count is masked to 6 bits */
+    assert_eq!(binop(1, -1, Shl), i64::MIN);
+    assert_eq!(binop(20, 2, Shr), 5);
+    assert_eq!(binop(-16, 2, Shr), -4);     /* Arithmetic, keeps the sign */
+    assert_eq!(binop(i64::MIN, 63, Shr), -1);
+    assert_eq!(binop(i64::MAX, -1, Shr), 0);
+    assert_eq!(binop(256, 65, Shr), 128);
+}
+
+#[test]
+fn unsigned_division() {
+    assert_eq!(binop(42, 6, UDiv), 7);
+    assert_eq!(binop(-1, 2, UDiv), i64::MAX);
+    assert_eq!(binop(-7, 2, UMod), 1);
+    assert_eq!(binop(i64::MIN, -1, UDiv), 0);  /* No overflow, -1 is u64::MAX */
+    assert_eq!(binop(i64::MIN, -1, UMod), i64::MIN);
+    assert_eq!(binop(7, i64::MIN, UMod), 7);
+}
+
+#[cfg(unix)]
+#[test]
+fn unsigned_division_traps() {
+    assert!(traps(vec![Load(1), Load(0), UDiv, Ret]));
+    assert!(traps(vec![Load(1), Load(0), UMod, Ret]));
+    assert!(traps(vec![Load(0), Store(0), Load(-1), LoadVar(0), UMod, Ret]));
+    assert!(!traps(vec![Load(-1), Store(0), Load(i64::MIN), LoadVar(0), UDiv, Ret]));
+}
+
+#[test]
+fn logical_shift() {
+    assert_eq!(binop(20, 2, LShr), 5);
+    assert_eq!(binop(-16, 2, LShr), (-16i64 as u64 >> 2) as i64);  /* Zeroes shifted in */
+    assert_eq!(binop(i64::MIN, 63, LShr), 1);
+    assert_eq!(binop(-1, 64, LShr), -1);    /* The count is masked to 6 bits */
+    assert_eq!(binop(-1, -1, LShr), 1);
+}
+
+#[test]
+fn unsigned_comparisons() {
+    let cases = [(1, 5), (5, 1), (3, 3), (-1, 1), (i64::MIN, i64::MAX), (i64::MAX, i64::MIN), (0, -1)];
+
+    for (a, b) in cases {
+        let (x, y) = (a as u64, b as u64);
+        assert_eq!(binop(a, b, ULt), (x < y) as i64, "{} <u {}", a, b);
+        assert_eq!(binop(a, b, UGt), (x > y) as i64, "{} >u {}", a, b);
+        assert_eq!(binop(a, b, ULte), (x <= y) as i64, "{} <=u {}", a, b);
+        assert_eq!(binop(a, b, UGte), (x >= y) as i64, "{} >=u {}", a, b);
+    }
+}
+
+/*
+    xorshift64, which needs the shifts to the right to be logical.
+*/
+#[test]
+fn xorshift() {
+    let mut expected = 88172645463325252u64;
+    for _ in 0..10 {
+        expected ^= expected << 13;
+        expected ^= expected >> 7;
+        expected ^= expected << 17;
+    }
+
+    let step = |shift, op| vec![LoadVar(0), LoadVar(0), Load(shift), op, Bxor, Store(0)];
+    let mut insts = vec![Load(88172645463325252), Store(0), Load(10), Store(1), Label(1)];
+    insts.extend(step(13, Shl));
+    insts.extend(step(7, LShr));
+    insts.extend(step(17, Shl));
+    insts.extend([LoadVar(1), Load(1), Sub, Dup, Store(1), JmpIf(1), LoadVar(0), Ret]);
+
+    assert_eq!(run(insts), expected as i64);
+}
+
+#[test]
+fn variables() {
+    assert_eq!(run(vec![Load(25), Store(0), Load(17), Store(1), LoadVar(0), LoadVar(1), Add, Ret]), 42);
+    assert_eq!(run(vec![Load(1), Store(0), Load(2), Store(0), LoadVar(0), Ret]), 2);
+    assert_eq!(run(vec![Load(i64::MIN), Store(127), LoadVar(127), Ret]), i64::MIN);
+}
+
+/*
+    More variables than registers, so some stay in the frame.
+*/
+#[test]
+fn variables_in_registers_and_memory() {
+    let mut insts = vec![];
+    for id in 0..8 {
+        insts.extend([Load(id as i64 + 1), Store(id)]);
+    }
+    insts.extend([Load(3), Store(9), Label(1)]);
+    for id in 0..8 {
+        insts.extend([LoadVar(id), LoadVar((id + 1) % 8), Add, Store(id)]);
+    }
+    insts.extend([LoadVar(9), Load(1), Sub, Dup, Store(9), JmpIf(1)]);
+    insts.push(LoadVar(0));
+    for id in 1..8 {
+        insts.extend([LoadVar(id), Add]);
+    }
+    insts.push(Ret);
+
+    assert_eq!(run(insts), 318);
+}
+
+#[test]
+fn variables_are_independent_of_the_operand_stack() {
+    assert_eq!(run(vec![Load(1), Load(2), Store(0), Load(3), Store(1), LoadVar(0), Add, Ret]), 3);
+}
+
+#[test]
+fn forward_jump() {
+    assert_eq!(run(vec![Load(1), Jmp(1), Load(999), Ret, Label(1), Ret]), 1);
+}
+
+#[test]
+fn conditional_jumps() {
+    let program = |cond, jump| run(vec![Load(cond), jump, Load(1), Ret, Label(1), Load(2), Ret]);
+
+    assert_eq!(program(1, JmpIf(1)), 2);
+    assert_eq!(program(0, JmpIf(1)), 1);
+    assert_eq!(program(-1, JmpIf(1)), 2);
+    assert_eq!(program(0, JmpIfNot(1)), 2);
+    assert_eq!(program(1, JmpIfNot(1)), 1);
+    assert_eq!(program(i64::MIN, JmpIfNot(1)), 1);
+}
+
+/*
+    Example 8 from the README.
+*/
+#[test]
+fn backward_jump_loop() {
+    assert_eq!(run(vec![
+        Load(0), Store(0),
+        Label(1),
+        LoadVar(0), Load(10), Gte, JmpIf(2),
+        LoadVar(0), Load(1), Add, Store(0),
+        Jmp(1),
+        Label(2),
+        LoadVar(0), Ret,
+    ]), 10);
+}
+
+/*
+    sum = 0; for i in 0..5 { for j in 0..7 { sum += i * j } }
+*/
+#[test]
+fn nested_loops() {
+    assert_eq!(run(vec![
+        Load(0), Store(0),                                      /* sum */
+        Load(0), Store(1),                                      /* i */
+        Label(1),
+        LoadVar(1), Load(5), Lt, JmpIfNot(4),
+        Load(0), Store(2),                                      /* j */
+        Label(2),
+        LoadVar(2), Load(7), Lt, JmpIfNot(3),
+        LoadVar(0), LoadVar(1), LoadVar(2), Mul, Add, Store(0),
+        LoadVar(2), Load(1), Add, Store(2),
+        Jmp(2),
+        Label(3),
+        LoadVar(1), Load(1), Add, Store(1),
+        Jmp(1),
+        Label(4),
+        LoadVar(0), Ret,
+    ]), 210);
+}
+
+/*
+    The conditional jump is the last instruction, so not taking it runs off the end.
+*/
+#[test]
+fn conditional_jump_at_the_end() {
+    assert_eq!(run(vec![Load(7), Label(0), Load(0), JmpIf(0)]), 7);
+    assert_eq!(run(vec![Load(7), Label(0), Load(1), JmpIfNot(0)]), 7);
+}
+
+#[test]
+fn empty_stack_at_the_end() {
+    assert_eq!(run(vec![Load(1), Pop]), 0);
+    assert_eq!(run(vec![]), 0);
+    assert_eq!(run(vec![Load(1), Store(0)]), 0);
+}
+
+/*
+    The code after the Ret is only reached by the jump, with 3 still on the stack.
+*/
+#[test]
+fn jump_past_a_ret() {
+    assert_eq!(run(vec![Load(3), Load(1), JmpIf(0), Load(5), Ret, Label(0)]), 3);
+    assert_eq!(run(vec![Load(3), Load(1), Load(1), Eq, JmpIf(0), Load(5), Ret, Label(0), Load(1), Add]), 4);
+    assert_eq!(run(vec![Load(3), Jmp(0), Load(5), Ret, Label(0)]), 3);
+}
+
+/*
+    Dead code between a jump and its label doesn't change how deep the stack is there.
+*/
+#[test]
+fn dead_code_before_a_label() {
+    assert_eq!(run(vec![Jmp(1), Load(5), Label(1)]), 0);
+    assert_eq!(run(vec![Load(3), Jmp(1), Load(5), Load(6), Label(1)]), 3);
+    assert_eq!(run(vec![Load(3), Load(1), JmpIf(1), Load(4), Ret, Pop, Pop, Label(1), Load(2), Add]), 5);
+}
+
+/*
+    The first two values become parameters of the block after the jump, and
+    only the second one is used. The copy into the unused one mustn't clobber it.
+*/
+#[test]
+fn unused_block_parameter() {
+    assert_eq!(run(vec![Load(i64::MAX), Load(-1), Load(1), Dup, Band, FDiv, Jmp(1), Label(1), Ret]), -1);
+}
+
+#[test]
+fn ret_from_the_middle() {
+    assert_eq!(run(vec![Load(1), Ret, Load(2), Ret]), 1);
+}
+
+#[test]
+fn write_leaves_the_value() {
+    assert_eq!(run(vec![Load(65), Write, Ret]), 65);
+    assert_eq!(run(vec![Load(65), WriteChar, Ret]), 65);
+}
+
+/*
+    Variables read before they're stored to are zero, in the frame as much as in registers.
+*/
+#[test]
+fn variables_start_out_as_zero() {
+    assert_eq!(run(vec![LoadVar(4), Load(38), Add]), 38);
+
+    let mut insts: Vec<Instruction> = (0..8).map(LoadVar).collect();
+    insts.extend([Add; 7]);
+    insts.extend([Load(1), Store(8), LoadVar(9), LoadVar(8), Add, Add]);
+    assert_eq!(run(insts), 1);
+}
+
+#[test]
+fn write_on_an_empty_stack() {
+    assert_eq!(run(vec![Write]), 0);
+    assert_eq!(run(vec![Load(3), Pop, WriteChar]), 0);
+}
+
+/*
+    Deeper than the register cache, so slots are spilled and popped back.
+*/
+#[test]
+fn deep_stack() {
+    assert_eq!(run(vec![Load(1), Load(2), Load(3), Load(4), Load(5), Add, Add, Add, Add, Ret]), 15);
+    assert_eq!(run(vec![Load(1), Load(2), Load(3), Sub, Sub, Ret]), 2);
+    assert_eq!(run(vec![Load(7), Load(1), Load(2), Swap, Sub, Mul, Ret]), 7);
+    assert_eq!(run(vec![Load(100), Load(7), Load(2), Dup, Mul, Mod, Div, Ret]), 33);
+    assert_eq!(run(vec![Load(1), Load(2), Load(3), Pop, Pop, Ret]), 1);
+    assert_eq!(run(vec![Load(3), Load(1), Load(4), Shl, Shl, Ret]), 3 << 16);
+    assert_eq!(run(vec![Load(100), Load(43), Load(7), Div, Add, Ret]), 106);
+    assert_eq!(run(vec![Load(100), Load(43), Load(7), Mod, Swap, Load(-3), Mod, Sub, Ret]), 0);
+}
+
+/*
+    Slots live across labels and jumps, where the cache is flushed.
+*/
+#[test]
+fn stack_across_jumps() {
+    assert_eq!(run(vec![Load(10), Load(20), Jmp(1), Label(1), Add, Ret]), 30);
+    assert_eq!(run(vec![Load(10), Load(20), Load(1), JmpIf(1), Pop, Label(1), Sub, Ret]), -10);
+    assert_eq!(run(vec![Load(10), Load(20), Load(0), JmpIf(1), Pop, Load(3), Label(1), Sub, Ret]), 7);
+}
+
+/*
+    Every comparison straight into a jump, which is compiled as cmp + jcc.
+*/
+#[test]
+fn compare_and_branch() {
+    let branch = |a, b, op, jmp: fn(u32) -> Instruction| {
+        run(vec![Load(a), Load(b), op, jmp(1), Load(0), Ret, Label(1), Load(1), Ret])
+    };
+
+    for (a, b) in [(1, 2), (2, 1), (2, 2), (-1, 1), (i64::MIN, i64::MAX)] {
+        for op in [Eq, Ne, Lt, Lte, Gt, Gte, ULt, ULte, UGt, UGte] {
+            let taken = branch(a, b, op, JmpIf);
+            assert_eq!(taken, binop(a, b, op), "{} {:?} {}", a, op, b);
+            assert_eq!(branch(a, b, op, JmpIfNot), 1 - taken, "{} {:?} {}", a, op, b);
+        }
+    }
+}
+
+/*
+    The README's loop, counting to 10 with a fused compare and branch at the top.
+*/
+#[test]
+fn fused_loop() {
+    assert_eq!(run(vec![
+        Load(0), Store(0),
+        Label(1),
+        LoadVar(0), Load(10), Gte, JmpIf(2),
+        LoadVar(0), Load(1), Add, Store(0),
+        Jmp(1),
+        Label(2),
+        LoadVar(0), Ret,
+    ]), 10);
+}
+
+/*
+    A loop body too long for rel8 jumps, so the jumps around it are rel32.
+*/
+#[test]
+fn long_loop_body() {
+    let mut insts = vec![Load(0), Store(0), Load(5), Store(1), Label(1), LoadVar(1), JmpIfNot(2)];
+    for _ in 0..20 {
+        insts.extend([LoadVar(0), Load(1), Add, Store(0)]);
+    }
+    insts.extend([LoadVar(1), Load(1), Sub, Store(1), Jmp(1), Label(2), LoadVar(0), Ret]);
+
+    assert_eq!(run(insts), 100);
+}
+
+/*
+    Mul, Div and Mod by a constant are strength reduced, check them against
+    the interpreter over the awkward ends of the range.
+*/
+#[test]
+fn arithmetic_by_constants() {
+    let values = [0, 1, -1, 2, -2, 3, 7, -7, 100, -100, 12345, i64::MAX, i64::MIN, i64::MIN + 1, i64::MAX - 1];
+    let constants = [
+        0, 1, -1, 2, -2, 3, -3, 5, 6, 7, -7, 9, 10, 16, -16, 25, 125, 641, 1 << 31, -(1 << 31),
+        (1 << 31) - 1, 1 << 40, 3 << 40, -(1 << 62), 1 << 62, i64::MAX, i64::MIN, i64::MIN + 1,
+    ];
+
+    for &c in &constants {
+        for &x in &values {
+            let mut ops = vec![Mul];
+            if c != 0 && !(c == -1 && x == i64::MIN) {
+                ops.extend([Div, Mod]);
+            }
+
+            for op in ops {
+                let expected = match op {
+                    Mul => x.wrapping_mul(c),
+                    Div => x / c,
+                    _ => x % c,
+                };
+                assert_eq!(binop(x, c, op), expected, "{} {:?} {}", x, op, c);
+            }
+        }
+    }
+}
+
+
+/*
+    More values live at once than there are registers to keep them in, with
+    divisions and shifts in between which need rdx and rcx for themselves.
+*/
+#[test]
+fn register_pressure() {
+    let mut insts = vec![];
+    for id in 0..14 {
+        insts.extend([Load(id as i64 * 3 + 1), Store(id)]);
+    }
+    for id in 0..14 {
+        insts.push(LoadVar(id));
+        match id % 3 {
+            0 => insts.extend([LoadVar(13), Mod]),
+            1 => insts.extend([LoadVar(0), Shl]),
+            _ => {}
+        }
+    }
+    insts.extend([Sub, Add, Mul, Bxor, Sub, Div, Add, Add, Sub, Mul, Add, Sub, Add, Ret]);
+
+    run(insts);
+}
+
+/*
+    The two values on the stack trade places every time round the loop, so
+    the block arguments overwrite each other's parameters.
+*/
+#[test]
+fn loop_rotates_its_stack() {
+    assert_eq!(run(vec![
+        Load(5), Store(0),
+        Load(1), Load(3),
+        Label(1),
+        Swap, Dup, Add,                     /* (a, b) becomes (b, 2a) */
+        LoadVar(0), Load(1), Sub, Dup, Store(0),
+        JmpIf(1),
+        Sub, Ret,
+    ]), 12 - 8);
+}
+
+/*
+    A loop full of work which is the same every time round, next to a
+    division by zero it never gets to.
+*/
+#[test]
+fn loop_invariants() {
+    assert_eq!(run(vec![
+        Load(4), Store(0), Load(0), Store(2), Load(7), Store(1),
+        Load(0),
+        Label(1),
+        LoadVar(0), JmpIfNot(3),
+        LoadVar(1), Load(3), Mod, LoadVar(1), Dup, Mul, Add, Add,
+        LoadVar(2), JmpIfNot(2),
+        LoadVar(1), LoadVar(2), Div, Add,
+        Label(2),
+        LoadVar(0), Load(1), Sub, Store(0),
+        Jmp(1),
+        Label(3),
+        Ret,
+    ]), 200);
+}
+
+/*
+    A subroutine with a loop and a variable of its own, inlined at two
+    call sites, one of them inside a loop which uses the same variable id.
+*/
+#[test]
+fn inlined_calls() {
+    use cjit::opt::{inline_calls, InlineOptions};
+
+    let program = Program::new(vec![
+        Load(3), Store(0), Load(0),
+        Label(1),
+        Load(4), Call(9), Add,
+        LoadVar(0), Load(1), Sub, Dup, Store(0), JmpIf(1),
+        Load(5), Call(9), Add,
+        Halt,
+        /* triangle(n) = n + (n - 1) + ... + 1 */
+        Label(9), Store(0), Load(0), Label(10), LoadVar(0), Add, LoadVar(0), Load(1), Sub, Dup, Store(0), JmpIf(10), Ret,
+    ]);
+    let (inlined, calls) = inline_calls(&program, &InlineOptions::default());
+
+    assert_eq!(calls, 2);
+    assert_eq!(run(inlined.insts().to_vec()), 3 * 10 + 15);
+}
+
+/*
+    Variable ids past the 1024 byte frame make it bigger.
+*/
+#[test]
+fn variables_past_the_frame() {
+    let mut insts = vec![];
+    for id in 0..6 {
+        insts.extend([Load(id as i64 + 1), Store(id * 100)]);
+    }
+    for id in 0..6 {
+        insts.push(LoadVar(id * 100));
+    }
+    insts.extend([Add, Add, Mul, Sub, Add, Ret]);
+
+    assert_eq!(run(insts), 1 + (2 - 3 * (4 + 5 + 6)));
+}
+
+/*
+    Floats, both from immediates and through variables so they aren't all folded away.
+*/
+fn fbinop(a: f64, b: f64, op: Instruction) -> f64 {
+    let folded = run(vec![LoadF(a), LoadF(b), op, Ret]);
+    let computed = run(vec![LoadF(a), Store(0), LoadF(b), Store(1), LoadVar(0), LoadVar(1), op, Ret]);
+    assert_eq!(folded, computed, "{} {:?} {}", a, op, b);
+    f64::from_bits(computed as u64)
+}
+
+fn fcmp(a: f64, b: f64, op: Instruction) -> bool {
+    let folded = run(vec![LoadF(a), LoadF(b), op, Ret]);
+    let computed = run(vec![LoadF(a), Store(0), LoadF(b), Store(1), LoadVar(0), LoadVar(1), op, Ret]);
+    assert_eq!(folded, computed, "{} {:?} {}", a, op, b);
+    computed != 0
+}
+
+fn funary(a: i64, op: Instruction) -> i64 {
+    let folded = run(vec![Load(a), op, Ret]);
+    let computed = run(vec![Load(a), Store(0), LoadVar(0), op, Ret]);
+    assert_eq!(folded, computed, "{:?} {}", op, a);
+    computed
+}
+
+fn bits(f: f64) -> i64 {
+    f.to_bits() as i64
+}
+
+#[test]
+fn load_float() {
+    assert_eq!(run(vec![LoadF(1.5), Ret]), bits(1.5));
+    assert_eq!(run(vec![LoadF(-0.0), Ret]), i64::MIN);
+}
+
+#[test]
+fn float_arithmetic() {
+    assert_eq!(fbinop(1.5, 2.25, FAdd), 3.75);
+    assert_eq!(fbinop(1.5, 2.25, FSub), -0.75);
+    assert_eq!(fbinop(1.5, -4.0, FMul), -6.0);
+    assert_eq!(fbinop(1.0, 4.0, FDiv), 0.25);
+    assert_eq!(fbinop(0.1, 0.2, FAdd), 0.1 + 0.2);
+    assert_eq!(fbinop(1.0, 0.0, FDiv), f64::INFINITY);      /* No trap */
+    assert_eq!(fbinop(-1.0, 0.0, FDiv), f64::NEG_INFINITY);
+    assert_eq!(fbinop(f64::MAX, 2.0, FMul), f64::INFINITY);
+}
+
+/*
+    Which NaN comes out is up to the hardware, and every backend agrees with it.
+*/
+#[test]
+fn float_nans() {
+    let default = 0xFFF8_0000_0000_0000_u64;
+    let signalling = f64::from_bits(0x7FF0_0000_0000_0001);
+
+    assert_eq!(fbinop(0.0, 0.0, FDiv).to_bits(), default);
+    assert_eq!(fbinop(f64::INFINITY, f64::INFINITY, FSub).to_bits(), default);
+    assert_eq!(fbinop(signalling, 1.0, FAdd).to_bits(), 0x7FF8_0000_0000_0001);
+    assert_eq!(fbinop(1.0, f64::from_bits(0x7FF8_0000_0000_0002), FMul).to_bits(), 0x7FF8_0000_0000_0002);
+    assert_eq!(funary(bits(-1.0), FSqrt) as u64, default);
+}
+
+#[test]
+fn float_unary() {
+    assert_eq!(funary(bits(2.5), FNeg), bits(-2.5));
+    assert_eq!(funary(bits(0.0), FNeg), bits(-0.0));
+    assert_eq!(funary(bits(f64::NAN), FNeg), bits(-f64::NAN));
+    assert_eq!(funary(bits(6.25), FSqrt), bits(2.5));
+    assert_eq!(funary(bits(-0.0), FSqrt), bits(-0.0));
+    assert_eq!(funary(bits(2.0), FSqrt), bits(2f64.sqrt()));
+}
+
+#[test]
+fn float_conversions() {
+    assert_eq!(funary(7, IToF), bits(7.0));
+    assert_eq!(funary(-7, IToF), bits(-7.0));
+    assert_eq!(funary(i64::MAX, IToF), bits(i64::MAX as f64));
+    assert_eq!(funary(bits(2.9), FToI), 2);
+    assert_eq!(funary(bits(-2.9), FToI), -2);               /* Truncates towards zero */
+    assert_eq!(funary(bits(i64::MIN as f64), FToI), i64::MIN);
+    assert_eq!(funary(bits(-(i64::MIN as f64)), FToI), i64::MIN);   /* Doesn't fit */
+    assert_eq!(funary(bits(f64::NAN), FToI), i64::MIN);
+    assert_eq!(funary(bits(f64::NEG_INFINITY), FToI), i64::MIN);
+}
+
+#[test]
+fn float_comparisons() {
+    for (a, b) in [(1.0, 2.0), (2.0, 1.0), (1.0, 1.0), (0.0, -0.0), (f64::NAN, 1.0), (1.0, f64::NAN), (f64::NAN, f64::NAN)] {
+        assert_eq!(fcmp(a, b, FEq), a == b, "{} == {}", a, b);
+        assert_eq!(fcmp(a, b, FNe), a != b, "{} != {}", a, b);
+        assert_eq!(fcmp(a, b, FLt), a < b, "{} < {}", a, b);
+        assert_eq!(fcmp(a, b, FGt), a > b, "{} > {}", a, b);
+        assert_eq!(fcmp(a, b, FLte), a <= b, "{} <= {}", a, b);
+        assert_eq!(fcmp(a, b, FGte), a >= b, "{} >= {}", a, b);
+    }
+}
+
+/*
+    Newton's method for sqrt(2), a float loop with an integer counter.
+*/
+#[test]
+fn float_loop() {
+    let result = run(vec![
+        LoadF(1.0), Store(0),
+        Load(6), Store(1),
+        Label(1),
+        /* x = (x + 2 / x) / 2 */
+        LoadVar(0), LoadF(2.0), LoadVar(0), FDiv, FAdd, LoadF(2.0), FDiv, Store(0),
+        LoadVar(1), Load(1), Sub, Dup, Store(1), JmpIf(1),
+        LoadVar(0), LoadF(1e6), FMul, FToI, Ret,
+    ]);
+
+    assert_eq!(result, 1_414_213);
+}